@@ -0,0 +1,190 @@
+use std::fmt;
+use std::str::FromStr;
+use borsh::{BorshDeserialize, BorshSerialize};
+use serde::{Deserialize, Serialize};
+
+use crate::hash::ContentHash;
+
+/// Longest claim text accepted by `Attest`
+pub const MAX_CLAIM_LEN: usize = 280;
+/// Attestations an attester may make on one image. Capped per attester, so that one attester can't use up the
+/// room of every other; the number of attesters is bounded by the admin keys.
+pub const MAX_ATTESTATIONS_PER_ATTESTER: usize = 8;
+
+/// Role an admin grants to an attester
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(rename_all = "kebab-case")]
+pub enum AttesterRole {
+    FactChecker,
+    Editor,
+}
+
+/// Claim attached to an image. Well-known claims have their own variant, anything else is free text.
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq, Eq)]
+pub enum Claim {
+    VerifiedLocation,
+    MisleadingContext,
+    AiGenerated,
+    Other(String),
+}
+
+/// Signed claim of an attester about one image
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct Attestation {
+    pub attester_pk: String,
+    /// Role of the attester when the claim was made
+    pub role: AttesterRole,
+    pub claim: Claim,
+    pub signature: String,
+    /// Contract height of the attestation
+    pub height: u64,
+}
+
+/// Attestation about an image of the queried lineage
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct LineageAttestation {
+    pub image_hash: ContentHash,
+    #[serde(flatten)]
+    pub attestation: Attestation,
+}
+
+impl AttesterRole {
+    /// Verdicts on an image (`misleading-context`, `ai-generated`) are left to fact-checkers, editors vouch for
+    /// the location and add notes
+    pub fn may_claim(&self, claim: &Claim) -> bool {
+        match self {
+            AttesterRole::FactChecker => true,
+            AttesterRole::Editor => matches!(claim, Claim::VerifiedLocation | Claim::Other(_)),
+        }
+    }
+}
+
+impl Claim {
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            Claim::Other(text) if text.trim().is_empty() => Err("Claim is empty".to_string()),
+            Claim::Other(text) if text.len() > MAX_CLAIM_LEN => {
+                Err(format!("Claim is longer than {} bytes", MAX_CLAIM_LEN))
+            }
+            // It would print like the verdict and pass for it, without the role `may_claim` asks for
+            Claim::Other(text) if Claim::well_known(text).is_some() => {
+                Err(format!("Claim '{}' is reserved, it can't be free text", text.trim()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Well-known claim printed as `tag`, regardless of case and surrounding whitespace
+    fn well_known(tag: &str) -> Option<Claim> {
+        match tag.trim().to_ascii_lowercase().as_str() {
+            "verified-location" => Some(Claim::VerifiedLocation),
+            "misleading-context" => Some(Claim::MisleadingContext),
+            "ai-generated" => Some(Claim::AiGenerated),
+            _ => None,
+        }
+    }
+}
+
+/// Message the attester signs for a claim
+pub fn attest_message(image_hash: &ContentHash, claim: &Claim) -> String {
+    format!("attest:{}:{}", image_hash, claim)
+}
+
+/// Message the admin keys sign to grant `role` to `attester_pk`, or to revoke it with `None`. It starts with the
+/// admin log length as nonce, like the governance messages.
+pub fn attester_message(nonce: usize, attester_pk: &str, role: Option<AttesterRole>) -> String {
+    match role {
+        Some(role) => format!("{}:attester:{}:{}", nonce, attester_pk, role),
+        None => format!("{}:revoke-attester:{}", nonce, attester_pk),
+    }
+}
+
+impl FromStr for AttesterRole {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "fact-checker" => Ok(AttesterRole::FactChecker),
+            "editor" => Ok(AttesterRole::Editor),
+            other => Err(format!("Unknown attester role '{}', expected fact-checker or editor", other)),
+        }
+    }
+}
+
+impl fmt::Display for AttesterRole {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AttesterRole::FactChecker => write!(f, "fact-checker"),
+            AttesterRole::Editor => write!(f, "editor"),
+        }
+    }
+}
+
+/// `verified-location`, `misleading-context`, `ai-generated` in any case, or any other text
+impl FromStr for Claim {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let claim = Claim::well_known(s).unwrap_or_else(|| Claim::Other(s.trim().to_string()));
+        claim.validate()?;
+        Ok(claim)
+    }
+}
+
+impl fmt::Display for Claim {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Claim::VerifiedLocation => write!(f, "verified-location"),
+            Claim::MisleadingContext => write!(f, "misleading-context"),
+            Claim::AiGenerated => write!(f, "ai-generated"),
+            Claim::Other(text) => write!(f, "{}", text),
+        }
+    }
+}
+
+/// Claims travel as their text form in JSON
+impl Serialize for Claim {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Claim {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = <String as Deserialize>::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(feature = "schemars")]
+impl schemars::JsonSchema for Claim {
+    fn schema_name() -> std::borrow::Cow<'static, str> {
+        "Claim".into()
+    }
+
+    fn json_schema(_: &mut schemars::SchemaGenerator) -> schemars::Schema {
+        schemars::json_schema!({
+            "type": "string",
+            "description": "`verified-location`, `misleading-context`, `ai-generated` or any other text"
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn free_text_cant_pass_for_a_well_known_claim() {
+        for text in ["misleading-context", "AI-Generated", " verified-location\n"] {
+            assert!(Claim::Other(text.to_string()).validate().is_err(), "{:?}", text);
+        }
+        assert_eq!(" Misleading-Context ".parse(), Ok(Claim::MisleadingContext));
+        assert_eq!("misleading context".parse(), Ok(Claim::Other("misleading context".to_string())));
+        assert!(Claim::Other(" ".to_string()).validate().is_err());
+        assert!(Claim::Other("a".repeat(MAX_CLAIM_LEN + 1)).validate().is_err());
+    }
+}
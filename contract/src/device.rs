@@ -0,0 +1,49 @@
+use borsh::{BorshDeserialize, BorshSerialize};
+use serde::{Deserialize, Serialize};
+
+use crate::hash::ContentHash;
+
+/// Capture-device or manufacturer attestation key trusted by the contract admin
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct TrustedDevice {
+    pub manufacturer: String,
+    pub model: String,
+}
+
+/// What the device states about the capture, signed along with the image hash
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct CaptureMetadata {
+    /// Unix timestamp, in seconds, as reported by the device clock
+    pub captured_at: u64,
+    pub location: Option<String>,
+}
+
+/// Device signature stored with an original image
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct CaptureAttestation {
+    pub device_pk: String,
+    pub metadata: CaptureMetadata,
+    pub device_signature: String,
+}
+
+/// Message the device signs at capture time
+pub fn capture_message(image_hash: &ContentHash, metadata: &CaptureMetadata) -> String {
+    format!(
+        "capture:{}:{}:{}",
+        image_hash,
+        metadata.captured_at,
+        metadata.location.as_deref().unwrap_or("")
+    )
+}
+
+/// Message the admin keys sign to trust a device key as `device`, or to distrust it with `None`. It starts with
+/// the admin log length as nonce, like the governance messages.
+pub fn registry_message(nonce: usize, device_pk: &str, device: Option<&TrustedDevice>) -> String {
+    match device {
+        Some(device) => format!("{}:trust-device:{}:{}:{}", nonce, device_pk, device.manufacturer, device.model),
+        None => format!("{}:distrust-device:{}", nonce, device_pk),
+    }
+}
@@ -0,0 +1,47 @@
+use borsh::{BorshDeserialize, BorshSerialize};
+use serde::{Deserialize, Serialize};
+
+use crate::hash::ContentHash;
+use crate::ownership::OwnerKeys;
+
+/// Longest public reason accepted by `ForceRevoke`
+pub const MAX_REASON_LEN: usize = 280;
+
+/// Admin action executed by the contract, kept in state for public review
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct AdminLogEntry {
+    /// Contract height of the action
+    pub height: u64,
+    /// Message the admin keys signed, which describes the action
+    pub action: String,
+    /// Admin keys whose signature authorized it
+    pub signers: Vec<String>,
+}
+
+/// Registration revoked by the admin keys, with the reason made public
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct Revocation {
+    pub reason: String,
+    pub height: u64,
+}
+
+// Governance messages start with the admin log length, so a signature can't be replayed once used.
+
+/// Message the admin keys sign to pause or unpause mutations
+pub fn pause_message(nonce: usize, paused: bool) -> String {
+    match paused {
+        true => format!("{}:pause", nonce),
+        false => format!("{}:unpause", nonce),
+    }
+}
+
+/// Message the current admin keys sign to hand over to `new_admin`
+pub fn rotate_admin_message(nonce: usize, new_admin: &OwnerKeys) -> String {
+    format!("{}:rotate-admin:{}", nonce, new_admin)
+}
+
+/// Message the admin keys sign to revoke a registration
+pub fn revoke_message(nonce: usize, image_hash: &ContentHash, reason: &str) -> String {
+    format!("{}:revoke:{}:{}", nonce, image_hash, reason)
+}
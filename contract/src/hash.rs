@@ -0,0 +1,305 @@
+use std::fmt;
+use std::str::FromStr;
+use borsh::{BorshDeserialize, BorshSerialize};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use sha2::{Digest, Sha256};
+use sha3::Keccak256;
+
+use crate::transform::RawImage;
+
+// The multicodec table has no code for a hash of decoded pixels, nor for our legacy keys: both are sha256 digests,
+// but of other bytes than the file, so tagging them `sha2-256` (0x12) would let them be mistaken for a file hash.
+// They get codes of the private-use range (0x300000 to 0x3fffff), which the table never assigns, made of the range
+// start plus the sha256 code so they read as variants of it. Only this contract reads them, and they are part of
+// the published text form: never renumber them.
+
+/// Multicodec code of the canonical pixel hash, in the private-use range
+pub const PIXEL_SHA256_CODE: u64 = 0x30_0012;
+/// Multicodec code of legacy keys, in the private-use range
+pub const LEGACY_SHA256_CODE: u64 = 0x30_0013;
+
+/// Hash function used to compute a `ContentHash`
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum HashAlgorithm {
+    Sha256,
+    Blake3,
+    Keccak256,
+    /// Sha256 over the decoded pixels, see `canonical_hash`
+    PixelSha256,
+    /// Sha256 of a key of the untyped state layouts that isn't a valid hash, see `legacy_hash`
+    LegacySha256,
+}
+
+impl HashAlgorithm {
+    pub const ALL: [HashAlgorithm; 5] = [
+        HashAlgorithm::Sha256,
+        HashAlgorithm::Blake3,
+        HashAlgorithm::Keccak256,
+        HashAlgorithm::PixelSha256,
+        HashAlgorithm::LegacySha256,
+    ];
+
+    /// Tag used in the `<tag>:<hex>` text form
+    pub fn tag(&self) -> &'static str {
+        match self {
+            HashAlgorithm::Sha256 => "sha256",
+            HashAlgorithm::Blake3 => "blake3",
+            HashAlgorithm::Keccak256 => "keccak256",
+            HashAlgorithm::PixelSha256 => "rgba8-sha256",
+            HashAlgorithm::LegacySha256 => "legacy-sha256",
+        }
+    }
+
+    /// Multicodec code used in the multihash form
+    pub fn code(&self) -> u64 {
+        match self {
+            HashAlgorithm::Sha256 => 0x12,
+            HashAlgorithm::Blake3 => 0x1e,
+            HashAlgorithm::Keccak256 => 0x1b,
+            HashAlgorithm::PixelSha256 => PIXEL_SHA256_CODE,
+            HashAlgorithm::LegacySha256 => LEGACY_SHA256_CODE,
+        }
+    }
+
+    fn from_code(code: u64) -> Option<Self> {
+        Self::ALL.into_iter().find(|a| a.code() == code)
+    }
+
+    fn from_tag(tag: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|a| a.tag().eq_ignore_ascii_case(tag))
+    }
+}
+
+/// Validated image hash: the algorithm and its 32 bytes digest.
+///
+/// Text forms accepted by `FromStr`:
+/// - `<tag>:<hex>` as printed by `Display`, e.g. `sha256:ab12...` or `rgba8-sha256:ab12...`
+/// - bare 64 characters hex, read as sha256
+/// - multihash in multibase, either base16 (`f1220...`) or base58btc (`z...`, or the bare `Qm...` form)
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ContentHash {
+    pub algorithm: HashAlgorithm,
+    pub digest: [u8; 32],
+}
+
+impl ContentHash {
+    pub fn new(algorithm: HashAlgorithm, digest: [u8; 32]) -> Self {
+        ContentHash { algorithm, digest }
+    }
+
+    /// Hashes `bytes` with `algorithm`. For `PixelSha256`, `bytes` must already be the canonical encoding.
+    pub fn compute(algorithm: HashAlgorithm, bytes: &[u8]) -> Self {
+        let digest = match algorithm {
+            HashAlgorithm::Sha256 | HashAlgorithm::PixelSha256 | HashAlgorithm::LegacySha256 => {
+                Sha256::digest(bytes).into()
+            }
+            HashAlgorithm::Blake3 => *blake3::hash(bytes).as_bytes(),
+            HashAlgorithm::Keccak256 => Keccak256::digest(bytes).into(),
+        };
+        ContentHash { algorithm, digest }
+    }
+
+    /// Whether this hash was computed with `canonical_hash`
+    pub fn is_canonical(&self) -> bool {
+        self.algorithm == HashAlgorithm::PixelSha256
+    }
+
+    /// Binary multihash: varint code, varint length, digest
+    pub fn to_multihash(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(36);
+        write_varint(&mut bytes, self.algorithm.code());
+        write_varint(&mut bytes, self.digest.len() as u64);
+        bytes.extend_from_slice(&self.digest);
+        bytes
+    }
+
+    pub fn from_multihash(bytes: &[u8]) -> Result<Self, String> {
+        let (code, rest) = read_varint(bytes)?;
+        let (len, digest) = read_varint(rest)?;
+        let algorithm = HashAlgorithm::from_code(code).ok_or(format!("Unsupported multihash code 0x{:x}", code))?;
+        if len != 32 || digest.len() != 32 {
+            return Err("Multihash digest must be 32 bytes".to_string());
+        }
+        Ok(ContentHash::new(algorithm, digest.try_into().unwrap()))
+    }
+}
+
+/// Tagged sha256 of the file bytes. Any change to the file (EXIF, compression...) changes it
+pub fn file_hash(bytes: &[u8]) -> ContentHash {
+    ContentHash::compute(HashAlgorithm::Sha256, bytes)
+}
+
+/// Hash of a key of the untyped state layouts: the key itself when it parses, otherwise the `LegacySha256` of its
+/// text, so that an image registered under a free-form key stays in the state and reachable
+pub fn legacy_hash(key: &str) -> ContentHash {
+    key.parse().unwrap_or_else(|_| ContentHash::compute(HashAlgorithm::LegacySha256, key.as_bytes()))
+}
+
+/// Sha256 of the pixels: width and height (u32 LE) followed by the RGBA8 buffer.
+/// Only the decoded content is hashed, so metadata-only changes keep the same hash.
+pub fn canonical_hash(image: &RawImage) -> ContentHash {
+    let mut hasher = Sha256::new();
+    hasher.update(image.width.to_le_bytes());
+    hasher.update(image.height.to_le_bytes());
+    hasher.update(&image.pixels);
+    ContentHash::new(HashAlgorithm::PixelSha256, hasher.finalize().into())
+}
+
+impl fmt::Display for ContentHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.algorithm.tag(), hex::encode(self.digest))
+    }
+}
+
+impl FromStr for ContentHash {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let hex_digest = |h: &str| -> Result<[u8; 32], String> {
+            let bytes = hex::decode(h.to_ascii_lowercase()).map_err(|e| format!("Invalid hex digest '{}': {}", h, e))?;
+            bytes.try_into().map_err(|_| format!("Digest '{}' must be 32 bytes", h))
+        };
+
+        if let Some((tag, digest)) = s.split_once(':') {
+            let algorithm = HashAlgorithm::from_tag(tag).ok_or(format!("Unknown hash algorithm '{}'", tag))?;
+            return Ok(ContentHash::new(algorithm, hex_digest(digest)?));
+        }
+        if s.len() == 64 {
+            return Ok(ContentHash::new(HashAlgorithm::Sha256, hex_digest(s)?));
+        }
+        let multihash = if let Some(base16) = s.strip_prefix('f').or_else(|| s.strip_prefix('F')) {
+            hex::decode(base16.to_ascii_lowercase()).map_err(|e| format!("Invalid base16 multihash: {}", e))?
+        } else if let Some(base58) = s.strip_prefix('z') {
+            bs58::decode(base58).into_vec().map_err(|e| format!("Invalid base58 multihash: {}", e))?
+        } else if s.starts_with("Qm") {
+            bs58::decode(s).into_vec().map_err(|e| format!("Invalid base58 multihash: {}", e))?
+        } else {
+            return Err(format!("Unrecognised hash '{}'", s));
+        };
+        ContentHash::from_multihash(&multihash)
+    }
+}
+
+/// JSON uses the `Display` text form, so hashes read the same in the API and on the CLI
+impl Serialize for ContentHash {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for ContentHash {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = <String as Deserialize>::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(feature = "schemars")]
+impl schemars::JsonSchema for ContentHash {
+    fn schema_name() -> std::borrow::Cow<'static, str> {
+        "ContentHash".into()
+    }
+
+    fn json_schema(_: &mut schemars::SchemaGenerator) -> schemars::Schema {
+        schemars::json_schema!({
+            "type": "string",
+            "description": "Typed hash `<algorithm>:<hex>`, a multihash, or a legacy bare SHA-256 hex digest"
+        })
+    }
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn read_varint(bytes: &[u8]) -> Result<(u64, &[u8]), String> {
+    let mut value = 0u64;
+    for (i, &byte) in bytes.iter().enumerate().take(9) {
+        value |= ((byte & 0x7f) as u64) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Ok((value, &bytes[i + 1..]));
+        }
+    }
+    Err("Invalid multihash varint".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DIGEST: &str = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08";
+
+    #[test]
+    fn every_algorithm_round_trips_through_each_text_form() {
+        for algorithm in HashAlgorithm::ALL {
+            let hash = ContentHash::compute(algorithm, b"test");
+            assert_eq!(hash.to_string().parse(), Ok(hash));
+            assert_eq!(hash.to_string().to_uppercase().parse(), Ok(hash));
+            let multihash = hash.to_multihash();
+            assert_eq!(ContentHash::from_multihash(&multihash), Ok(hash));
+            assert_eq!(format!("f{}", hex::encode(&multihash)).parse(), Ok(hash));
+            assert_eq!(format!("F{}", hex::encode_upper(&multihash)).parse(), Ok(hash));
+            assert_eq!(format!("z{}", bs58::encode(&multihash).into_string()).parse(), Ok(hash));
+        }
+    }
+
+    #[test]
+    fn bare_forms_are_sha256() {
+        let hash = ContentHash::compute(HashAlgorithm::Sha256, b"test");
+        assert_eq!(hash.to_string(), format!("sha256:{}", DIGEST));
+        assert_eq!(DIGEST.parse(), Ok(hash));
+        assert_eq!(format!(" {} ", DIGEST.to_uppercase()).parse(), Ok(hash));
+        let cid = bs58::encode(hash.to_multihash()).into_string();
+        assert!(cid.starts_with("Qm"));
+        assert_eq!(cid.parse(), Ok(hash));
+        // Codes that need a multi-byte varint
+        let pixels = ContentHash::new(HashAlgorithm::PixelSha256, hash.digest).to_multihash();
+        assert_eq!(pixels[..4], [0x92, 0x80, 0xc0, 0x01]);
+    }
+
+    #[test]
+    fn wrong_digest_lengths_are_rejected() {
+        let short = &DIGEST[..62];
+        let long = format!("{}00", DIGEST);
+        for text in [format!("sha256:{}", short), format!("sha256:{}", long), short.to_string(), long.clone()] {
+            assert!(text.parse::<ContentHash>().is_err(), "{}", text);
+        }
+        assert!(format!("sha256:{}", &DIGEST[..63]).parse::<ContentHash>().is_err());
+        assert!(format!("sha256:{}g", &DIGEST[..63]).parse::<ContentHash>().is_err());
+        for len in [20u8, 31, 33] {
+            let mut multihash = vec![0x12, len];
+            multihash.extend(vec![7; len as usize]);
+            assert!(format!("f{}", hex::encode(&multihash)).parse::<ContentHash>().is_err());
+            assert!(format!("z{}", bs58::encode(&multihash).into_string()).parse::<ContentHash>().is_err());
+        }
+        // Declared length matching, digest truncated
+        let multihash = ContentHash::compute(HashAlgorithm::Sha256, b"test").to_multihash();
+        assert!(ContentHash::from_multihash(&multihash[..33]).is_err());
+        assert!(ContentHash::from_multihash(&[0x12]).is_err());
+        assert!(ContentHash::from_multihash(&[0xff; 12]).is_err());
+    }
+
+    #[test]
+    fn unknown_algorithms_are_rejected() {
+        assert!(format!("md5:{}", DIGEST).parse::<ContentHash>().unwrap_err().contains("Unknown hash algorithm"));
+        assert!(format!(":{}", DIGEST).parse::<ContentHash>().is_err());
+        // sha2-512, identity, and the private-use neighbours of our codes
+        for code in [0x13, 0x00, 0x30_0011, 0x30_0014] {
+            let mut multihash = vec![];
+            write_varint(&mut multihash, code);
+            multihash.push(32);
+            multihash.extend([7; 32]);
+            let error = ContentHash::from_multihash(&multihash).unwrap_err();
+            assert!(error.contains("Unsupported multihash code"), "{}", error);
+            assert!(format!("f{}", hex::encode(&multihash)).parse::<ContentHash>().is_err());
+        }
+        for text in ["", "photo.jpg", "bafybeigdyrzt5sfp7udm7hu76uh7y26nf3efuylqabf3oclgtqy55fbzdi", "z0OIl"] {
+            assert!(text.parse::<ContentHash>().is_err(), "{}", text);
+        }
+    }
+}
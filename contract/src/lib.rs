@@ -1,90 +1,334 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::HashMap;
 use borsh::{io::Error, BorshDeserialize, BorshSerialize};
 use serde::{Deserialize, Serialize};
 
 use sdk::{Digestable, HyleContract, RunResult};
 
+pub mod attestation;
+pub mod device;
+pub mod governance;
+pub mod hash;
+pub mod ownership;
+pub mod provenance;
+pub mod state;
+pub mod transform;
+
+use attestation::{Attestation, AttesterRole, Claim, LineageAttestation};
+use device::{CaptureAttestation, CaptureMetadata, TrustedDevice};
+use governance::{AdminLogEntry, Revocation};
+use hash::ContentHash;
+use ownership::{MultiSignature, OwnerKeys, PublisherGrant};
+use provenance::{AiDisclosure, GeneratorModel, ProvenanceClass};
+use transform::{RawImage, Redaction, Transformation, TransformWitness};
+
 /// Struct to store image metadata
 #[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
 pub struct ImageMetadata {
-    pub previous_image_hash: Option<String>,
-    pub owner_pk: String,
-    pub publishers: HashSet<String>,
+    pub previous_image_hash: Option<ContentHash>,
+    pub owner_pk: OwnerKeys,
+    /// Publisher key, normalized -> its grant. Grants given by a key after it was declared compromised are void.
+    pub publishers: HashMap<String, PublisherGrant>,
     pub is_root: bool,
+    /// Set when the edit was proven from pixels rather than signed by a publisher
+    pub transformation: Option<Transformation>,
+    /// 64-bit perceptual hash (dHash) computed by the host, used for near-duplicate lookups
+    pub perceptual_hash: Option<u64>,
+    /// Contract height (see `ImageState::height`) at which the image was registered
+    pub registered_at: u64,
+    /// Keys whose signature authorized this edit
+    pub signed_by: Vec<String>,
+    /// Signature of the capture device, for originals registered straight from a camera
+    pub capture: Option<CaptureAttestation>,
+    pub provenance: ProvenanceClass,
 }
 
-#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Debug, Clone)]
+impl ImageMetadata {
+    /// Regions hidden by this edit, empty unless it is a proven redaction
+    pub fn redactions(&self) -> &[Redaction] {
+        match &self.transformation {
+            Some(Transformation::Redact { redactions }) => redactions,
+            _ => &[],
+        }
+    }
+}
+
+/// Contract state. It is stored on-chain in a versioned envelope, see `state::STATE_VERSION`.
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Debug, Clone, Default)]
 pub struct ImageState {
-    pub hash_map: HashMap<String, ImageMetadata>,
+    pub hash_map: HashMap<ContentHash, ImageMetadata>,
+    /// Canonical pixel hash -> key of the image in `hash_map`
+    pub canonical_hashes: HashMap<ContentHash, ContentHash>,
+    /// Number of actions executed by the contract, used to order registrations and key compromises
+    pub height: u64,
+    /// Owner key, normalized (see `ownership::normalize_key`) -> keys allowed to rotate it when it is lost
+    pub recovery_keys: HashMap<String, OwnerKeys>,
+    /// Rotated-away key, normalized -> height from which its signatures are not trusted anymore
+    pub compromised_keys: HashMap<String, u64>,
+    /// Keys allowed to manage the contract registries and governance, fixed at contract registration
+    /// and only changed by `RotateAdmin`
+    pub admin_keys: Option<OwnerKeys>,
+    /// When set, only admin actions are executed
+    pub paused: bool,
+    /// Every admin action executed, oldest first. Its length is the nonce of governance messages
+    pub admin_log: Vec<AdminLogEntry>,
+    /// Image key in `hash_map` -> why the admin keys revoked it
+    pub revocations: HashMap<ContentHash, Revocation>,
+    /// Device attestation key, normalized -> device it belongs to
+    pub trusted_devices: HashMap<String, TrustedDevice>,
+    /// Attester key, normalized -> role granted by the admin keys
+    pub attesters: HashMap<String, AttesterRole>,
+    /// Image key in `hash_map` -> claims made about it, at most `MAX_ATTESTATIONS_PER_ATTESTER` per attester
+    pub attestations: HashMap<ContentHash, Vec<Attestation>>,
+    /// Generator model key, normalized -> model it belongs to
+    pub generator_models: HashMap<String, GeneratorModel>,
 }
 
 impl HyleContract for ImageState {
     fn execute(&mut self, contract_input: &sdk::ContractInput) -> RunResult {
         let (action, ctx) = sdk::utils::parse_raw_contract_input::<ImageAction>(contract_input)?;
+        // Rejections fail the transaction: the state, height included, only changes when the action succeeds
+        let height = self.height + 1;
 
-        let program_output = match action {
-            ImageAction::RegisterImage { image_hash, image_signature: _, owner_pk } => {
-                println!("Trying register");
-                println!("Existing keys: {:?}", self.hash_map.keys());
-
-                if !self.hash_map.contains_key(&image_hash) {
-                    self.hash_map.insert(
-                        image_hash.clone(),
-                        ImageMetadata {
-                            previous_image_hash: None,
-                            owner_pk,
-                            publishers: HashSet::new(),
-                            is_root: true,
-                        },
-                    );
-                    println!("Image registered");
-                    format!("Image registered: {}", image_hash)
+        if self.paused && !action.is_admin() {
+            return Err("Contract is paused! Only admin actions are executed.".to_string());
+        }
+
+        let program_output: Result<String, String> = match action {
+            ImageAction::RegisterImage {
+                image_hash,
+                canonical_hash,
+                perceptual_hash,
+                image_signature: _,
+                owner_pk,
+            } => {
+                let canonical_hash = proven_canonical_hash(&image_hash, canonical_hash, &contract_input.private_input)?;
+                let metadata = ImageMetadata::original(owner_pk, perceptual_hash, height, ProvenanceClass::Declared);
+                self.register_original(image_hash, canonical_hash, metadata)
+            }
+
+            ImageAction::RegisterCapturedImage {
+                image_hash,
+                canonical_hash,
+                perceptual_hash,
+                owner_pk,
+                capture,
+                device_pk,
+                device_signature,
+            } => {
+                let message = device::capture_message(&image_hash, &capture);
+                let canonical_hash = proven_canonical_hash(&image_hash, canonical_hash, &contract_input.private_input);
+                if !self.trusted_devices.contains_key(&ownership::normalize_key(&device_pk)) {
+                    Err(format!("Device {} is not trusted!", device_pk))
+                } else if !ownership::dummy_verify_signature(&device_pk, &message, &device_signature) {
+                    Err("Invalid device signature! Image not registered.".to_string())
+                } else if let Err(err) = canonical_hash {
+                    Err(err)
                 } else {
-                    "Nothing added...".to_string()
+                    let attestation = CaptureAttestation {
+                        device_pk,
+                        metadata: capture,
+                        device_signature,
+                    };
+                    let metadata = ImageMetadata {
+                        capture: Some(attestation),
+                        ..ImageMetadata::original(owner_pk, perceptual_hash, height, ProvenanceClass::Captured)
+                    };
+                    self.register_original(image_hash, canonical_hash.ok().flatten(), metadata)
+                }
+            }
+
+            ImageAction::RegisterGeneratedImage {
+                image_hash,
+                canonical_hash,
+                perceptual_hash,
+                owner_pk,
+                model_pk,
+                model_signature,
+            } => {
+                let message = provenance::generation_message(&image_hash);
+                let canonical_hash = proven_canonical_hash(&image_hash, canonical_hash, &contract_input.private_input);
+                if !self.generator_models.contains_key(&ownership::normalize_key(&model_pk)) {
+                    Err(format!("Generator model {} is not registered!", model_pk))
+                } else if !ownership::dummy_verify_signature(&model_pk, &message, &model_signature) {
+                    Err("Invalid model signature! Image not registered.".to_string())
+                } else if let Err(err) = canonical_hash {
+                    Err(err)
+                } else {
+                    let provenance = ProvenanceClass::AiGenerated { model_pk };
+                    let metadata = ImageMetadata::original(owner_pk, perceptual_hash, height, provenance);
+                    self.register_original(image_hash, canonical_hash.ok().flatten(), metadata)
+                }
+            }
+
+            ImageAction::SetGeneratorModel {
+                model_pk,
+                model,
+                admin_signature,
+            } => {
+                let message = provenance::generator_registry_message(self.admin_log.len(), &model_pk, model.as_ref());
+                if !self.authorize_admin(height, message, &admin_signature) {
+                    Err("Invalid admin signature! Generator registry unchanged.".to_string())
+                } else if let Some(model) = model {
+                    self.generator_models.insert(ownership::normalize_key(&model_pk), model);
+                    Ok(format!("Generator model {} registered", model_pk))
+                } else if self.generator_models.remove(&ownership::normalize_key(&model_pk)).is_some() {
+                    Ok(format!("Generator model {} removed", model_pk))
+                } else {
+                    Ok(format!("Generator model {} was not registered", model_pk))
+                }
+            }
+
+            ImageAction::SetTrustedDevice {
+                device_pk,
+                device,
+                admin_signature,
+            } => {
+                let message = device::registry_message(self.admin_log.len(), &device_pk, device.as_ref());
+                if !self.authorize_admin(height, message, &admin_signature) {
+                    Err("Invalid admin signature! Device registry unchanged.".to_string())
+                } else if let Some(device) = device {
+                    self.trusted_devices.insert(ownership::normalize_key(&device_pk), device);
+                    Ok(format!("Device {} is now trusted", device_pk))
+                } else if self.trusted_devices.remove(&ownership::normalize_key(&device_pk)).is_some() {
+                    Ok(format!("Device {} is not trusted anymore", device_pk))
+                } else {
+                    Ok(format!("Device {} was not trusted", device_pk))
                 }
             }
 
             ImageAction::RegisterEdit {
                 original_image_hash,
                 edited_image_hash,
+                edited_canonical_hash,
                 original_edit_signature,
+                generative,
             } => {
-                println!("Checking for existing image keys: {:?}", self.hash_map.keys());
-
-                if self.hash_map.contains_key(&edited_image_hash) {
-                    "Edited hash already exists!".to_string()
-                } else if !self.hash_map.contains_key(&original_image_hash) {
-                    "Original image does not exist!".to_string()
-                } else {
-                    let initial_img_hash = match self.find_original_image(original_image_hash.clone()) {
-                        Ok(hash) => hash,
-                        Err(err) => {
-                            eprintln!("Error finding original image: {}", err);
-                            return Err(err.to_string());
-                        }
-                    };
+                let edited_canonical_hash =
+                    proven_canonical_hash(&edited_image_hash, edited_canonical_hash, &contract_input.private_input);
+                let edited_known = self.resolve_hash(&edited_image_hash).is_some()
+                    || edited_canonical_hash
+                        .as_ref()
+                        .is_ok_and(|h| h.is_some_and(|h| self.resolve_hash(&h).is_some()));
+                if edited_known {
+                    Err("Edited hash already exists!".to_string())
+                } else if let Err(err) = edited_canonical_hash {
+                    Err(err)
+                } else if self.revocation(original_image_hash).is_some() {
+                    Err("Original image was revoked!".to_string())
+                } else if let Some(original_key) = self.resolve_hash(&original_image_hash) {
+                    let initial_img_hash = self.find_original_image(original_key).map_err(|err| err.to_string())?;
                     let initial_img_meta = self.hash_map.get(&initial_img_hash).unwrap();
 
                     let publisher_pk_set = initial_img_meta.publishers.clone();
                     let owner_pk = initial_img_meta.owner_pk.clone();
-                    let message = format!("{}{}", original_image_hash, edited_image_hash);
-                    let signature_verification = dummy_verify_signature(owner_pk.clone(), message.clone(), original_edit_signature.clone())
-                        || publisher_pk_set.iter().any(|e| dummy_verify_signature(e.clone(), message.clone(), original_edit_signature.clone()));
+                    let message = ownership::edit_message(&original_image_hash, &edited_image_hash);
+                    // Owners need `threshold` signatures, a single publisher signature is enough
+                    let signed_by = if owner_pk.is_authorized(&message, &original_edit_signature) {
+                        owner_pk.signers(&message, &original_edit_signature)
+                    } else {
+                        publisher_pk_set
+                            .into_iter()
+                            .filter(|(pk, grant)| {
+                                !self.is_compromised(&grant.granted_by, grant.granted_at)
+                                    && original_edit_signature.signed_by(pk, &message)
+                            })
+                            .map(|(pk, _)| pk)
+                            .take(1)
+                            .collect()
+                    };
 
-                    if signature_verification {
+                    if !signed_by.is_empty() {
                         self.hash_map.insert(
-                            edited_image_hash.clone(),
+                            edited_image_hash,
                             ImageMetadata {
-                                previous_image_hash: Some(original_image_hash.clone()),
+                                previous_image_hash: Some(original_key),
                                 owner_pk,
-                                publishers: HashSet::new(),
+                                publishers: HashMap::new(),
                                 is_root: false,
+                                transformation: None,
+                                perceptual_hash: None,
+                                registered_at: height,
+                                signed_by,
+                                capture: None,
+                                provenance: match generative {
+                                    true => ProvenanceClass::AiAssistedEdit,
+                                    false => ProvenanceClass::Edited,
+                                },
                             },
                         );
-                        format!("Edit registered successfully: {}", edited_image_hash)
+                        if let Ok(Some(canonical_hash)) = edited_canonical_hash {
+                            self.canonical_hashes.insert(canonical_hash, edited_image_hash);
+                        }
+                        Ok(format!("Edit registered successfully: {}", edited_image_hash))
                     } else {
-                        "Invalid signature! Edit not registered.".to_string()
+                        Err("Invalid signature! Edit not registered.".to_string())
+                    }
+                } else {
+                    Err("Original image does not exist!".to_string())
+                }
+            }
+
+            ImageAction::RegisterProvenEdit {
+                original_image_hash,
+                edited_image_hash,
+                transformation,
+            } => {
+                if self.resolve_hash(&edited_image_hash).is_some() {
+                    Err("Edited hash already exists!".to_string())
+                } else if self.revocation(original_image_hash).is_some() {
+                    Err("Original image was revoked!".to_string())
+                } else if let Some(original_key) = self.resolve_hash(&original_image_hash) {
+                    // The pixels only live in the private input: the proof shows they hash to the
+                    // registered values and that one is the transformation of the other.
+                    let witness: TransformWitness = borsh::from_slice(&contract_input.private_input)
+                        .map_err(|e| format!("Could not decode transformation witness: {}", e))?;
+                    let original = RawImage::decode(&witness.original_file)?;
+                    let edited = RawImage::decode(&witness.edited_file)?;
+
+                    if !matches_file(&original_image_hash, &witness.original_file, &original) {
+                        return Err("Original file does not match the original image hash".to_string());
+                    }
+                    if !matches_file(&edited_image_hash, &witness.edited_file, &edited) {
+                        return Err("Edited file does not match the edited image hash".to_string());
+                    }
+                    let edited_canonical_hash = hash::canonical_hash(&edited);
+                    if self.resolve_hash(&edited_canonical_hash).is_some() {
+                        return Err("Edited hash already exists!".to_string());
+                    }
+
+                    let verified = transformation.verify_format(&witness.edited_file);
+                    match verified.and_then(|()| transformation.verify(&original, &edited)) {
+                        Ok(()) => {
+                            let initial_img_hash = self
+                                .find_original_image(original_key)
+                                .map_err(|e| e.to_string())?;
+                            let owner_pk = self.hash_map[&initial_img_hash].owner_pk.clone();
+                            self.hash_map.insert(
+                                edited_image_hash,
+                                ImageMetadata {
+                                    previous_image_hash: Some(original_key),
+                                    owner_pk,
+                                    publishers: HashMap::new(),
+                                    is_root: false,
+                                    transformation: Some(transformation),
+                                    perceptual_hash: None,
+                                    registered_at: height,
+                                    signed_by: vec![],
+                                    capture: None,
+                                    provenance: ProvenanceClass::Edited,
+                                },
+                            );
+                            // The guest decoded the edit anyway, so its canonical hash comes for free
+                            if edited_canonical_hash != edited_image_hash {
+                                self.canonical_hashes.insert(edited_canonical_hash, edited_image_hash);
+                            }
+                            Ok(format!("Proven edit registered successfully: {}", edited_image_hash))
+                        }
+                        Err(err) => Err(format!("Invalid transformation! Edit not registered: {}", err)),
                     }
+                } else {
+                    Err("Original image does not exist!".to_string())
                 }
             }
 
@@ -93,61 +337,502 @@ impl HyleContract for ImageState {
                 original_image_signature,
                 publisher_pk,
             } => {
-                if let Some(image_metadata) = self.hash_map.get_mut(&original_image_hash) {
-                    let owner_pk = image_metadata.owner_pk.clone();
-                    let is_correct = dummy_verify_signature(owner_pk.clone(), original_image_hash.clone(), original_image_signature);
+                let original_key = self.resolve_hash(&original_image_hash).unwrap_or(original_image_hash);
+                if self.revocations.contains_key(&original_key) {
+                    Err("Original image was revoked!".to_string())
+                } else if let Some(image_metadata) = self.hash_map.get_mut(&original_key) {
+                    let message = ownership::add_publisher_message(&original_image_hash);
+                    let is_correct = image_metadata.owner_pk.is_authorized(&message, &original_image_signature);
 
                     if is_correct && image_metadata.is_root {
-                        if !image_metadata.publishers.insert(publisher_pk.clone()) {
-                            format!("Publisher {} is already registered!", publisher_pk)
+                        let publisher = ownership::normalize_key(&publisher_pk);
+                        if image_metadata.publishers.contains_key(&publisher) {
+                            Err(format!("Publisher {} is already registered!", publisher_pk))
                         } else {
-                            format!("Publisher {} added successfully!", publisher_pk)
+                            let grant = PublisherGrant {
+                                granted_at: height,
+                                granted_by: image_metadata.owner_pk.signers(&message, &original_image_signature),
+                            };
+                            image_metadata.publishers.insert(publisher, grant);
+                            Ok(format!("Publisher {} added successfully!", publisher_pk))
                         }
                     } else {
-                        "Invalid signature or not an original image!".to_string()
+                        Err("Invalid signature or not an original image!".to_string())
+                    }
+                } else {
+                    Err("Original image does not exist!".to_string())
+                }
+            }
+
+            ImageAction::SetAttester {
+                attester_pk,
+                role,
+                admin_signature,
+            } => {
+                let message = attestation::attester_message(self.admin_log.len(), &attester_pk, role);
+                if !self.authorize_admin(height, message, &admin_signature) {
+                    Err("Invalid admin signature! Attester registry unchanged.".to_string())
+                } else if let Some(role) = role {
+                    self.attesters.insert(ownership::normalize_key(&attester_pk), role);
+                    Ok(format!("{} is now a {}", attester_pk, role))
+                } else if self.attesters.remove(&ownership::normalize_key(&attester_pk)).is_some() {
+                    Ok(format!("{} is not an attester anymore", attester_pk))
+                } else {
+                    Ok(format!("{} was not an attester", attester_pk))
+                }
+            }
+
+            ImageAction::Attest {
+                image_hash,
+                claim,
+                attester_pk,
+                signature,
+            } => {
+                let message = attestation::attest_message(&image_hash, &claim);
+                let attester = ownership::normalize_key(&attester_pk);
+                let key = self.resolve_hash(&image_hash);
+                let existing = key.and_then(|key| self.attestations.get(&key)).map(Vec::as_slice).unwrap_or_default();
+                let own = || existing.iter().filter(|a| ownership::normalize_key(&a.attester_pk) == attester);
+                match (key, self.attesters.get(&attester).copied()) {
+                    (None, _) => Err("Image not found! Attestation not added.".to_string()),
+                    (_, None) => Err(format!("{} is not a registered attester!", attester_pk)),
+                    (Some(key), Some(role)) => {
+                        if let Err(err) = claim.validate() {
+                            Err(err)
+                        } else if !role.may_claim(&claim) {
+                            Err(format!("A {} can't claim {}!", role, claim))
+                        } else if !ownership::dummy_verify_signature(&attester_pk, &message, &signature) {
+                            Err("Invalid attester signature! Attestation not added.".to_string())
+                        } else if own().any(|a| a.claim == claim) {
+                            Err("Claim already attested by this attester".to_string())
+                        } else if own().count() >= attestation::MAX_ATTESTATIONS_PER_ATTESTER {
+                            Err(format!(
+                                "{} already made {} attestations on this image",
+                                attester_pk,
+                                attestation::MAX_ATTESTATIONS_PER_ATTESTER
+                            ))
+                        } else {
+                            let response = format!("Attested {} on {}", claim, key);
+                            self.attestations.entry(key).or_default().push(Attestation {
+                                attester_pk,
+                                role,
+                                claim,
+                                signature,
+                                height,
+                            });
+                            Ok(response)
+                        }
                     }
+                }
+            }
+
+            ImageAction::SetPaused { paused, admin_signature } => {
+                let message = governance::pause_message(self.admin_log.len(), paused);
+                if !self.authorize_admin(height, message, &admin_signature) {
+                    Err("Invalid admin signature! Pause switch unchanged.".to_string())
+                } else {
+                    self.paused = paused;
+                    match paused {
+                        true => Ok("Contract paused".to_string()),
+                        false => Ok("Contract unpaused".to_string()),
+                    }
+                }
+            }
+
+            ImageAction::RotateAdmin {
+                new_admin,
+                admin_signature,
+            } => {
+                let message = governance::rotate_admin_message(self.admin_log.len(), &new_admin);
+                if let Err(err) = new_admin.validate() {
+                    Err(err)
+                } else if !self.authorize_admin(height, message, &admin_signature) {
+                    Err("Invalid admin signature! Admin keys unchanged.".to_string())
+                } else {
+                    let response = format!("Admin keys rotated to {}", new_admin);
+                    self.admin_keys = Some(new_admin);
+                    Ok(response)
+                }
+            }
+
+            ImageAction::ForceRevoke {
+                image_hash,
+                reason,
+                admin_signature,
+            } => {
+                let message = governance::revoke_message(self.admin_log.len(), &image_hash, &reason);
+                match self.resolve_hash(&image_hash) {
+                    None => Err("Image not found! Nothing revoked.".to_string()),
+                    Some(key) if self.revocations.contains_key(&key) => Err("Image already revoked".to_string()),
+                    Some(_) if reason.trim().is_empty() || reason.len() > governance::MAX_REASON_LEN => {
+                        Err(format!("Reason must be 1 to {} bytes long", governance::MAX_REASON_LEN))
+                    }
+                    Some(key) => {
+                        if !self.authorize_admin(height, message, &admin_signature) {
+                            Err("Invalid admin signature! Nothing revoked.".to_string())
+                        } else {
+                            self.revocations.insert(key, Revocation { reason, height });
+                            Ok(format!("Image revoked: {}", key))
+                        }
+                    }
+                }
+            }
+
+            ImageAction::SetRecoveryKeys { owner_pk, recovery, signature } => {
+                let message = ownership::recovery_message(&owner_pk, &recovery);
+                let owner = ownership::normalize_key(&owner_pk);
+                // A leaked owner key alone must not be able to lock its recovery keys out
+                let by_recovery = match self.recovery_keys.get(&owner) {
+                    Some(current) => current.is_authorized(&message, &signature),
+                    None => true,
+                };
+                if let Err(err) = recovery.validate() {
+                    Err(err)
+                } else if !signature.signed_by(&owner_pk, &message) {
+                    Err("Invalid signature! Recovery keys not set.".to_string())
+                } else if !by_recovery {
+                    Err("Replacing recovery keys needs the current ones to sign! Recovery keys not set.".to_string())
                 } else {
-                    "Original image does not exist!".to_string()
+                    self.recovery_keys.insert(owner, recovery);
+                    Ok(format!("Recovery keys set for {}", owner_pk))
+                }
+            }
+
+            ImageAction::RotateKey {
+                old_pk,
+                new_pk,
+                compromised_since,
+                signature,
+            } => {
+                let message = ownership::rotation_message(&old_pk, &new_pk, compromised_since);
+                let (old, new) = (ownership::normalize_key(&old_pk), ownership::normalize_key(&new_pk));
+                let by_owner = signature.signed_by(&old_pk, &message);
+                let by_recovery = self
+                    .recovery_keys
+                    .get(&old)
+                    .is_some_and(|recovery| recovery.is_authorized(&message, &signature));
+
+                if !by_owner && !by_recovery {
+                    Err("Invalid signature! Key not rotated.".to_string())
+                } else if old == new {
+                    Err("New key must differ from the old key!".to_string())
+                } else if self.hash_map.values().any(|m| m.owner_pk.contains(&old_pk) && m.owner_pk.contains(&new_pk)) {
+                    Err("New key already co-owns an image of the old key!".to_string())
+                } else {
+                    // Every image and publisher grant moves in this single proof. The grants `old_pk` gave keep it
+                    // as their signer, so those given since the compromise become void.
+                    let mut images = 0;
+                    let mut grants = 0;
+                    let mut voided = 0;
+                    for metadata in self.hash_map.values_mut() {
+                        if metadata.owner_pk.replace_key(&old_pk, &new_pk) {
+                            images += 1;
+                        }
+                        if let Some(grant) = metadata.publishers.remove(&old) {
+                            metadata.publishers.insert(new.clone(), grant);
+                            grants += 1;
+                        }
+                        voided += metadata
+                            .publishers
+                            .values()
+                            .filter(|grant| {
+                                compromised_since.is_some_and(|since| grant.granted_at >= since)
+                                    && grant.granted_by.iter().any(|pk| ownership::normalize_key(pk) == old)
+                            })
+                            .count();
+                    }
+                    // Recovery keys follow the key, so they can still take it back from whoever rotated it
+                    if let Some(recovery) = self.recovery_keys.remove(&old) {
+                        self.recovery_keys.insert(new, recovery);
+                    }
+                    if let Some(since) = compromised_since {
+                        self.compromised_keys.insert(old, since);
+                    }
+                    Ok(format!(
+                        "Key {} rotated to {}: {} images and {} publisher grants updated, {} grants void",
+                        old_pk, new_pk, images, grants, voided
+                    ))
                 }
             }
         };
 
-        println!("Execution result: {}", program_output);
+        let program_output = program_output?;
+        self.height = height;
         Ok((program_output, ctx, vec![]))
     }
 }
 
-fn dummy_verify_signature(_pk: String, _message: String, signature: String) -> bool {
-    signature.to_lowercase() == _pk.to_lowercase()
+/// Canonical alias claimed for `image_hash`, recomputed from the image file passed as private input so that
+/// nobody can claim the canonical hash of someone else's image. The error rejects the action.
+fn proven_canonical_hash(
+    image_hash: &ContentHash,
+    canonical_hash: Option<ContentHash>,
+    private_input: &[u8],
+) -> Result<Option<ContentHash>, String> {
+    let Some(canonical_hash) = canonical_hash else {
+        return Ok(None);
+    };
+    if !canonical_hash.is_canonical() {
+        return Err("Canonical hash must be a pixel hash!".to_string());
+    }
+    let image = RawImage::decode(private_input)
+        .map_err(|e| format!("Canonical hash needs the image file as private input: {}", e))?;
+    if !matches_file(image_hash, private_input, &image) {
+        return Err("Private input is not the file of the image hash!".to_string());
+    }
+    if hash::canonical_hash(&image) != canonical_hash {
+        return Err("Canonical hash does not match the image pixels!".to_string());
+    }
+    Ok(Some(canonical_hash))
+}
+
+/// Whether `hash` is the hash of `file`, or the canonical hash of its decoded pixels
+fn matches_file(hash: &ContentHash, file: &[u8], image: &RawImage) -> bool {
+    if hash.is_canonical() {
+        hash::canonical_hash(image) == *hash
+    } else {
+        ContentHash::compute(hash.algorithm, file) == *hash
+    }
 }
 
-#[derive(BorshSerialize, BorshDeserialize, Debug, Clone)]
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
 pub enum ImageAction {
-    RegisterImage { image_hash: String, image_signature: String, owner_pk: String },
-    RegisterEdit { original_image_hash: String, edited_image_hash: String, original_edit_signature: String },
-    AddPublisher { original_image_hash: String, original_image_signature: String, publisher_pk: String },
+    /// `image_hash` is the key of the image, `canonical_hash` an optional `hash::canonical_hash` alias of it.
+    /// Every canonical hash of a registration is recomputed from the image file, passed as private input.
+    RegisterImage {
+        image_hash: ContentHash,
+        canonical_hash: Option<ContentHash>,
+        perceptual_hash: Option<u64>,
+        image_signature: String,
+        owner_pk: OwnerKeys,
+    },
+    RegisterEdit {
+        original_image_hash: ContentHash,
+        edited_image_hash: ContentHash,
+        edited_canonical_hash: Option<ContentHash>,
+        original_edit_signature: MultiSignature,
+        /// Made with a generative tool, recorded as an AI-assisted edit
+        generative: bool,
+    },
+    AddPublisher { original_image_hash: ContentHash, original_image_signature: MultiSignature, publisher_pk: String },
+    /// Edit proven from the pixels passed as `TransformWitness` in the private input
+    RegisterProvenEdit { original_image_hash: ContentHash, edited_image_hash: ContentHash, transformation: Transformation },
+    /// Original registered with the signature of a trusted capture device over its hash and capture metadata
+    RegisterCapturedImage {
+        image_hash: ContentHash,
+        canonical_hash: Option<ContentHash>,
+        perceptual_hash: Option<u64>,
+        owner_pk: OwnerKeys,
+        capture: CaptureMetadata,
+        device_pk: String,
+        device_signature: String,
+    },
+    /// Output of a generator, signed by a model key registered with `SetGeneratorModel`
+    RegisterGeneratedImage {
+        image_hash: ContentHash,
+        canonical_hash: Option<ContentHash>,
+        perceptual_hash: Option<u64>,
+        owner_pk: OwnerKeys,
+        model_pk: String,
+        model_signature: String,
+    },
+    /// Registers `model_pk` as a key of `model`, or removes it with `None`. Signed by the admin keys
+    SetGeneratorModel { model_pk: String, model: Option<GeneratorModel>, admin_signature: MultiSignature },
+    /// Trusts `device_pk` as `device`, or distrusts it with `None`. Signed by the admin keys
+    SetTrustedDevice { device_pk: String, device: Option<TrustedDevice>, admin_signature: MultiSignature },
+    /// Grants `role` to `attester_pk`, or revokes it with `None`. Signed by the admin keys
+    SetAttester { attester_pk: String, role: Option<AttesterRole>, admin_signature: MultiSignature },
+    /// Signed claim of a registered attester about an image it doesn't need to own
+    Attest { image_hash: ContentHash, claim: Claim, attester_pk: String, signature: String },
+    /// Stops, or resumes, every non-admin action. Signed by the admin keys
+    SetPaused { paused: bool, admin_signature: MultiSignature },
+    /// Replaces the admin key set. Signed by the current admin keys
+    RotateAdmin { new_admin: OwnerKeys, admin_signature: MultiSignature },
+    /// Revokes an abusive registration, and the edits made from it, with a public reason. Signed by the admin keys
+    ForceRevoke { image_hash: ContentHash, reason: String, admin_signature: MultiSignature },
+    /// Pre-registers the keys allowed to rotate `owner_pk` if it is lost, signed by `owner_pk`. Replacing them
+    /// is also signed by the current recovery keys.
+    SetRecoveryKeys { owner_pk: String, recovery: OwnerKeys, signature: MultiSignature },
+    /// Re-points every image and publisher grant of `old_pk` to `new_pk`. Signed by `old_pk`, or by its
+    /// recovery keys. With `compromised_since`, edits `old_pk` authorized from that height on get flagged, and the
+    /// publisher grants it gave from then on are void: they authorize no more edits and flag the ones they did.
+    RotateKey { old_pk: String, new_pk: String, compromised_since: Option<u64>, signature: MultiSignature },
+}
+
+impl ImageMetadata {
+    fn original(owner_pk: OwnerKeys, perceptual_hash: Option<u64>, registered_at: u64, provenance: ProvenanceClass) -> Self {
+        ImageMetadata {
+            previous_image_hash: None,
+            owner_pk,
+            publishers: HashMap::new(),
+            is_root: true,
+            transformation: None,
+            perceptual_hash,
+            registered_at,
+            signed_by: vec![],
+            capture: None,
+            provenance,
+        }
+    }
+}
+
+impl ImageState {
+    fn register_original(
+        &mut self,
+        image_hash: ContentHash,
+        canonical_hash: Option<ContentHash>,
+        metadata: ImageMetadata,
+    ) -> Result<String, String> {
+        let known = self.resolve_hash(&image_hash).is_some()
+            || canonical_hash.is_some_and(|h| self.resolve_hash(&h).is_some());
+        if let Err(err) = metadata.owner_pk.validate() {
+            Err(err)
+        } else if !known {
+            self.hash_map.insert(image_hash, metadata);
+            if let Some(canonical_hash) = canonical_hash {
+                self.canonical_hashes.insert(canonical_hash, image_hash);
+            }
+            Ok(format!("Image registered: {}", image_hash))
+        } else {
+            Err("Image already registered! Nothing added.".to_string())
+        }
+    }
+
+    /// Whether one of `signers` was declared compromised at or before `height`
+    fn is_compromised(&self, signers: &[String], height: u64) -> bool {
+        signers.iter().any(|pk| {
+            self.compromised_keys
+                .get(&ownership::normalize_key(pk))
+                .is_some_and(|since| height >= *since)
+        })
+    }
+
+    /// Checks the admin threshold on `message` and logs the action when it is met
+    fn authorize_admin(&mut self, height: u64, message: String, signature: &MultiSignature) -> bool {
+        let Some(admin) = self.admin_keys.as_ref().filter(|admin| admin.is_authorized(&message, signature)) else {
+            return false;
+        };
+        self.admin_log.push(AdminLogEntry {
+            height,
+            signers: admin.signers(&message, signature),
+            action: message,
+        });
+        true
+    }
 }
 
 /// Utils function for the host
 impl ImageState {
+    /// Versioned state passed to the guest as `ContractInput::state`
     pub fn as_bytes(&self) -> Result<Vec<u8>, Error> {
-        borsh::to_vec(self)
+        Ok(self.to_versioned_bytes())
+    }
+
+    /// Key of the image in `hash_map`, `img_hash` being either its key or its canonical hash
+    pub fn resolve_hash(&self, img_hash: &ContentHash) -> Option<ContentHash> {
+        if self.hash_map.contains_key(img_hash) {
+            Some(*img_hash)
+        } else {
+            self.canonical_hashes.get(img_hash).copied()
+        }
     }
 
-    pub fn is_original_image(&self, img_hash: String) -> Result<bool, Error> {
-        Ok(self.hash_map.contains_key(&img_hash) && self.hash_map[&img_hash].is_root)
+    pub fn is_original_image(&self, img_hash: ContentHash) -> Result<bool, Error> {
+        Ok(self.revocation(img_hash).is_none()
+            && self.resolve_hash(&img_hash).is_some_and(|key| self.hash_map[&key].is_root))
     }
-    pub fn is_edit_image(&self, img_hash: String) -> Result<bool, Error> {
-        Ok(self.hash_map.contains_key(&img_hash) && !self.hash_map[&img_hash].is_root)
+    pub fn is_edit_image(&self, img_hash: ContentHash) -> Result<bool, Error> {
+        Ok(self.revocation(img_hash).is_none()
+            && self.resolve_hash(&img_hash).is_some_and(|key| !self.hash_map[&key].is_root))
     }
-    pub fn find_original_image(&self, mut img_hash: String) -> Result<String, Error> {
+    /// Revocation of the image or of an image it was edited from. Revoked images don't verify anymore.
+    pub fn revocation(&self, img_hash: ContentHash) -> Option<(ContentHash, &Revocation)> {
+        self.lineage(img_hash)
+            .into_iter()
+            .find_map(|key| self.revocations.get(&key).map(|revocation| (key, revocation)))
+    }
+    /// Capture attestation of an original, if its device is still trusted and the image isn't revoked: the
+    /// image is camera-attested when this is set.
+    pub fn camera_attestation(&self, img_hash: ContentHash) -> Option<&CaptureAttestation> {
+        if self.revocation(img_hash).is_some() {
+            return None;
+        }
+        let key = self.resolve_hash(&img_hash)?;
+        let metadata = &self.hash_map[&key];
+        metadata
+            .capture
+            .as_ref()
+            .filter(|capture| {
+                metadata.is_root && self.trusted_devices.contains_key(&ownership::normalize_key(&capture.device_pk))
+            })
+    }
+
+    /// Whether the image was authorized by a key after that key was declared compromised, or through a publisher
+    /// grant such a key gave
+    pub fn is_flagged(&self, img_hash: ContentHash) -> bool {
+        let Some(key) = self.resolve_hash(&img_hash) else {
+            return false;
+        };
+        let metadata = &self.hash_map[&key];
+        let grants = self.find_original_image(key).map(|root| &self.hash_map[&root].publishers);
+        self.is_compromised(&metadata.signed_by, metadata.registered_at)
+            || grants.is_ok_and(|grants| {
+                metadata.signed_by.iter().any(|pk| {
+                    grants
+                        .get(&ownership::normalize_key(pk))
+                        .is_some_and(|grant| self.is_compromised(&grant.granted_by, grant.granted_at))
+                })
+            })
+    }
+
+    /// Keys of the image and of every image it was edited from, up to the original
+    pub fn lineage(&self, img_hash: ContentHash) -> Vec<ContentHash> {
+        let mut lineage = vec![];
+        let mut next = self.resolve_hash(&img_hash);
+        while let Some(key) = next.filter(|key| !lineage.contains(key)) {
+            lineage.push(key);
+            next = self.hash_map.get(&key).and_then(|metadata| metadata.previous_image_hash);
+        }
+        lineage
+    }
+
+    /// Attestations about the image and its lineage, the image's own first
+    pub fn attestations_for(&self, img_hash: ContentHash) -> Vec<LineageAttestation> {
+        self.lineage(img_hash)
+            .into_iter()
+            .flat_map(|image_hash| {
+                self.attestations.get(&image_hash).into_iter().flatten().map(move |attestation| LineageAttestation {
+                    image_hash,
+                    attestation: attestation.clone(),
+                })
+            })
+            .collect()
+    }
+
+    /// First AI-generated image or AI-assisted edit met walking from the image up to its original
+    pub fn ai_disclosure(&self, img_hash: ContentHash) -> Option<AiDisclosure> {
+        let lineage = self.lineage(img_hash);
+        let own = lineage.first().copied();
+        lineage.into_iter().find_map(|image_hash| {
+            let provenance = &self.hash_map.get(&image_hash)?.provenance;
+            provenance.is_generative().then(|| AiDisclosure {
+                image_hash,
+                provenance: provenance.clone(),
+                inherited: Some(image_hash) != own,
+            })
+        })
+    }
+
+    pub fn find_original_image(&self, img_hash: ContentHash) -> Result<ContentHash, Error> {
+        let mut img_hash = self.resolve_hash(&img_hash).unwrap_or(img_hash);
         while let Some(metadata) = self.hash_map.get(&img_hash) {
             if metadata.is_root {
                 return Ok(img_hash); // Found the original image
             }
             // Move to the previous image in the chain (if exists)
-            if let Some(prev_hash) = &metadata.previous_image_hash {
-                img_hash = prev_hash.clone();
+            if let Some(prev_hash) = metadata.previous_image_hash {
+                img_hash = prev_hash;
             } else {
                 break; // Stop if no previous image exists (should not happen)
             }
@@ -158,6 +843,19 @@ impl ImageState {
 }
 
 impl ImageAction {
+    /// Actions signed by the admin keys, still executed while the contract is paused
+    pub fn is_admin(&self) -> bool {
+        matches!(
+            self,
+            ImageAction::SetPaused { .. }
+                | ImageAction::RotateAdmin { .. }
+                | ImageAction::ForceRevoke { .. }
+                | ImageAction::SetTrustedDevice { .. }
+                | ImageAction::SetAttester { .. }
+                | ImageAction::SetGeneratorModel { .. }
+        )
+    }
+
     pub fn as_blob(&self, contract_name: &str) -> sdk::Blob {
         sdk::Blob {
             contract_name: contract_name.into(),
@@ -171,14 +869,411 @@ impl ImageAction {
 /// while storing the full-state off-chain
 impl Digestable for ImageState {
     fn as_digest(&self) -> sdk::StateDigest {
-        sdk::StateDigest(borsh::to_vec(self).expect("Failed to encode ImageState"))
+        sdk::StateDigest(self.to_versioned_bytes())
     }
 }
 
-impl From<sdk::StateDigest> for ImageState {
-    fn from(state: sdk::StateDigest) -> Self {
-        borsh::from_slice(&state.0)
-            .map_err(|_| "Could not decode hyllar state".to_string())
-            .unwrap()
+/// Decodes any previous version of the state, migrated forward
+impl TryFrom<sdk::StateDigest> for ImageState {
+    type Error = String;
+
+    fn try_from(state: sdk::StateDigest) -> Result<Self, Self::Error> {
+        ImageState::from_versioned_bytes(&state.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ADMIN: &str = "admin";
+
+    /// Executes `action` on `state` as the guest would
+    fn run(state: &mut ImageState, action: ImageAction) -> Result<String, String> {
+        let input = sdk::ContractInput {
+            state: state.as_bytes().unwrap(),
+            identity: "tester.image_state".into(),
+            tx_hash: sdk::TxHash(format!("tx-{}", state.height)),
+            private_input: vec![],
+            tx_ctx: None,
+            blobs: vec![action.as_blob("image_state")],
+            index: sdk::BlobIndex(0),
+        };
+        state.execute(&input).map(|(output, _, _)| output)
+    }
+
+    /// Signature of each of `pks`, on any message
+    fn signed(pks: &[&str]) -> MultiSignature {
+        MultiSignature(
+            pks.iter()
+                .map(|pk| ownership::PartialSignature {
+                    pk: pk.to_string(),
+                    signature: ownership::dummy_sign(pk, ""),
+                })
+                .collect(),
+        )
+    }
+
+    /// State administered by `ADMIN`, with `owner` owning the original `image`
+    fn registry() -> ImageState {
+        let mut state = ImageState {
+            admin_keys: Some(OwnerKeys::single(ADMIN.to_string())),
+            ..ImageState::default()
+        };
+        run(&mut state, register("image", "owner")).unwrap();
+        state
+    }
+
+    fn image(name: &str) -> ContentHash {
+        hash::file_hash(name.as_bytes())
+    }
+
+    fn register(name: &str, owner: &str) -> ImageAction {
+        ImageAction::RegisterImage {
+            image_hash: image(name),
+            canonical_hash: None,
+            perceptual_hash: None,
+            image_signature: String::new(),
+            owner_pk: OwnerKeys::single(owner.to_string()),
+        }
+    }
+
+    fn attest(claim: Claim, attester_pk: &str) -> ImageAction {
+        ImageAction::Attest {
+            image_hash: image("image"),
+            claim,
+            attester_pk: attester_pk.to_string(),
+            signature: attester_pk.to_string(),
+        }
+    }
+
+    fn set_attester(attester_pk: &str, role: Option<AttesterRole>) -> ImageAction {
+        ImageAction::SetAttester {
+            attester_pk: attester_pk.to_string(),
+            role,
+            admin_signature: signed(&[ADMIN]),
+        }
+    }
+
+    fn add_publisher(publisher_pk: &str, by: &str) -> ImageAction {
+        ImageAction::AddPublisher {
+            original_image_hash: image("image"),
+            original_image_signature: signed(&[by]),
+            publisher_pk: publisher_pk.to_string(),
+        }
+    }
+
+    fn edit(name: &str, by: &str) -> ImageAction {
+        ImageAction::RegisterEdit {
+            original_image_hash: image("image"),
+            edited_image_hash: image(name),
+            edited_canonical_hash: None,
+            original_edit_signature: signed(&[by]),
+            generative: false,
+        }
+    }
+
+    fn rotate(old_pk: &str, new_pk: &str, compromised_since: Option<u64>, by: &[&str]) -> ImageAction {
+        ImageAction::RotateKey {
+            old_pk: old_pk.to_string(),
+            new_pk: new_pk.to_string(),
+            compromised_since,
+            signature: signed(by),
+        }
+    }
+
+    fn trust_device(device_pk: &str, trusted: bool) -> ImageAction {
+        let device = TrustedDevice {
+            manufacturer: "Acme".to_string(),
+            model: "X1".to_string(),
+        };
+        ImageAction::SetTrustedDevice {
+            device_pk: device_pk.to_string(),
+            device: trusted.then_some(device),
+            admin_signature: signed(&[ADMIN]),
+        }
+    }
+
+    fn capture(name: &str, device_pk: &str, device_signature: &str) -> ImageAction {
+        ImageAction::RegisterCapturedImage {
+            image_hash: image(name),
+            canonical_hash: None,
+            perceptual_hash: None,
+            owner_pk: OwnerKeys::single("owner".to_string()),
+            capture: CaptureMetadata {
+                captured_at: 1_700_000_000,
+                location: None,
+            },
+            device_pk: device_pk.to_string(),
+            device_signature: device_signature.to_string(),
+        }
+    }
+
+    #[test]
+    fn capture_by_a_trusted_device_is_camera_attested() {
+        let mut state = registry();
+        run(&mut state, trust_device("Camera", true)).unwrap();
+        run(&mut state, capture("photo", "camera", "camera")).unwrap();
+        assert!(state.is_original_image(image("photo")).unwrap());
+        let attestation = state.camera_attestation(image("photo")).unwrap();
+        assert_eq!(attestation.device_pk, "camera");
+        assert_eq!(attestation.metadata.captured_at, 1_700_000_000);
+        assert_eq!(state.hash_map[&image("photo")].provenance, ProvenanceClass::Captured);
+        // Declared originals and edits of a capture aren't attested
+        assert!(state.is_original_image(image("image")).unwrap());
+        assert!(state.camera_attestation(image("image")).is_none());
+        let edit = ImageAction::RegisterEdit {
+            original_image_hash: image("photo"),
+            edited_image_hash: image("crop"),
+            edited_canonical_hash: None,
+            original_edit_signature: signed(&["owner"]),
+            generative: false,
+        };
+        run(&mut state, edit).unwrap();
+        assert!(state.camera_attestation(image("crop")).is_none());
+    }
+
+    #[test]
+    fn capture_attestation_ends_with_the_device_trust_or_the_image() {
+        let mut state = registry();
+        run(&mut state, trust_device("camera", true)).unwrap();
+        run(&mut state, capture("photo", "camera", "camera")).unwrap();
+        run(&mut state, capture("other", "camera", "camera")).unwrap();
+
+        run(&mut state, trust_device("CAMERA", false)).unwrap();
+        assert!(state.is_original_image(image("photo")).unwrap());
+        assert!(state.camera_attestation(image("photo")).is_none());
+        assert!(run(&mut state, capture("late", "camera", "camera")).unwrap_err().contains("not trusted"));
+        // Trusted again, the attestation stored at capture time counts again
+        run(&mut state, trust_device("camera", true)).unwrap();
+        assert!(state.camera_attestation(image("photo")).is_some());
+
+        let revoke = ImageAction::ForceRevoke {
+            image_hash: image("photo"),
+            reason: "staged".to_string(),
+            admin_signature: signed(&[ADMIN]),
+        };
+        run(&mut state, revoke).unwrap();
+        assert!(!state.is_original_image(image("photo")).unwrap());
+        assert!(state.camera_attestation(image("photo")).is_none());
+        assert!(state.camera_attestation(image("other")).is_some());
+    }
+
+    #[test]
+    fn capture_needs_a_valid_signature_of_a_trusted_device() {
+        let mut state = registry();
+        run(&mut state, trust_device("camera", true)).unwrap();
+        let error = run(&mut state, capture("photo", "camera", "forged")).unwrap_err();
+        assert!(error.contains("Invalid device signature"), "{}", error);
+        assert!(run(&mut state, capture("photo", "webcam", "webcam")).unwrap_err().contains("not trusted"));
+        assert!(!state.is_original_image(image("photo")).unwrap());
+        assert!(state.camera_attestation(image("photo")).is_none());
+        let forged_trust = ImageAction::SetTrustedDevice {
+            device_pk: "webcam".to_string(),
+            device: None,
+            admin_signature: signed(&["thief"]),
+        };
+        assert!(run(&mut state, forged_trust).is_err());
+    }
+
+    #[test]
+    fn rejected_actions_leave_the_state_untouched() {
+        let mut state = registry();
+        let before = state.as_digest();
+        assert!(run(&mut state, register("image", "thief")).is_err());
+        assert_eq!(state.as_digest(), before);
+        assert_eq!(state.height, 1);
+    }
+
+    #[test]
+    fn editors_cant_claim_a_verdict_as_free_text() {
+        let mut state = registry();
+        run(&mut state, set_attester("Editor", Some(AttesterRole::Editor))).unwrap();
+        let verdicts = [
+            Claim::MisleadingContext,
+            Claim::Other("misleading-context".into()),
+            Claim::Other(" AI-generated".into()),
+        ];
+        for claim in verdicts {
+            assert!(run(&mut state, attest(claim.clone(), "editor")).is_err(), "{:?}", claim);
+        }
+        run(&mut state, attest(Claim::Other("shot at dawn".into()), "EDITOR")).unwrap();
+        assert_eq!(state.attestations_for(image("image")).len(), 1);
+    }
+
+    #[test]
+    fn attester_keys_are_compared_regardless_of_case() {
+        let mut state = registry();
+        run(&mut state, set_attester("CHECKER", Some(AttesterRole::FactChecker))).unwrap();
+        run(&mut state, attest(Claim::AiGenerated, "checker")).unwrap();
+        assert!(run(&mut state, attest(Claim::AiGenerated, "Checker")).is_err());
+        for n in 1..attestation::MAX_ATTESTATIONS_PER_ATTESTER {
+            run(&mut state, attest(Claim::Other(format!("note {}", n)), "Checker")).unwrap();
+        }
+        assert!(run(&mut state, attest(Claim::Other("one too many".into()), "checker")).is_err());
+
+        run(&mut state, set_attester("checker", None)).unwrap();
+        assert!(state.attesters.is_empty());
+        assert!(run(&mut state, attest(Claim::VerifiedLocation, "CHECKER")).is_err());
+    }
+
+    #[test]
+    fn rotation_moves_owned_images_and_held_grants() {
+        let mut state = registry();
+        run(&mut state, add_publisher("Publisher", "owner")).unwrap();
+        assert!(run(&mut state, rotate("owner", "owner2", None, &["thief"])).is_err());
+        assert!(run(&mut state, rotate("owner", "OWNER", None, &["owner"])).is_err());
+
+        run(&mut state, rotate("owner", "owner2", None, &["owner"])).unwrap();
+        run(&mut state, rotate("publisher", "publisher2", None, &["publisher"])).unwrap();
+        assert!(state.hash_map[&image("image")].owner_pk.contains("owner2"));
+        assert!(run(&mut state, add_publisher("other", "owner")).is_err());
+        assert!(run(&mut state, edit("by-old-publisher", "publisher")).is_err());
+        run(&mut state, edit("by-new-publisher", "publisher2")).unwrap();
+        run(&mut state, add_publisher("other", "owner2")).unwrap();
+        assert!(state.compromised_keys.is_empty());
+        assert!(!state.is_flagged(image("by-new-publisher")));
+    }
+
+    #[test]
+    fn compromise_flags_what_the_key_authorized_since_and_voids_its_grants() {
+        let mut state = registry();
+        let recovery = ImageAction::SetRecoveryKeys {
+            owner_pk: "owner".into(),
+            recovery: OwnerKeys::single("rescuer".into()),
+            signature: signed(&["owner"]),
+        };
+        run(&mut state, recovery).unwrap();
+        run(&mut state, add_publisher("early", "owner")).unwrap();
+        run(&mut state, edit("by-early", "early")).unwrap();
+        run(&mut state, edit("by-owner", "owner")).unwrap();
+        // The thief holds the owner key from here on
+        let since = state.height + 1;
+        run(&mut state, add_publisher("accomplice", "owner")).unwrap();
+        run(&mut state, edit("by-accomplice", "accomplice")).unwrap();
+        run(&mut state, edit("by-thief", "owner")).unwrap();
+        assert!(!state.is_flagged(image("by-thief")));
+
+        // Only the recovery keys can also declare the compromise
+        assert!(run(&mut state, rotate("owner", "owner2", Some(since), &["thief"])).is_err());
+        run(&mut state, rotate("owner", "owner2", Some(since), &["rescuer"])).unwrap();
+        assert!(!state.is_flagged(image("by-early")));
+        assert!(!state.is_flagged(image("by-owner")));
+        assert!(state.is_flagged(image("by-accomplice")));
+        assert!(state.is_flagged(image("by-thief")));
+        assert_eq!(state.compromised_keys["owner"], since);
+        assert!(state.recovery_keys.contains_key("owner2"));
+
+        assert!(run(&mut state, edit("late-accomplice", "accomplice")).is_err());
+        assert!(run(&mut state, edit("late-thief", "owner")).is_err());
+        run(&mut state, edit("late-early", "early")).unwrap();
+        run(&mut state, edit("late-owner", "owner2")).unwrap();
+        assert!(!state.is_flagged(image("late-early")));
+        assert!(!state.is_flagged(image("late-owner")));
+    }
+
+    fn pause(paused: bool, by: &[&str]) -> ImageAction {
+        ImageAction::SetPaused {
+            paused,
+            admin_signature: signed(by),
+        }
+    }
+
+    fn revoke(name: &str, reason: &str, by: &[&str]) -> ImageAction {
+        ImageAction::ForceRevoke {
+            image_hash: image(name),
+            reason: reason.to_string(),
+            admin_signature: signed(by),
+        }
+    }
+
+    fn actions(state: &ImageState) -> Vec<&str> {
+        state.admin_log.iter().map(|entry| entry.action.as_str()).collect()
+    }
+
+    #[test]
+    fn admin_messages_are_bound_to_the_next_nonce() {
+        let mut state = registry();
+        assert!(run(&mut state, pause(true, &["intruder"])).is_err());
+        assert!(state.admin_log.is_empty());
+
+        run(&mut state, pause(true, &[ADMIN])).unwrap();
+        run(&mut state, pause(false, &[ADMIN])).unwrap();
+        assert_eq!(actions(&state), vec!["0:pause", "1:unpause"]);
+        assert_eq!(state.admin_log[0].signers, vec![ADMIN]);
+        assert_eq!(state.admin_log[0].height, 2);
+
+        // The placeholder signatures verify any message, so check what a replayed signature is verified against:
+        // the message of the next nonce, which isn't the one it signed
+        run(&mut state, pause(true, &[ADMIN])).unwrap();
+        assert_eq!(actions(&state)[2], governance::pause_message(2, true));
+        assert_ne!(governance::pause_message(0, true), governance::pause_message(2, true));
+    }
+
+    #[test]
+    fn paused_contract_only_executes_admin_actions() {
+        let mut state = registry();
+        run(&mut state, pause(true, &[ADMIN])).unwrap();
+        let paused = Err("Contract is paused! Only admin actions are executed.".to_string());
+        assert_eq!(run(&mut state, register("other", "owner")), paused);
+        assert_eq!(run(&mut state, edit("edit", "owner")), paused);
+        assert_eq!(run(&mut state, add_publisher("publisher", "owner")), paused);
+        assert_eq!(run(&mut state, rotate("owner", "owner2", None, &["owner"])), paused);
+        assert!(state.resolve_hash(&image("other")).is_none());
+
+        run(&mut state, set_attester("fact-checker", Some(AttesterRole::FactChecker))).unwrap();
+        run(&mut state, revoke("image", "stolen", &[ADMIN])).unwrap();
+        run(&mut state, pause(false, &[ADMIN])).unwrap();
+        run(&mut state, register("other", "owner")).unwrap();
+    }
+
+    #[test]
+    fn rotated_admin_keys_replace_the_old_ones() {
+        let new_admin: OwnerKeys = "2/a,b,c".parse().unwrap();
+        let rotate_admin = |new_admin: &OwnerKeys, by: &[&str]| ImageAction::RotateAdmin {
+            new_admin: new_admin.clone(),
+            admin_signature: signed(by),
+        };
+        let mut state = registry();
+        let unreachable = OwnerKeys {
+            threshold: 4,
+            ..new_admin.clone()
+        };
+        assert!(run(&mut state, rotate_admin(&unreachable, &[ADMIN])).is_err());
+        assert!(run(&mut state, rotate_admin(&new_admin, &["a", "b"])).is_err());
+        run(&mut state, rotate_admin(&new_admin, &[ADMIN])).unwrap();
+        assert_eq!(state.admin_keys, Some(new_admin.clone()));
+        assert_eq!(actions(&state), vec![governance::rotate_admin_message(0, &new_admin)]);
+
+        // The old key is out and the new ones need their threshold
+        assert!(run(&mut state, pause(true, &[ADMIN])).is_err());
+        assert!(run(&mut state, pause(true, &["a"])).is_err());
+        assert!(run(&mut state, pause(true, &["a", "a"])).is_err());
+        run(&mut state, pause(true, &["c", "A"])).unwrap();
+        assert!(state.paused);
+        assert_eq!(state.admin_log[1].signers, vec!["a", "c"]);
+    }
+
+    #[test]
+    fn force_revoke_needs_the_admin_and_a_public_reason() {
+        let mut state = registry();
+        run(&mut state, edit("edit", "owner")).unwrap();
+        assert!(run(&mut state, revoke("image", "stolen", &["owner"])).is_err());
+        assert!(run(&mut state, revoke("image", " ", &[ADMIN])).is_err());
+        let too_long = "x".repeat(governance::MAX_REASON_LEN + 1);
+        assert!(run(&mut state, revoke("image", &too_long, &[ADMIN])).is_err());
+        assert!(run(&mut state, revoke("unknown", "stolen", &[ADMIN])).is_err());
+        assert!(state.admin_log.is_empty());
+
+        run(&mut state, revoke("image", "stolen", &[ADMIN])).unwrap();
+        let (revoked, revocation) = state.revocation(image("edit")).unwrap();
+        assert_eq!(revoked, image("image"));
+        assert_eq!(revocation.reason, "stolen");
+        assert!(!state.is_original_image(image("image")).unwrap());
+        assert!(!state.is_edit_image(image("edit")).unwrap());
+        assert!(run(&mut state, revoke("image", "again", &[ADMIN])).is_err());
+        assert_eq!(actions(&state), vec![governance::revoke_message(0, &image("image"), "stolen")]);
+        // Revoked images can't be edited anymore
+        assert!(run(&mut state, edit("late-edit", "owner")).is_err());
     }
 }
@@ -0,0 +1,363 @@
+use std::collections::BTreeSet;
+use std::fmt;
+use std::str::FromStr;
+use borsh::{BorshDeserialize, BorshSerialize};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::hash::ContentHash;
+
+/// M-of-N key set owning an image. A single key is the 1-of-1 set.
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq, Eq)]
+pub struct OwnerKeys {
+    pub threshold: u32,
+    pub keys: BTreeSet<String>,
+}
+
+/// Right to register edits of an original, granted by its owners with `AddPublisher`
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct PublisherGrant {
+    /// Contract height of the `AddPublisher`
+    pub granted_at: u64,
+    /// Owner keys whose signature granted it
+    pub granted_by: Vec<String>,
+}
+
+/// Signature of one key, as collected offline from each co-owner
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq, Eq)]
+pub struct PartialSignature {
+    pub pk: String,
+    pub signature: String,
+}
+
+/// Every partial signature of a submission. A legacy single signature has an empty `pk`
+/// and is checked against each key: a signature only verifies for the key that made it, so it still
+/// counts for one key at most, and a key signing twice is still one signer.
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct MultiSignature(pub Vec<PartialSignature>);
+
+impl OwnerKeys {
+    pub fn single(pk: String) -> Self {
+        OwnerKeys {
+            threshold: 1,
+            keys: BTreeSet::from([pk]),
+        }
+    }
+
+    /// Keys are compared regardless of case, so `AB..` and `ab..` in one set would count one signature twice
+    pub fn validate(&self) -> Result<(), String> {
+        if self.threshold == 0 || self.threshold as usize > self.keys.len() {
+            return Err(format!("Threshold {} is invalid for {} keys", self.threshold, self.keys.len()));
+        }
+        let distinct: BTreeSet<String> = self.keys.iter().map(|key| normalize_key(key)).collect();
+        if distinct.len() != self.keys.len() {
+            return Err("Owner keys must be distinct, regardless of case".to_string());
+        }
+        Ok(())
+    }
+
+    pub fn contains(&self, pk: &str) -> bool {
+        self.keys.iter().any(|key| key.eq_ignore_ascii_case(pk))
+    }
+
+    /// Whether at least `threshold` distinct keys of the set signed `message`
+    pub fn is_authorized(&self, message: &str, signatures: &MultiSignature) -> bool {
+        self.signers(message, signatures).len() >= self.threshold as usize
+    }
+
+    /// Keys of the set that signed `message`
+    pub fn signers(&self, message: &str, signatures: &MultiSignature) -> Vec<String> {
+        self.keys
+            .iter()
+            .filter(|key| signatures.signed_by(key, message))
+            .cloned()
+            .collect()
+    }
+
+    /// Swaps `old_pk` for `new_pk`, returns whether the set contained `old_pk`
+    pub fn replace_key(&mut self, old_pk: &str, new_pk: &str) -> bool {
+        let Some(old) = self.keys.iter().find(|key| key.eq_ignore_ascii_case(old_pk)).cloned() else {
+            return false;
+        };
+        self.keys.remove(&old);
+        self.keys.insert(new_pk.to_string());
+        true
+    }
+}
+
+impl MultiSignature {
+    /// Whether one of the partial signatures is a valid signature of `message` by `pk`
+    pub fn signed_by(&self, pk: &str, message: &str) -> bool {
+        self.0.iter().any(|partial| {
+            (partial.pk.is_empty() || partial.pk.eq_ignore_ascii_case(pk))
+                && dummy_verify_signature(pk, message, &partial.signature)
+        })
+    }
+
+    /// Merges partial signatures collected separately, keeping one per key. Bare signatures don't name
+    /// their key, so only exact duplicates of them are dropped.
+    pub fn combine(parts: impl IntoIterator<Item = MultiSignature>) -> Self {
+        let mut combined: Vec<PartialSignature> = vec![];
+        for partial in parts.into_iter().flat_map(|part| part.0) {
+            let duplicate = |p: &PartialSignature| match partial.pk.is_empty() {
+                true => p.pk.is_empty() && p.signature == partial.signature,
+                false => p.pk.eq_ignore_ascii_case(&partial.pk),
+            };
+            if !combined.iter().any(duplicate) {
+                combined.push(partial);
+            }
+        }
+        MultiSignature(combined)
+    }
+}
+
+/// Keys are hex, so their case doesn't matter: this is the form compared, and stored as the key of the
+/// registries indexed by public key (publishers, recovery and compromised keys, devices, attesters and models)
+pub fn normalize_key(pk: &str) -> String {
+    pk.to_ascii_lowercase()
+}
+
+/// Message owners sign to grant publishing rights on an image
+pub fn add_publisher_message(original_image_hash: &ContentHash) -> String {
+    original_image_hash.to_string()
+}
+
+/// Message owners or publishers sign to register an edit
+pub fn edit_message(original_image_hash: &ContentHash, edited_image_hash: &ContentHash) -> String {
+    format!("{}{}", original_image_hash, edited_image_hash)
+}
+
+/// Message an owner signs to pre-register its recovery keys. Replacing registered recovery keys also needs
+/// the signatures of the registered ones.
+pub fn recovery_message(owner_pk: &str, recovery: &OwnerKeys) -> String {
+    format!("recovery:{}:{}", owner_pk, recovery)
+}
+
+/// Message the old key, or its recovery keys, sign to rotate to a new key
+pub fn rotation_message(old_pk: &str, new_pk: &str, compromised_since: Option<u64>) -> String {
+    match compromised_since {
+        Some(height) => format!("rotate:{}:{}:{}", old_pk, new_pk, height),
+        None => format!("rotate:{}:{}", old_pk, new_pk),
+    }
+}
+
+/// Counterpart of `dummy_verify_signature`, for the CLI to produce partial signatures
+pub fn dummy_sign(pk: &str, _message: &str) -> String {
+    pk.to_string()
+}
+
+pub fn dummy_verify_signature(pk: &str, _message: &str, signature: &str) -> bool {
+    signature.to_lowercase() == pk.to_lowercase()
+}
+
+/// `pk` for a single key, `<threshold>/<pk>,<pk>,...` for a key set
+impl FromStr for OwnerKeys {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let owner = match s.split_once('/') {
+            Some((threshold, keys)) => OwnerKeys {
+                threshold: threshold.trim().parse().map_err(|e| format!("Invalid threshold '{}': {}", threshold, e))?,
+                keys: keys.split(',').map(|k| k.trim().to_string()).filter(|k| !k.is_empty()).collect(),
+            },
+            None => OwnerKeys::single(s.trim().to_string()),
+        };
+        owner.validate()?;
+        Ok(owner)
+    }
+}
+
+impl fmt::Display for OwnerKeys {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.threshold, self.keys.len()) {
+            (1, 1) => write!(f, "{}", self.keys.iter().next().unwrap()),
+            _ => write!(f, "{}/{}", self.threshold, self.keys.iter().cloned().collect::<Vec<_>>().join(",")),
+        }
+    }
+}
+
+/// `<pk>:<signature>` pairs separated by commas, or a single bare signature
+impl FromStr for MultiSignature {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let partials = s
+            .split(',')
+            .map(str::trim)
+            .filter(|part| !part.is_empty())
+            .map(|part| match part.split_once(':') {
+                Some((pk, signature)) => PartialSignature {
+                    pk: pk.to_string(),
+                    signature: signature.to_string(),
+                },
+                None => PartialSignature {
+                    pk: String::new(),
+                    signature: part.to_string(),
+                },
+            })
+            .collect::<Vec<_>>();
+        if partials.is_empty() {
+            return Err("Missing signature".to_string());
+        }
+        Ok(MultiSignature(partials))
+    }
+}
+
+impl fmt::Display for MultiSignature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let parts: Vec<String> = self
+            .0
+            .iter()
+            .map(|p| if p.pk.is_empty() { p.signature.clone() } else { format!("{}:{}", p.pk, p.signature) })
+            .collect();
+        write!(f, "{}", parts.join(","))
+    }
+}
+
+/// Owners travel as their text form in JSON, so a plain public key string keeps working
+impl Serialize for OwnerKeys {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for OwnerKeys {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = <String as Deserialize>::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(feature = "schemars")]
+impl schemars::JsonSchema for OwnerKeys {
+    fn schema_name() -> std::borrow::Cow<'static, str> {
+        "OwnerKeys".into()
+    }
+
+    fn json_schema(_: &mut schemars::SchemaGenerator) -> schemars::Schema {
+        schemars::json_schema!({
+            "type": "string",
+            "description": "`pk`, or `<threshold>/<pk>,<pk>,...` for co-owned images"
+        })
+    }
+}
+
+/// Signatures travel as their text form in JSON, so a plain signature string keeps working
+impl Serialize for MultiSignature {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for MultiSignature {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = <String as Deserialize>::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(feature = "schemars")]
+impl schemars::JsonSchema for MultiSignature {
+    fn schema_name() -> std::borrow::Cow<'static, str> {
+        "MultiSignature".into()
+    }
+
+    fn json_schema(_: &mut schemars::SchemaGenerator) -> schemars::Schema {
+        schemars::json_schema!({
+            "type": "string",
+            "description": "`<pk>:<signature>` pairs separated by commas, or a single bare signature"
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn partial(pk: &str, signature: &str) -> PartialSignature {
+        PartialSignature {
+            pk: pk.to_string(),
+            signature: signature.to_string(),
+        }
+    }
+
+    fn owners() -> OwnerKeys {
+        "2/aa,bb,cc".parse().unwrap()
+    }
+
+    #[test]
+    fn threshold_is_met_by_enough_distinct_signers() {
+        let owners = owners();
+        let signatures = |pks: &[&str]| {
+            MultiSignature(pks.iter().map(|pk| partial(pk, &dummy_sign(pk, "m"))).collect())
+        };
+        assert!(owners.is_authorized("m", &signatures(&["aa", "cc"])));
+        assert!(owners.is_authorized("m", &signatures(&["aa", "bb", "cc"])));
+        assert!(!owners.is_authorized("m", &signatures(&["aa"])));
+        assert!(!owners.is_authorized("m", &signatures(&["aa", "dd"])));
+        assert!(!owners.is_authorized("m", &MultiSignature::default()));
+        // A partial signature made by another key than the one it names counts for nobody
+        let forged = MultiSignature(vec![partial("aa", "aa"), partial("bb", "aa")]);
+        assert_eq!(owners.signers("m", &forged), vec!["aa".to_string()]);
+    }
+
+    #[test]
+    fn duplicate_signers_are_counted_once() {
+        let owners = owners();
+        for signatures in ["aa:aa,aa:aa", "aa:aa,AA:aa", "aa:aa,aa", "aa,aa", "aa,AA"] {
+            let signatures: MultiSignature = signatures.parse().unwrap();
+            assert_eq!(owners.signers("m", &signatures), vec!["aa".to_string()], "{}", signatures);
+            assert!(!owners.is_authorized("m", &signatures));
+        }
+    }
+
+    #[test]
+    fn keys_and_signatures_match_regardless_of_case() {
+        let owners: OwnerKeys = "2/Aa,bB".parse().unwrap();
+        let signatures: MultiSignature = "AA:aA,bb:BB".parse().unwrap();
+        assert_eq!(owners.signers("m", &signatures), vec!["Aa".to_string(), "bB".to_string()]);
+        assert!(owners.contains("aa") && owners.contains("BB") && !owners.contains("cc"));
+        assert!("2/aa,AA".parse::<OwnerKeys>().unwrap_err().contains("distinct"));
+        assert_eq!(normalize_key("Ab01CD"), "ab01cd");
+        assert_eq!(normalize_key(&normalize_key("Ab01CD")), "ab01cd");
+    }
+
+    #[test]
+    fn bare_signature_counts_only_for_the_key_that_made_it() {
+        let owners = owners();
+        let bare: MultiSignature = "bb".parse().unwrap();
+        assert_eq!(bare, MultiSignature(vec![partial("", "bb")]));
+        assert_eq!(owners.signers("m", &bare), vec!["bb".to_string()]);
+        assert!(OwnerKeys::single("bb".to_string()).is_authorized("m", &bare));
+        assert!(!OwnerKeys::single("aa".to_string()).is_authorized("m", &bare));
+        let two: MultiSignature = "aa,bb".parse().unwrap();
+        assert!(owners.is_authorized("m", &two));
+    }
+
+    #[test]
+    fn partial_signatures_combine_and_parse_back() {
+        let parts = ["aa:aa", "bb:bb,AA:other", "cc", "cc", "dd"].map(|p| p.parse::<MultiSignature>().unwrap());
+        let combined = MultiSignature::combine(parts);
+        assert_eq!(
+            combined,
+            MultiSignature(vec![partial("aa", "aa"), partial("bb", "bb"), partial("", "cc"), partial("", "dd")])
+        );
+        assert_eq!(combined.to_string(), "aa:aa,bb:bb,cc,dd");
+        assert_eq!(combined.to_string().parse(), Ok(combined));
+        assert_eq!(" aa:aa , ,bb:bb ".parse(), Ok(MultiSignature(vec![partial("aa", "aa"), partial("bb", "bb")])));
+        assert!("".parse::<MultiSignature>().is_err());
+        assert!(" , ".parse::<MultiSignature>().is_err());
+    }
+
+    #[test]
+    fn owner_keys_parse_and_print() {
+        let owners = owners();
+        assert_eq!(owners.to_string(), "2/aa,bb,cc");
+        assert_eq!(owners.to_string().parse(), Ok(owners));
+        assert_eq!("aa".parse(), Ok(OwnerKeys::single("aa".to_string())));
+        assert_eq!("1/aa".parse::<OwnerKeys>().unwrap().to_string(), "aa");
+        for invalid in ["0/aa,bb", "3/aa,bb", "x/aa", "1/", "2/aa, ,aa"] {
+            assert!(invalid.parse::<OwnerKeys>().is_err(), "{}", invalid);
+        }
+    }
+}
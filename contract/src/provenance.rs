@@ -0,0 +1,61 @@
+use borsh::{BorshDeserialize, BorshSerialize};
+use serde::{Deserialize, Serialize};
+
+use crate::hash::ContentHash;
+
+/// How an image came to be, for synthetic-media labelling
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(rename_all = "kebab-case", tag = "class")]
+pub enum ProvenanceClass {
+    /// Original captured by a trusted device, whose capture attestation it carries
+    Captured,
+    /// Edit signed by a publisher or proven from the pixels
+    Edited,
+    /// Output of a generator, signed by its registered model key
+    AiGenerated { model_pk: String },
+    /// Edit declared as made with a generative tool
+    AiAssistedEdit,
+    /// Original registered on its owner's word alone, with nothing proving how it was made
+    Declared,
+}
+
+/// Generator model whose key may register AI-generated images
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct GeneratorModel {
+    pub vendor: String,
+    pub model: String,
+}
+
+/// Generative content found in the lineage of a queried image
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct AiDisclosure {
+    /// Image of the lineage that is AI-generated or an AI-assisted edit
+    pub image_hash: ContentHash,
+    pub provenance: ProvenanceClass,
+    /// Whether the flag comes from an image the queried one was edited from
+    pub inherited: bool,
+}
+
+impl ProvenanceClass {
+    /// Whether generative tools produced or altered the image
+    pub fn is_generative(&self) -> bool {
+        matches!(self, ProvenanceClass::AiGenerated { .. } | ProvenanceClass::AiAssistedEdit)
+    }
+}
+
+/// Message the model key signs for each output it registers
+pub fn generation_message(image_hash: &ContentHash) -> String {
+    format!("generated:{}", image_hash)
+}
+
+/// Message the admin keys sign to register a model key as `model`, or to remove it with `None`. It starts with
+/// the admin log length as nonce, like the governance messages.
+pub fn generator_registry_message(nonce: usize, model_pk: &str, model: Option<&GeneratorModel>) -> String {
+    match model {
+        Some(model) => format!("{}:register-generator:{}:{}:{}", nonce, model_pk, model.vendor, model.model),
+        None => format!("{}:remove-generator:{}", nonce, model_pk),
+    }
+}
@@ -0,0 +1,249 @@
+use std::collections::{HashMap, HashSet};
+use borsh::io::{Read, Write};
+use borsh::{BorshDeserialize, BorshSerialize};
+
+use sdk::{Digestable, HyleContract, RunResult};
+
+use crate::hash::legacy_hash;
+use crate::ownership::{normalize_key, OwnerKeys, PublisherGrant};
+use crate::provenance::ProvenanceClass;
+use crate::{ImageMetadata, ImageState};
+
+/// Layout version of `ImageState` written by this contract. Bump it whenever the deployed layout of the state, or
+/// of a type stored in it, changes: the deployed layout becomes a module below, migrated to the new one.
+pub const STATE_VERSION: u16 = 2;
+/// Prefix of versioned states, followed by the version (u16 LE) and the borsh encoded state
+pub const STATE_MAGIC: &[u8; 4] = b"IMGS";
+
+/// Layout of the first deployments, stored as plain borsh before the envelope was introduced
+mod v1 {
+    use super::*;
+
+    #[derive(BorshDeserialize)]
+    pub struct ImageMetadata {
+        pub previous_image_hash: Option<String>,
+        pub owner_pk: String,
+        pub publishers: HashSet<String>,
+        pub is_root: bool,
+    }
+
+    #[derive(BorshDeserialize)]
+    pub struct ImageState {
+        pub hash_map: HashMap<String, ImageMetadata>,
+    }
+}
+
+/// A key that isn't a valid hash is kept under its `legacy_hash`, and so are the links to it. Nothing recorded
+/// heights, signers or how an image was made: images and grants date from height 0, grants were given by the
+/// owner, originals are declared and edits plain edits.
+impl From<v1::ImageState> for ImageState {
+    fn from(state: v1::ImageState) -> Self {
+        let hash_map = state.hash_map.into_iter().map(|(hash, m)| {
+            let metadata = ImageMetadata {
+                previous_image_hash: m.previous_image_hash.as_deref().map(legacy_hash),
+                owner_pk: OwnerKeys::single(m.owner_pk.clone()),
+                publishers: m
+                    .publishers
+                    .iter()
+                    .map(|pk| {
+                        let grant = PublisherGrant {
+                            granted_at: 0,
+                            granted_by: vec![m.owner_pk.clone()],
+                        };
+                        (normalize_key(pk), grant)
+                    })
+                    .collect(),
+                is_root: m.is_root,
+                transformation: None,
+                perceptual_hash: None,
+                registered_at: 0,
+                signed_by: vec![],
+                capture: None,
+                provenance: match m.is_root {
+                    true => ProvenanceClass::Declared,
+                    false => ProvenanceClass::Edited,
+                },
+            };
+            (legacy_hash(&hash), metadata)
+        });
+        ImageState {
+            hash_map: hash_map.collect(),
+            ..ImageState::default()
+        }
+    }
+}
+
+impl ImageState {
+    /// Envelope of the current version, as stored on-chain and passed to the guest
+    pub fn to_versioned_bytes(&self) -> Vec<u8> {
+        let mut bytes = STATE_MAGIC.to_vec();
+        bytes.extend_from_slice(&STATE_VERSION.to_le_bytes());
+        bytes.extend(borsh::to_vec(self).expect("Failed to encode ImageState"));
+        bytes
+    }
+
+    /// Decodes the current version of the state, or the first one migrated to it. States stored before the
+    /// envelope carry no version: they are all of the first layout.
+    pub fn from_versioned_bytes(bytes: &[u8]) -> Result<Self, String> {
+        let Some(rest) = bytes.strip_prefix(STATE_MAGIC) else {
+            return decode(1, bytes);
+        };
+        if rest.len() < 2 {
+            return Err("Truncated state version".to_string());
+        }
+        let (version, body) = rest.split_at(2);
+        decode(u16::from_le_bytes([version[0], version[1]]), body)
+    }
+}
+
+/// Decodes the layout of `version` and migrates it to the current one
+fn decode(version: u16, body: &[u8]) -> Result<ImageState, String> {
+    let state = match version {
+        1 => borsh::from_slice::<v1::ImageState>(body).map(ImageState::from),
+        STATE_VERSION => borsh::from_slice(body),
+        version if version > STATE_VERSION => {
+            return Err(format!(
+                "State version {} is newer than this contract (version {})",
+                version, STATE_VERSION
+            ))
+        }
+        version => return Err(format!("Unknown state version {}", version)),
+    };
+    state.map_err(|e| format!("Could not decode state v{}: {}", version, e))
+}
+
+/// State decoded by the guest. A state that can't be decoded doesn't panic the guest: it is kept as is,
+/// and every action fails with the decoding error.
+pub enum ContractState {
+    Ready(Box<ImageState>),
+    Undecodable { bytes: Vec<u8>, error: String },
+}
+
+impl HyleContract for ContractState {
+    fn execute(&mut self, contract_input: &sdk::ContractInput) -> RunResult {
+        match self {
+            ContractState::Ready(state) => state.execute(contract_input),
+            ContractState::Undecodable { error, .. } => Err(format!("Could not decode contract state: {}", error)),
+        }
+    }
+}
+
+impl Digestable for ContractState {
+    fn as_digest(&self) -> sdk::StateDigest {
+        match self {
+            ContractState::Ready(state) => state.as_digest(),
+            ContractState::Undecodable { bytes, .. } => sdk::StateDigest(bytes.clone()),
+        }
+    }
+}
+
+impl From<sdk::StateDigest> for ContractState {
+    fn from(state: sdk::StateDigest) -> Self {
+        match ImageState::from_versioned_bytes(&state.0) {
+            Ok(state) => ContractState::Ready(Box::new(state)),
+            Err(error) => ContractState::Undecodable { bytes: state.0, error },
+        }
+    }
+}
+
+/// Reads the whole remaining input as a versioned state
+impl BorshDeserialize for ContractState {
+    fn deserialize_reader<R: Read>(reader: &mut R) -> borsh::io::Result<Self> {
+        let mut bytes = vec![];
+        reader.read_to_end(&mut bytes)?;
+        Ok(sdk::StateDigest(bytes).into())
+    }
+}
+
+impl BorshSerialize for ContractState {
+    fn serialize<W: Write>(&self, writer: &mut W) -> borsh::io::Result<()> {
+        writer.write_all(&self.as_digest().0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash::{ContentHash, HashAlgorithm};
+
+    /// State of a first deployment: `photo-1`, owned by `Owner` with the publisher `PUB`, edited into `EDIT`
+    const V1_STATE: &str = concat!(
+        "020000000700000070686f746f2d3100050000004f776e657201000000030000005055420147000000",
+        "7368613235363a30303131323233333434353536363737383839396161626263636464656566663030",
+        "313132323333343435353636373738383939616162626363646465656666010700000070686f746f2d",
+        "31050000004f776e65720000000000",
+    );
+    const EDIT: &str = "sha256:00112233445566778899aabbccddeeff00112233445566778899aabbccddeeff";
+
+    fn v1_state() -> Vec<u8> {
+        (0..V1_STATE.len()).step_by(2).map(|i| u8::from_str_radix(&V1_STATE[i..i + 2], 16).unwrap()).collect()
+    }
+
+    #[test]
+    fn v1_state_is_migrated_keeping_unparseable_keys() {
+        let state = ImageState::from_versioned_bytes(&v1_state()).unwrap();
+
+        let original = ContentHash::compute(HashAlgorithm::LegacySha256, b"photo-1");
+        let edit: ContentHash = EDIT.parse().unwrap();
+        assert_eq!(state.hash_map.len(), 2);
+        assert_eq!(state.hash_map[&edit].previous_image_hash, Some(original));
+        assert_eq!(state.hash_map[&edit].provenance, ProvenanceClass::Edited);
+        assert_eq!(state.hash_map[&original].provenance, ProvenanceClass::Declared);
+        assert_eq!(state.hash_map[&original].owner_pk, OwnerKeys::single("Owner".to_string()));
+        let grant = PublisherGrant {
+            granted_at: 0,
+            granted_by: vec!["Owner".to_string()],
+        };
+        assert_eq!(state.hash_map[&original].publishers, HashMap::from([("pub".to_string(), grant)]));
+        assert_eq!(state.find_original_image(edit).unwrap(), original);
+
+        // Once migrated, the state is stored in the current envelope and reads back the same
+        let bytes = state.to_versioned_bytes();
+        assert_eq!(bytes[..4], *STATE_MAGIC);
+        assert_eq!(ImageState::from_versioned_bytes(&bytes).unwrap().to_versioned_bytes(), bytes);
+        let mut enveloped = STATE_MAGIC.to_vec();
+        enveloped.extend_from_slice(&1u16.to_le_bytes());
+        enveloped.extend(v1_state());
+        assert_eq!(ImageState::from_versioned_bytes(&enveloped).unwrap().to_versioned_bytes(), bytes);
+    }
+
+    #[test]
+    fn current_state_round_trips() {
+        let mut state = ImageState {
+            height: 7,
+            ..ImageState::default()
+        };
+        state.compromised_keys.insert("ab".to_string(), 3);
+        let bytes = state.to_versioned_bytes();
+        assert_eq!(ImageState::from_versioned_bytes(&bytes).unwrap().to_versioned_bytes(), bytes);
+    }
+
+    #[test]
+    fn truncated_or_garbage_state_is_undecodable() {
+        let v1 = v1_state();
+        let current = ImageState::default().to_versioned_bytes();
+        let cases = [
+            vec![],
+            b"not a state".to_vec(),
+            v1[..v1.len() - 1].to_vec(),
+            [v1.as_slice(), &[0]].concat(),
+            current[..5].to_vec(),
+            current[..current.len() - 1].to_vec(),
+        ];
+        for bytes in cases {
+            match ContractState::from(sdk::StateDigest(bytes.clone())) {
+                ContractState::Undecodable { bytes: kept, .. } => assert_eq!(kept, bytes),
+                ContractState::Ready(_) => panic!("decoded {:?}", bytes),
+            }
+        }
+    }
+
+    #[test]
+    fn unknown_versions_are_refused() {
+        for (version, error) in [(0, "Unknown"), (STATE_VERSION + 1, "newer")] {
+            let mut bytes = STATE_MAGIC.to_vec();
+            bytes.extend_from_slice(&version.to_le_bytes());
+            assert!(ImageState::from_versioned_bytes(&bytes).unwrap_err().contains(error));
+        }
+    }
+}
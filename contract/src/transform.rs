@@ -0,0 +1,797 @@
+use std::str::FromStr;
+use borsh::{BorshDeserialize, BorshSerialize};
+use serde::{Deserialize, Serialize};
+use zune_jpeg::zune_core::colorspace::ColorSpace;
+use zune_jpeg::zune_core::options::DecoderOptions;
+use zune_jpeg::JpegDecoder;
+
+/// Largest brightness shift (in 8-bit levels) accepted for a `BrightnessContrast` edit
+pub const MAX_BRIGHTNESS_SHIFT: i16 = 64;
+/// Accepted contrast range, in percent of the original contrast
+pub const MIN_CONTRAST_PERCENT: u16 = 50;
+pub const MAX_CONTRAST_PERCENT: u16 = 150;
+/// Largest per-channel error a JPEG re-encode may introduce
+pub const MAX_JPEG_ERROR: u8 = 32;
+/// Largest mean squared error of a JPEG re-encode over every colour channel, i.e. a PSNR of at least 30 dB.
+/// Keeps edits that stay under `max_error` on every pixel, such as low-contrast overlays, from passing as re-encodes.
+pub const MAX_JPEG_MEAN_SQUARED_ERROR: u64 = 65;
+/// Smallest blur radius accepted for a redaction, smaller ones leave faces readable
+pub const MIN_BLUR_RADIUS: u32 = 8;
+/// Upper bound on the number of rectangles of a single redaction edit
+pub const MAX_REDACTIONS: usize = 64;
+
+/// Per-channel slack allowed for edits that involve integer rounding
+const ROUNDING_TOLERANCE: i32 = 1;
+
+/// Magic prefix of the raw RGBA8 format: `RGBA` + width (u32 LE) + height (u32 LE) + pixels
+const RAW_MAGIC: &[u8; 4] = b"RGBA";
+const PNG_MAGIC: &[u8; 8] = b"\x89PNG\r\n\x1a\n";
+const JPEG_MAGIC: &[u8; 3] = b"\xff\xd8\xff";
+
+/// The edits that can be proven from pixels, without any publisher signature
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub enum Transformation {
+    Crop { x: u32, y: u32, width: u32, height: u32 },
+    Downscale { width: u32, height: u32 },
+    Grayscale,
+    BrightnessContrast { brightness: i16, contrast_percent: u16 },
+    JpegReencode { max_error: u8 },
+    /// Hides rectangular regions, every other pixel is kept as is
+    Redact { redactions: Vec<Redaction> },
+}
+
+/// Rectangle in pixel coordinates
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct Region {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// How the pixels of a redacted region were replaced
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub enum RedactionStyle {
+    /// Every pixel set to the same RGBA colour
+    Fill { rgba: [u8; 4] },
+    /// Box blur of the original pixels, the window being clipped to the region
+    Blur { radius: u32 },
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct Redaction {
+    pub region: Region,
+    pub style: RedactionStyle,
+}
+
+/// Private input of a `RegisterProvenEdit` action: both files, never revealed on-chain
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone)]
+pub struct TransformWitness {
+    pub original_file: Vec<u8>,
+    pub edited_file: Vec<u8>,
+}
+
+/// Decoded image, normalised to 8-bit RGBA
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RawImage {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<u8>,
+}
+
+impl RawImage {
+    /// Decodes a raw RGBA8, binary PPM (P6), PNG or JPEG file
+    pub fn decode(bytes: &[u8]) -> Result<Self, String> {
+        if bytes.starts_with(RAW_MAGIC) {
+            Self::decode_raw(bytes)
+        } else if bytes.starts_with(b"P6") {
+            Self::decode_ppm(bytes)
+        } else if bytes.starts_with(PNG_MAGIC) {
+            Self::decode_png(bytes)
+        } else if bytes.starts_with(JPEG_MAGIC) {
+            Self::decode_jpeg(bytes)
+        } else {
+            Err("Unsupported image format".to_string())
+        }
+    }
+
+    /// Encodes the image in the raw RGBA8 format understood by `decode`
+    pub fn encode_raw(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(12 + self.pixels.len());
+        bytes.extend_from_slice(RAW_MAGIC);
+        bytes.extend_from_slice(&self.width.to_le_bytes());
+        bytes.extend_from_slice(&self.height.to_le_bytes());
+        bytes.extend_from_slice(&self.pixels);
+        bytes
+    }
+
+    pub fn new(width: u32, height: u32, pixels: Vec<u8>) -> Result<Self, String> {
+        if pixels.len() as u64 != width as u64 * height as u64 * 4 {
+            return Err("Pixel buffer does not match image dimensions".to_string());
+        }
+        Ok(RawImage { width, height, pixels })
+    }
+
+    fn decode_raw(bytes: &[u8]) -> Result<Self, String> {
+        if bytes.len() < 12 {
+            return Err("Truncated raw image header".to_string());
+        }
+        let width = u32::from_le_bytes(bytes[4..8].try_into().unwrap());
+        let height = u32::from_le_bytes(bytes[8..12].try_into().unwrap());
+        Self::new(width, height, bytes[12..].to_vec())
+    }
+
+    fn decode_ppm(bytes: &[u8]) -> Result<Self, String> {
+        // Header is "P6 <width> <height> <maxval>" followed by a single whitespace byte
+        let mut fields = Vec::with_capacity(3);
+        let mut pos = 2;
+        while fields.len() < 3 {
+            while pos < bytes.len() && (bytes[pos].is_ascii_whitespace() || bytes[pos] == b'#') {
+                if bytes[pos] == b'#' {
+                    while pos < bytes.len() && bytes[pos] != b'\n' {
+                        pos += 1;
+                    }
+                } else {
+                    pos += 1;
+                }
+            }
+            let start = pos;
+            while pos < bytes.len() && bytes[pos].is_ascii_digit() {
+                pos += 1;
+            }
+            let field = std::str::from_utf8(&bytes[start..pos])
+                .ok()
+                .and_then(|s| s.parse::<u32>().ok())
+                .ok_or("Invalid PPM header")?;
+            fields.push(field);
+        }
+        if fields[2] != 255 {
+            return Err("Only 8-bit PPM images are supported".to_string());
+        }
+        if !bytes.get(pos).ok_or("Truncated PPM image")?.is_ascii_whitespace() {
+            return Err("Invalid PPM header".to_string());
+        }
+        let data = &bytes[pos + 1..];
+        let (width, height) = (fields[0], fields[1]);
+        if data.len() as u64 != width as u64 * height as u64 * 3 {
+            return Err("PPM pixel data does not match image dimensions".to_string());
+        }
+        let pixels = data
+            .chunks_exact(3)
+            .flat_map(|rgb| [rgb[0], rgb[1], rgb[2], 255])
+            .collect();
+        Self::new(width, height, pixels)
+    }
+
+    fn decode_png(bytes: &[u8]) -> Result<Self, String> {
+        let mut decoder = png::Decoder::new(bytes);
+        decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+        let mut reader = decoder.read_info().map_err(|e| e.to_string())?;
+        let mut buf = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut buf).map_err(|e| e.to_string())?;
+        let data = &buf[..info.buffer_size()];
+        let pixels = match info.color_type {
+            png::ColorType::Rgba => data.to_vec(),
+            png::ColorType::Rgb => data.chunks_exact(3).flat_map(|p| [p[0], p[1], p[2], 255]).collect(),
+            png::ColorType::GrayscaleAlpha => data.chunks_exact(2).flat_map(|p| [p[0], p[0], p[0], p[1]]).collect(),
+            png::ColorType::Grayscale => data.iter().flat_map(|&g| [g, g, g, 255]).collect(),
+            png::ColorType::Indexed => return Err("Indexed PNG was not expanded".to_string()),
+        };
+        Self::new(info.width, info.height, pixels)
+    }
+
+    fn decode_jpeg(bytes: &[u8]) -> Result<Self, String> {
+        let options = DecoderOptions::default().jpeg_set_out_colorspace(ColorSpace::RGBA);
+        let mut decoder = JpegDecoder::new_with_options(bytes, options);
+        let data = decoder.decode().map_err(|e| format!("{:?}", e))?;
+        let (width, height) = decoder.dimensions().ok_or("JPEG dimensions are unknown")?;
+        // Grayscale JPEGs are always decoded to luma
+        let pixels = match decoder.get_output_colorspace() {
+            Some(ColorSpace::RGBA) => data,
+            Some(ColorSpace::Luma) => data.iter().flat_map(|&g| [g, g, g, 255]).collect(),
+            colorspace => return Err(format!("Unsupported JPEG colour space {:?}", colorspace)),
+        };
+        Self::new(width as u32, height as u32, pixels)
+    }
+
+    fn pixel(&self, x: u32, y: u32) -> &[u8] {
+        let offset = ((y as usize * self.width as usize) + x as usize) * 4;
+        &self.pixels[offset..offset + 4]
+    }
+
+    fn same_dimensions(&self, other: &RawImage) -> Result<(), String> {
+        if self.width != other.width || self.height != other.height {
+            return Err("Edited image dimensions differ from the original".to_string());
+        }
+        Ok(())
+    }
+}
+
+impl Region {
+    fn contains(&self, x: u32, y: u32) -> bool {
+        x >= self.x && y >= self.y && x - self.x < self.width && y - self.y < self.height
+    }
+
+    fn fits_in(&self, image: &RawImage) -> bool {
+        self.width > 0
+            && self.height > 0
+            && self.x.checked_add(self.width).is_some_and(|r| r <= image.width)
+            && self.y.checked_add(self.height).is_some_and(|b| b <= image.height)
+    }
+
+    fn overlaps(&self, other: &Region) -> bool {
+        self.x < other.x + other.width
+            && other.x < self.x + self.width
+            && self.y < other.y + other.height
+            && other.y < self.y + self.height
+    }
+}
+
+impl Redaction {
+    /// Checks the pixels of the region only, the caller checks everything outside of it
+    fn verify(&self, original: &RawImage, edited: &RawImage) -> Result<(), String> {
+        let Region { x, y, width, height } = self.region;
+        match self.style {
+            RedactionStyle::Fill { rgba } => {
+                for row in y..y + height {
+                    for col in x..x + width {
+                        if edited.pixel(col, row) != rgba {
+                            return Err(format!("Pixel ({}, {}) is not filled with the redaction colour", col, row));
+                        }
+                    }
+                }
+                Ok(())
+            }
+            RedactionStyle::Blur { radius } => {
+                if radius < MIN_BLUR_RADIUS {
+                    return Err(format!("Blur radius below {} is not allowed", MIN_BLUR_RADIUS));
+                }
+                // Summed-area table of the region, so every window mean is O(1) in the guest
+                let stride = width as usize + 1;
+                let mut sums = vec![[0u64; 4]; stride * (height as usize + 1)];
+                for row in 0..height as usize {
+                    for col in 0..width as usize {
+                        let pixel = original.pixel(x + col as u32, y + row as u32);
+                        for channel in 0..4 {
+                            sums[(row + 1) * stride + col + 1][channel] = pixel[channel] as u64
+                                + sums[row * stride + col + 1][channel]
+                                + sums[(row + 1) * stride + col][channel]
+                                - sums[row * stride + col][channel];
+                        }
+                    }
+                }
+                for row in 0..height {
+                    let (y0, y1) = (row.saturating_sub(radius) as usize, (row + radius + 1).min(height) as usize);
+                    for col in 0..width {
+                        let (x0, x1) = (col.saturating_sub(radius) as usize, (col + radius + 1).min(width) as usize);
+                        let count = ((x1 - x0) * (y1 - y0)) as u64;
+                        let pixel = edited.pixel(x + col, y + row);
+                        for channel in 0..4 {
+                            let sum = sums[y1 * stride + x1][channel] + sums[y0 * stride + x0][channel]
+                                - sums[y0 * stride + x1][channel]
+                                - sums[y1 * stride + x0][channel];
+                            let expected = (sum / count) as i32;
+                            if (pixel[channel] as i32 - expected).abs() > ROUNDING_TOLERANCE {
+                                return Err(format!("Pixel ({}, {}) is not blurred", x + col, y + row));
+                            }
+                        }
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Transformation {
+    /// Checks that the edited file is in the format the transformation produces: a re-encode must be a JPEG
+    pub fn verify_format(&self, edited_file: &[u8]) -> Result<(), String> {
+        match self {
+            Transformation::JpegReencode { .. } if !edited_file.starts_with(JPEG_MAGIC) => {
+                Err("A JPEG re-encode must be a JPEG file".to_string())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks that `edited` is exactly `original` with this transformation applied
+    pub fn verify(&self, original: &RawImage, edited: &RawImage) -> Result<(), String> {
+        match *self {
+            Transformation::Crop { x, y, width, height } => {
+                let fits = x.checked_add(width).is_some_and(|r| r <= original.width)
+                    && y.checked_add(height).is_some_and(|b| b <= original.height);
+                if !fits || width == 0 || height == 0 {
+                    return Err("Crop box is outside the original image".to_string());
+                }
+                if edited.width != width || edited.height != height {
+                    return Err("Edited image does not match the crop box".to_string());
+                }
+                for row in 0..height {
+                    for col in 0..width {
+                        if original.pixel(x + col, y + row) != edited.pixel(col, row) {
+                            return Err(format!("Pixel ({}, {}) differs from the cropped original", col, row));
+                        }
+                    }
+                }
+                Ok(())
+            }
+            Transformation::Downscale { width, height } => {
+                let shrinks = (width, height) != (original.width, original.height);
+                if width == 0 || height == 0 || width > original.width || height > original.height || !shrinks {
+                    return Err("Downscale must shrink the original image on at least one side, and enlarge none".to_string());
+                }
+                if edited.width != width || edited.height != height {
+                    return Err("Edited image does not match the downscale size".to_string());
+                }
+                // Box filter: every output pixel is the mean of the source pixels it covers
+                for row in 0..height {
+                    let (y0, y1) = box_range(row, height, original.height);
+                    for col in 0..width {
+                        let (x0, x1) = box_range(col, width, original.width);
+                        let count = ((x1 - x0) * (y1 - y0)) as i32;
+                        for channel in 0..4 {
+                            let mut sum = 0i32;
+                            for sy in y0..y1 {
+                                for sx in x0..x1 {
+                                    sum += original.pixel(sx, sy)[channel] as i32;
+                                }
+                            }
+                            let expected = sum / count;
+                            if (edited.pixel(col, row)[channel] as i32 - expected).abs() > ROUNDING_TOLERANCE {
+                                return Err(format!("Pixel ({}, {}) is not a box-filtered average", col, row));
+                            }
+                        }
+                    }
+                }
+                Ok(())
+            }
+            Transformation::Grayscale => {
+                original.same_dimensions(edited)?;
+                for (src, dst) in original.pixels.chunks_exact(4).zip(edited.pixels.chunks_exact(4)) {
+                    let luma = (299 * src[0] as i32 + 587 * src[1] as i32 + 114 * src[2] as i32) / 1000;
+                    let gray = dst[..3].iter().all(|&c| (c as i32 - luma).abs() <= ROUNDING_TOLERANCE);
+                    if !gray || src[3] != dst[3] {
+                        return Err("Edited image is not the grayscale of the original".to_string());
+                    }
+                }
+                Ok(())
+            }
+            Transformation::BrightnessContrast { brightness, contrast_percent } => {
+                if brightness.abs() > MAX_BRIGHTNESS_SHIFT {
+                    return Err(format!("Brightness shift above {} is not allowed", MAX_BRIGHTNESS_SHIFT));
+                }
+                if !(MIN_CONTRAST_PERCENT..=MAX_CONTRAST_PERCENT).contains(&contrast_percent) {
+                    return Err(format!(
+                        "Contrast must be between {}% and {}%",
+                        MIN_CONTRAST_PERCENT, MAX_CONTRAST_PERCENT
+                    ));
+                }
+                original.same_dimensions(edited)?;
+                for (src, dst) in original.pixels.chunks_exact(4).zip(edited.pixels.chunks_exact(4)) {
+                    for channel in 0..3 {
+                        let value = (src[channel] as i32 - 128) * contrast_percent as i32 / 100 + 128 + brightness as i32;
+                        let expected = value.clamp(0, 255);
+                        if (dst[channel] as i32 - expected).abs() > ROUNDING_TOLERANCE {
+                            return Err("Edited image is not a brightness/contrast change of the original".to_string());
+                        }
+                    }
+                    if src[3] != dst[3] {
+                        return Err("Brightness/contrast edits must not change transparency".to_string());
+                    }
+                }
+                Ok(())
+            }
+            Transformation::JpegReencode { max_error } => {
+                if max_error > MAX_JPEG_ERROR {
+                    return Err(format!("JPEG re-encode error above {} is not allowed", MAX_JPEG_ERROR));
+                }
+                original.same_dimensions(edited)?;
+                let mut squared_error = 0u64;
+                for (src, dst) in original.pixels.chunks_exact(4).zip(edited.pixels.chunks_exact(4)) {
+                    for channel in 0..4 {
+                        let error = src[channel].abs_diff(dst[channel]);
+                        if error > max_error {
+                            return Err("Edited image drifts too far from the original for a re-encode".to_string());
+                        }
+                        if channel < 3 {
+                            squared_error += error as u64 * error as u64;
+                        }
+                    }
+                }
+                let samples = original.width as u64 * original.height as u64 * 3;
+                if squared_error > MAX_JPEG_MEAN_SQUARED_ERROR * samples {
+                    return Err(format!(
+                        "Edited image differs from the original by a mean squared error above {}, more than a re-encode",
+                        MAX_JPEG_MEAN_SQUARED_ERROR
+                    ));
+                }
+                Ok(())
+            }
+            Transformation::Redact { ref redactions } => {
+                if redactions.is_empty() || redactions.len() > MAX_REDACTIONS {
+                    return Err(format!("A redaction needs between 1 and {} regions", MAX_REDACTIONS));
+                }
+                original.same_dimensions(edited)?;
+                for (i, redaction) in redactions.iter().enumerate() {
+                    if !redaction.region.fits_in(original) {
+                        return Err("Redaction region is outside the original image".to_string());
+                    }
+                    if redactions[..i].iter().any(|other| other.region.overlaps(&redaction.region)) {
+                        return Err("Redaction regions must not overlap".to_string());
+                    }
+                    redaction.verify(original, edited)?;
+                }
+                for row in 0..original.height {
+                    for col in 0..original.width {
+                        let redacted = redactions.iter().any(|r| r.region.contains(col, row));
+                        if !redacted && original.pixel(col, row) != edited.pixel(col, row) {
+                            return Err(format!("Pixel ({}, {}) outside the redactions was modified", col, row));
+                        }
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Source range covered by output index `i` when resizing `src` samples down to `dst`
+fn box_range(i: u32, dst: u32, src: u32) -> (u32, u32) {
+    let start = (i as u64 * src as u64 / dst as u64) as u32;
+    let end = ((i as u64 + 1) * src as u64 / dst as u64) as u32;
+    (start, end.max(start + 1))
+}
+
+/// Parses the CLI form of a transformation, e.g. `crop=10,10,200,100`, `downscale=640,480`,
+/// `grayscale`, `brightness-contrast=20,110`, `jpeg=8` or
+/// `redact=fill:000000ff@10,10,50,50;blur:12@100,20,40,40`
+impl FromStr for Transformation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, args) = s.split_once('=').unwrap_or((s, ""));
+        let numbers = |count: usize| -> Result<Vec<i64>, String> {
+            let values = args
+                .split(',')
+                .filter(|a| !a.is_empty())
+                .map(|a| a.trim().parse::<i64>().map_err(|e| format!("Invalid argument '{}': {}", a, e)))
+                .collect::<Result<Vec<_>, _>>()?;
+            if values.len() != count {
+                return Err(format!("'{}' expects {} arguments", name, count));
+            }
+            Ok(values)
+        };
+        let to = |v: i64| -> Result<u32, String> { u32::try_from(v).map_err(|e| e.to_string()) };
+
+        match name {
+            "crop" => {
+                let v = numbers(4)?;
+                Ok(Transformation::Crop { x: to(v[0])?, y: to(v[1])?, width: to(v[2])?, height: to(v[3])? })
+            }
+            "downscale" => {
+                let v = numbers(2)?;
+                Ok(Transformation::Downscale { width: to(v[0])?, height: to(v[1])? })
+            }
+            "grayscale" => {
+                numbers(0)?;
+                Ok(Transformation::Grayscale)
+            }
+            "brightness-contrast" => {
+                let v = numbers(2)?;
+                Ok(Transformation::BrightnessContrast {
+                    brightness: i16::try_from(v[0]).map_err(|e| e.to_string())?,
+                    contrast_percent: u16::try_from(v[1]).map_err(|e| e.to_string())?,
+                })
+            }
+            "jpeg" => {
+                let v = numbers(1)?;
+                Ok(Transformation::JpegReencode { max_error: u8::try_from(v[0]).map_err(|e| e.to_string())? })
+            }
+            "redact" => {
+                let redactions = args.split(';').map(parse_redaction).collect::<Result<Vec<_>, _>>()?;
+                Ok(Transformation::Redact { redactions })
+            }
+            _ => Err(format!("Unknown transformation '{}'", name)),
+        }
+    }
+}
+
+/// Parses a single `<style>@x,y,w,h` redaction, the style being `fill:RRGGBBAA` or `blur:<radius>`
+fn parse_redaction(s: &str) -> Result<Redaction, String> {
+    let (style, rect) = s.split_once('@').ok_or(format!("Redaction '{}' is missing its region", s))?;
+    let style = match style.split_once(':') {
+        Some(("fill", color)) => {
+            let bytes = hex::decode(color).map_err(|e| format!("Invalid fill colour '{}': {}", color, e))?;
+            let rgba: [u8; 4] = bytes.try_into().map_err(|_| format!("Fill colour '{}' must be RRGGBBAA", color))?;
+            RedactionStyle::Fill { rgba }
+        }
+        Some(("blur", radius)) => RedactionStyle::Blur {
+            radius: radius.parse().map_err(|e| format!("Invalid blur radius '{}': {}", radius, e))?,
+        },
+        _ => return Err(format!("Unknown redaction style '{}'", style)),
+    };
+    let values = rect
+        .split(',')
+        .map(|v| v.trim().parse::<u32>().map_err(|e| format!("Invalid region '{}': {}", rect, e)))
+        .collect::<Result<Vec<_>, _>>()?;
+    let [x, y, width, height] = values[..] else {
+        return Err(format!("Region '{}' must be x,y,w,h", rect));
+    };
+    Ok(Redaction {
+        region: Region { x, y, width, height },
+        style,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `width` x `height` image whose pixels are given by `rgba(x, y)`
+    fn image(width: u32, height: u32, rgba: impl Fn(u32, u32) -> [u8; 4]) -> RawImage {
+        let pixels = (0..height).flat_map(|y| (0..width).flat_map(|x| rgba(x, y)).collect::<Vec<_>>()).collect();
+        RawImage::new(width, height, pixels).unwrap()
+    }
+
+    /// Distinct opaque colour for every pixel
+    fn gradient(width: u32, height: u32) -> RawImage {
+        image(width, height, |x, y| [(x * 40) as u8, (y * 60) as u8, (x * 7 + y * 13) as u8, 255])
+    }
+
+    /// `image` with one channel of pixel (x, y) moved by `delta`
+    fn nudged(image: &RawImage, x: u32, y: u32, delta: i16) -> RawImage {
+        let mut nudged = image.clone();
+        let offset = ((y * image.width + x) * 4) as usize;
+        nudged.pixels[offset] = (nudged.pixels[offset] as i16 + delta).clamp(0, 255) as u8;
+        nudged
+    }
+
+    fn crop(original: &RawImage, x: u32, y: u32, width: u32, height: u32) -> RawImage {
+        image(width, height, |col, row| original.pixel(x + col, y + row).try_into().unwrap())
+    }
+
+    #[test]
+    fn crop_up_to_the_edges_is_accepted() {
+        let original = gradient(4, 3);
+        let transformation = Transformation::Crop { x: 1, y: 1, width: 3, height: 2 };
+        transformation.verify(&original, &crop(&original, 1, 1, 3, 2)).unwrap();
+        Transformation::Crop { x: 0, y: 0, width: 4, height: 3 }.verify(&original, &original).unwrap();
+    }
+
+    #[test]
+    fn crop_past_the_edges_or_of_other_pixels_is_rejected() {
+        let original = gradient(4, 3);
+        let edited = crop(&original, 1, 1, 3, 2);
+        for (x, y, width, height) in [(1, 1, 4, 2), (1, 2, 3, 2), (2, 1, 3, 2), (1, 1, 0, 2), (u32::MAX, 1, 3, 2)] {
+            let transformation = Transformation::Crop { x, y, width, height };
+            assert!(transformation.verify(&original, &edited).is_err(), "{:?}", transformation);
+        }
+        let transformation = Transformation::Crop { x: 1, y: 1, width: 3, height: 2 };
+        assert!(transformation.verify(&original, &crop(&original, 0, 1, 3, 2)).is_err());
+        assert!(transformation.verify(&original, &crop(&original, 1, 1, 2, 2)).is_err());
+        assert!(transformation.verify(&original, &nudged(&edited, 2, 1, 1)).is_err());
+    }
+
+    /// Mean of the original pixels in the columns `xs` and rows `ys`
+    fn mean(original: &RawImage, xs: std::ops::Range<u32>, ys: std::ops::Range<u32>) -> [u8; 4] {
+        let count = xs.len() * ys.len();
+        let mut sums = [0usize; 4];
+        for y in ys {
+            for x in xs.clone() {
+                for (sum, value) in sums.iter_mut().zip(original.pixel(x, y)) {
+                    *sum += *value as usize;
+                }
+            }
+        }
+        sums.map(|sum| (sum / count) as u8)
+    }
+
+    #[test]
+    fn downscale_to_a_non_divisible_size_is_box_filtered() {
+        // 5 columns shrink to 2 boxes of 2 and 3 columns, 3 rows to boxes of 1 and 2 rows
+        let original = gradient(5, 3);
+        let (columns, rows) = ([0..2, 2..5], [0..1, 1..3]);
+        let edited = image(2, 2, |x, y| mean(&original, columns[x as usize].clone(), rows[y as usize].clone()));
+        let transformation = Transformation::Downscale { width: 2, height: 2 };
+        transformation.verify(&original, &edited).unwrap();
+        transformation.verify(&original, &nudged(&edited, 1, 1, 1)).unwrap();
+        assert!(transformation.verify(&original, &nudged(&edited, 1, 1, 2)).is_err());
+
+        // Boxes of 3 and 2 columns average other pixels
+        let (columns, rows) = ([0..3, 3..5], [0..1, 1..3]);
+        let shifted = image(2, 2, |x, y| mean(&original, columns[x as usize].clone(), rows[y as usize].clone()));
+        assert!(transformation.verify(&original, &shifted).is_err());
+    }
+
+    #[test]
+    fn downscale_must_shrink() {
+        let original = gradient(5, 3);
+        for (width, height) in [(5, 3), (6, 3), (5, 4), (0, 2), (2, 0)] {
+            let transformation = Transformation::Downscale { width, height };
+            assert!(transformation.verify(&original, &gradient(width, height)).is_err(), "{:?}", transformation);
+        }
+        let transformation = Transformation::Downscale { width: 5, height: 2 };
+        assert!(transformation.verify(&original, &gradient(5, 3)).is_err());
+        let edited = image(5, 2, |x, y| mean(&original, x..x + 1, [0..1, 1..3][y as usize].clone()));
+        transformation.verify(&original, &edited).unwrap();
+    }
+
+    #[test]
+    fn grayscale_keeps_alpha() {
+        let original = image(3, 2, |x, y| [(x * 90) as u8, (y * 200) as u8, 30, 128 + x as u8]);
+        let luma = |p: &[u8]| ((299 * p[0] as u32 + 587 * p[1] as u32 + 114 * p[2] as u32) / 1000) as u8;
+        let edited = image(3, 2, |x, y| {
+            let p = original.pixel(x, y);
+            [luma(p), luma(p), luma(p), p[3]]
+        });
+        Transformation::Grayscale.verify(&original, &edited).unwrap();
+        assert!(Transformation::Grayscale.verify(&original, &original).is_err());
+        assert!(Transformation::Grayscale.verify(&original, &nudged(&edited, 2, 1, 2)).is_err());
+        let mut opaque = edited.clone();
+        opaque.pixels[3] = 255;
+        assert!(Transformation::Grayscale.verify(&original, &opaque).is_err());
+        assert!(Transformation::Grayscale.verify(&original, &crop(&edited, 0, 0, 2, 2)).is_err());
+    }
+
+    #[test]
+    fn brightness_contrast_is_bounded() {
+        let original = gradient(4, 3);
+        let adjust = |brightness: i32, contrast: i32| {
+            image(4, 3, |x, y| {
+                let p = original.pixel(x, y);
+                let channel = |c: u8| ((c as i32 - 128) * contrast / 100 + 128 + brightness).clamp(0, 255) as u8;
+                [channel(p[0]), channel(p[1]), channel(p[2]), p[3]]
+            })
+        };
+        let transformation = |brightness, contrast_percent| Transformation::BrightnessContrast {
+            brightness,
+            contrast_percent,
+        };
+        transformation(20, 110).verify(&original, &adjust(20, 110)).unwrap();
+        transformation(-64, 50).verify(&original, &adjust(-64, 50)).unwrap();
+        transformation(64, 150).verify(&original, &adjust(64, 150)).unwrap();
+        assert!(transformation(65, 100).verify(&original, &adjust(65, 100)).is_err());
+        assert!(transformation(0, 49).verify(&original, &adjust(0, 49)).is_err());
+        assert!(transformation(0, 151).verify(&original, &adjust(0, 151)).is_err());
+        assert!(transformation(20, 110).verify(&original, &adjust(22, 110)).is_err());
+        let mut translucent = adjust(20, 110);
+        translucent.pixels[3] = 0;
+        assert!(transformation(20, 110).verify(&original, &translucent).is_err());
+    }
+
+    #[test]
+    fn jpeg_reencode_is_bounded_and_must_be_a_jpeg() {
+        let original = gradient(4, 3);
+        let reencoded = image(4, 3, |x, y| {
+            let p = original.pixel(x, y);
+            [p[0].saturating_add(((x + y) % 3) as u8), p[1], p[2].saturating_sub(1), p[3]]
+        });
+        let transformation = Transformation::JpegReencode { max_error: 2 };
+        transformation.verify(&original, &reencoded).unwrap();
+        assert!(transformation.verify(&original, &nudged(&reencoded, 3, 2, 3)).is_err());
+        assert!(Transformation::JpegReencode { max_error: 33 }.verify(&original, &original).is_err());
+        // Under `max_error` everywhere, but too far off on average
+        let overlay = image(4, 3, |x, y| {
+            let p = original.pixel(x, y);
+            [p[0].saturating_add(30), p[1].saturating_add(30), p[2].saturating_add(30), p[3]]
+        });
+        assert!(Transformation::JpegReencode { max_error: 32 }.verify(&original, &overlay).is_err());
+
+        transformation.verify_format(b"\xff\xd8\xff\xe0 rest of the file").unwrap();
+        assert!(transformation.verify_format(&reencoded.encode_raw()).is_err());
+        assert!(transformation.verify_format(b"\x89PNG\r\n\x1a\n").is_err());
+        Transformation::Grayscale.verify_format(&reencoded.encode_raw()).unwrap();
+    }
+
+    /// `original` with `region` box blurred by `radius`, the window clipped to the region
+    fn blurred(original: &RawImage, region: Region, radius: u32) -> RawImage {
+        image(original.width, original.height, |x, y| match region.contains(x, y) {
+            true => {
+                let xs = x.saturating_sub(radius).max(region.x)..(x + radius + 1).min(region.x + region.width);
+                let ys = y.saturating_sub(radius).max(region.y)..(y + radius + 1).min(region.y + region.height);
+                mean(original, xs, ys)
+            }
+            false => original.pixel(x, y).try_into().unwrap(),
+        })
+    }
+
+    /// `original` with `region` set to `rgba`
+    fn filled(original: &RawImage, region: Region, rgba: [u8; 4]) -> RawImage {
+        image(original.width, original.height, |x, y| match region.contains(x, y) {
+            true => rgba,
+            false => original.pixel(x, y).try_into().unwrap(),
+        })
+    }
+
+    const FACE: Region = Region { x: 2, y: 3, width: 18, height: 12 };
+    const PLATE: Region = Region { x: 20, y: 16, width: 4, height: 2 };
+    const BLACK: [u8; 4] = [0, 0, 0, 255];
+
+    fn redact(redactions: &[(Region, RedactionStyle)]) -> Transformation {
+        let redactions = redactions.iter().map(|&(region, style)| Redaction { region, style }).collect();
+        Transformation::Redact { redactions }
+    }
+
+    #[test]
+    fn redaction_is_accepted() {
+        let original = gradient(24, 20);
+        let edited = filled(&blurred(&original, FACE, 8), PLATE, BLACK);
+        let transformation = redact(&[
+            (FACE, RedactionStyle::Blur { radius: 8 }),
+            (PLATE, RedactionStyle::Fill { rgba: BLACK }),
+        ]);
+        transformation.verify(&original, &edited).unwrap();
+        transformation.verify(&original, &nudged(&edited, 10, 10, 1)).unwrap();
+        assert_eq!("redact=blur:8@2,3,18,12;fill:000000ff@20,16,4,2".parse::<Transformation>(), Ok(transformation));
+    }
+
+    #[test]
+    fn redaction_modifying_pixels_outside_its_regions_is_rejected() {
+        let original = gradient(24, 20);
+        let edited = filled(&original, PLATE, BLACK);
+        let transformation = redact(&[(PLATE, RedactionStyle::Fill { rgba: BLACK })]);
+        transformation.verify(&original, &edited).unwrap();
+        // Right next to the region, on each side
+        for (x, y) in [(19, 16), (20, 15), (23, 18), (0, 0)] {
+            let error = transformation.verify(&original, &nudged(&edited, x, y, 1)).unwrap_err();
+            assert!(error.contains("outside the redactions"), "{}", error);
+        }
+        // Filling a bigger region than declared
+        let bigger = Region { x: 19, ..PLATE };
+        assert!(transformation.verify(&original, &filled(&original, bigger, BLACK)).is_err());
+    }
+
+    #[test]
+    fn redaction_with_another_style_is_rejected() {
+        let original = gradient(24, 20);
+        let edited = blurred(&original, FACE, 8);
+        for radius in [9, 10, 16] {
+            let transformation = redact(&[(FACE, RedactionStyle::Blur { radius })]);
+            assert!(transformation.verify(&original, &edited).is_err(), "radius {}", radius);
+        }
+        let transformation = redact(&[(FACE, RedactionStyle::Blur { radius: 8 })]);
+        assert!(transformation.verify(&original, &nudged(&edited, 10, 10, 2)).is_err());
+        // Too weak to hide anything, even when applied as declared
+        let weak = redact(&[(FACE, RedactionStyle::Blur { radius: 7 })]);
+        assert!(weak.verify(&original, &blurred(&original, FACE, 7)).is_err());
+
+        let edited = filled(&original, PLATE, BLACK);
+        let white = redact(&[(PLATE, RedactionStyle::Fill { rgba: [255; 4] })]);
+        assert!(white.verify(&original, &edited).is_err());
+        let translucent = redact(&[(PLATE, RedactionStyle::Fill { rgba: [0, 0, 0, 254] })]);
+        assert!(translucent.verify(&original, &edited).is_err());
+        let blur = redact(&[(PLATE, RedactionStyle::Blur { radius: 8 })]);
+        assert!(blur.verify(&original, &edited).is_err());
+    }
+
+    #[test]
+    fn redaction_regions_must_fit_without_overlapping() {
+        let original = gradient(24, 20);
+        let fill = RedactionStyle::Fill { rgba: BLACK };
+        let outside = Region { x: 21, ..PLATE };
+        assert!(redact(&[(outside, fill)]).verify(&original, &original).is_err());
+        let overlapping = Region { x: 19, y: 14, width: 2, height: 3 };
+        let edited = filled(&filled(&original, FACE, BLACK), overlapping, BLACK);
+        assert!(redact(&[(FACE, fill), (overlapping, fill)]).verify(&original, &edited).is_err());
+        assert!(redact(&[]).verify(&original, &original).is_err());
+    }
+
+    #[test]
+    fn ppm_header_ends_with_a_single_whitespace_byte() {
+        let pixels = [1, 2, 3, 4, 5, 6];
+        let ppm = |header: &[u8]| [header, &pixels].concat();
+        let expected = RawImage::new(2, 1, vec![1, 2, 3, 255, 4, 5, 6, 255]).unwrap();
+        assert_eq!(RawImage::decode(&ppm(b"P6 2 1 255\n")), Ok(expected.clone()));
+        assert_eq!(RawImage::decode(&ppm(b"P6\n# comment\n2 1\n255 ")), Ok(expected));
+        // The first pixel byte would be taken as the separator
+        assert!(RawImage::decode(&ppm(b"P6 2 1 255")).is_err());
+        assert!(RawImage::decode(&ppm(b"P6 2 1 255\n\n")).is_err());
+        assert!(RawImage::decode(&ppm(b"P6 2 1 65535\n")).is_err());
+        assert!(RawImage::decode(b"P6 2 1 255").is_err());
+        assert!(RawImage::decode(&ppm(b"P6 2 2 255\n")).is_err());
+    }
+}
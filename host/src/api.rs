@@ -0,0 +1,481 @@
+use std::collections::{BTreeSet, HashMap};
+use contract::device::CaptureAttestation;
+use contract::governance::Revocation;
+use contract::hash::ContentHash;
+use contract::ownership::normalize_key;
+use contract::provenance::{AiDisclosure, ProvenanceClass};
+use contract::ImageMetadata;
+use schemars::JsonSchema;
+use serde::Serialize;
+use warp::http::StatusCode;
+use warp::reject::Reject;
+use warp::{Rejection, Reply};
+use crate::state_cache::Index;
+use crate::pipeline::{PipelineError, Stage};
+
+/// Page size of the collection routes when `limit` isn't given, and its maximum
+pub const DEFAULT_LIMIT: usize = 50;
+pub const MAX_LIMIT: usize = 500;
+
+/// Message of the `not-found` error of paths no route serves
+pub const NO_SUCH_ROUTE: &str = "No such route";
+
+/// Class of an `ApiError`, serialized as its `code`. Each class has its own HTTP status.
+#[derive(Serialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ErrorKind {
+    /// 400: malformed body, query, hash or image
+    InvalidInput,
+    /// 401: missing or invalid API key or signature
+    Unauthorized,
+    /// 403: the key lacks the scope of the route
+    Forbidden,
+    /// 404: unknown route, image or job
+    NotFound,
+    /// 405
+    MethodNotAllowed,
+    /// 411: a body was sent without `Content-Length`, so its size couldn't be checked
+    LengthRequired,
+    /// 413
+    PayloadTooLarge,
+    /// 415
+    UnsupportedMediaType,
+    /// 422: the contract refused to execute the action
+    ContractRejected,
+    /// 429: the key sent more requests than its rate limit
+    RateLimited,
+    /// 500: the proof couldn't be generated
+    ProverFailed,
+    /// 500
+    Internal,
+    /// 503: the node couldn't be reached or didn't accept a transaction
+    NodeUnavailable,
+}
+
+impl ErrorKind {
+    pub fn status(self) -> StatusCode {
+        match self {
+            ErrorKind::InvalidInput => StatusCode::BAD_REQUEST,
+            ErrorKind::Unauthorized => StatusCode::UNAUTHORIZED,
+            ErrorKind::Forbidden => StatusCode::FORBIDDEN,
+            ErrorKind::NotFound => StatusCode::NOT_FOUND,
+            ErrorKind::MethodNotAllowed => StatusCode::METHOD_NOT_ALLOWED,
+            ErrorKind::LengthRequired => StatusCode::LENGTH_REQUIRED,
+            ErrorKind::PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            ErrorKind::UnsupportedMediaType => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ErrorKind::ContractRejected => StatusCode::UNPROCESSABLE_ENTITY,
+            ErrorKind::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+            ErrorKind::ProverFailed | ErrorKind::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+            ErrorKind::NodeUnavailable => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+}
+
+/// Error of every route, replied by `recover` with the status of its kind and the body
+/// `{"code": "<kind>", "message": "..."}`
+#[derive(Serialize, JsonSchema, Debug)]
+pub struct ApiError {
+    pub code: ErrorKind,
+    pub message: String,
+}
+
+impl Reject for ApiError {}
+
+impl ApiError {
+    pub fn new(code: ErrorKind, message: impl Into<String>) -> Self {
+        ApiError {
+            code,
+            message: message.into(),
+        }
+    }
+
+    pub fn bad_request(message: impl Into<String>) -> Self {
+        ApiError::new(ErrorKind::InvalidInput, message)
+    }
+
+    pub fn not_found(message: impl Into<String>) -> Self {
+        ApiError::new(ErrorKind::NotFound, message)
+    }
+}
+
+/// Node stages fail as `node-unavailable`, execution as `contract-rejected` and proving as `prover-failed`
+impl From<PipelineError> for ApiError {
+    fn from(error: PipelineError) -> Self {
+        let code = match error.stage {
+            Stage::FetchState | Stage::SendBlob | Stage::SendProof | Stage::Settle => ErrorKind::NodeUnavailable,
+            Stage::Execute => ErrorKind::ContractRejected,
+            Stage::Prove => ErrorKind::ProverFailed,
+        };
+        ApiError::new(code, error.to_string())
+    }
+}
+
+/// Turns every rejection into a JSON `ApiError` reply, warp's own rejections included
+pub async fn recover(rejection: Rejection) -> Result<impl Reply, Rejection> {
+    let error = if let Some(error) = rejection.find::<ApiError>() {
+        ApiError::new(error.code, error.message.clone())
+    } else if rejection.is_not_found() {
+        ApiError::not_found(NO_SUCH_ROUTE)
+    } else if let Some(error) = rejection.find::<warp::filters::body::BodyDeserializeError>() {
+        ApiError::bad_request(error.to_string())
+    } else if let Some(error) = rejection.find::<warp::reject::InvalidQuery>() {
+        ApiError::bad_request(error.to_string())
+    } else if let Some(error) = rejection.find::<warp::reject::MethodNotAllowed>() {
+        ApiError::new(ErrorKind::MethodNotAllowed, error.to_string())
+    } else if let Some(error) = rejection.find::<warp::reject::LengthRequired>() {
+        ApiError::new(ErrorKind::LengthRequired, error.to_string())
+    } else if let Some(error) = rejection.find::<warp::reject::PayloadTooLarge>() {
+        ApiError::new(ErrorKind::PayloadTooLarge, error.to_string())
+    } else if let Some(error) = rejection.find::<warp::reject::UnsupportedMediaType>() {
+        ApiError::new(ErrorKind::UnsupportedMediaType, error.to_string())
+    } else if let Some(error) = rejection.find::<warp::cors::CorsForbidden>() {
+        ApiError::new(ErrorKind::Forbidden, error.to_string())
+    } else {
+        eprintln!("Unhandled rejection: {:?}", rejection);
+        ApiError::new(ErrorKind::Internal, "Unhandled rejection")
+    };
+    Ok(warp::reply::with_status(warp::reply::json(&error), error.code.status()))
+}
+
+#[derive(Serialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ImageStatus {
+    Original,
+    Edit,
+    /// Revoked by the admin keys, itself or an image it was edited from
+    Revoked,
+}
+
+/// Pagination and filters of the collection routes: `offset`, `limit`, `kind` (`original` or `edit`) and the
+/// registration height range `since`..=`until`
+#[derive(Debug, Clone, Default)]
+pub struct Filter {
+    pub offset: usize,
+    pub limit: usize,
+    pub is_root: Option<bool>,
+    pub since: Option<u64>,
+    pub until: Option<u64>,
+}
+
+impl Filter {
+    pub fn parse(query: &HashMap<String, String>) -> Result<Self, ApiError> {
+        let number = |name: &str| -> Result<Option<u64>, ApiError> {
+            query
+                .get(name)
+                .map(|value| value.parse().map_err(|_| ApiError::bad_request(format!("Invalid {}: '{}'", name, value))))
+                .transpose()
+        };
+        let limit = number("limit")?.map_or(DEFAULT_LIMIT, |limit| limit as usize);
+        if limit == 0 || limit > MAX_LIMIT {
+            return Err(ApiError::bad_request(format!("limit must be between 1 and {}", MAX_LIMIT)));
+        }
+        let is_root = match query.get("kind").map(String::as_str) {
+            None => None,
+            Some("original") => Some(true),
+            Some("edit") => Some(false),
+            Some(kind) => return Err(ApiError::bad_request(format!("Invalid kind '{}', expected original or edit", kind))),
+        };
+        Ok(Filter {
+            offset: number("offset")?.unwrap_or(0) as usize,
+            limit,
+            is_root,
+            since: number("since")?,
+            until: number("until")?,
+        })
+    }
+
+    fn matches(&self, metadata: &ImageMetadata) -> bool {
+        self.is_root.is_none_or(|is_root| metadata.is_root == is_root)
+            && self.since.is_none_or(|since| metadata.registered_at >= since)
+            && self.until.is_none_or(|until| metadata.registered_at <= until)
+    }
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    /// Items matching the filters, over all pages
+    pub total: usize,
+    pub offset: usize,
+    pub limit: usize,
+}
+
+/// Image as listed by the collection routes
+#[derive(Serialize, JsonSchema)]
+pub struct ImageSummary<'a> {
+    pub image_hash: ContentHash,
+    pub status: ImageStatus,
+    pub previous_image_hash: Option<ContentHash>,
+    pub provenance: &'a ProvenanceClass,
+    pub registered_at: u64,
+}
+
+/// Full resource of `GET /images/{hash}`
+#[derive(Serialize, JsonSchema)]
+pub struct ImageResource<'a> {
+    pub image_hash: ContentHash,
+    pub status: ImageStatus,
+    /// Original the image descends from
+    pub root: Option<ContentHash>,
+    pub metadata: &'a ImageMetadata,
+    /// Edits registered directly from the image
+    pub edits: Vec<ContentHash>,
+    pub camera_attested: bool,
+    pub capture: Option<&'a CaptureAttestation>,
+    pub flagged: bool,
+    pub ai_disclosure: Option<AiDisclosure>,
+    pub revocation: Option<&'a Revocation>,
+}
+
+/// `GET /images/{hash}`, by file, canonical or any typed form of the hash
+pub fn image<'a>(index: &'a Index, hash: &str) -> Result<ImageResource<'a>, ApiError> {
+    let state = &index.state;
+    let key = resolve(index, hash)?;
+    let capture = state.camera_attestation(key);
+    Ok(ImageResource {
+        image_hash: key,
+        status: status(index, key),
+        root: state.find_original_image(key).ok(),
+        metadata: &state.hash_map[&key],
+        edits: index.indexes.descendants.get(&key).into_iter().flatten().copied().collect(),
+        camera_attested: capture.is_some(),
+        capture,
+        flagged: state.is_flagged(key),
+        ai_disclosure: state.ai_disclosure(key),
+        revocation: state.revocation(key).map(|(_, revocation)| revocation),
+    })
+}
+
+/// `GET /images/{hash}/lineage`: the image then every image it was edited from, up to its original
+pub fn lineage<'a>(index: &'a Index, hash: &str, filter: &Filter) -> Result<Page<ImageSummary<'a>>, ApiError> {
+    let key = resolve(index, hash)?;
+    Ok(page(index, index.state.lineage(key), filter))
+}
+
+/// `GET /owners/{pk}/images`: images the key owns or co-owns, by registration height
+pub fn owner_images<'a>(index: &'a Index, pk: &str, filter: &Filter) -> Page<ImageSummary<'a>> {
+    page(index, by_registration(index, index.indexes.owners.get(&normalize_key(pk))), filter)
+}
+
+/// `GET /publishers/{pk}/grants`: originals the key was granted to publish, by registration height
+pub fn publisher_grants<'a>(index: &'a Index, pk: &str, filter: &Filter) -> Page<ImageSummary<'a>> {
+    page(index, by_registration(index, index.indexes.publishers.get(&normalize_key(pk))), filter)
+}
+
+fn resolve(index: &Index, hash: &str) -> Result<ContentHash, ApiError> {
+    let hash: ContentHash = hash.parse().map_err(|e: String| ApiError::bad_request(e))?;
+    index
+        .state
+        .resolve_hash(&hash)
+        .ok_or(ApiError::not_found(format!("Image {} is not registered", hash)))
+}
+
+fn status(index: &Index, key: ContentHash) -> ImageStatus {
+    match (index.state.revocation(key), index.state.hash_map[&key].is_root) {
+        (Some(_), _) => ImageStatus::Revoked,
+        (None, true) => ImageStatus::Original,
+        (None, false) => ImageStatus::Edit,
+    }
+}
+
+fn by_registration(index: &Index, hashes: Option<&BTreeSet<ContentHash>>) -> Vec<ContentHash> {
+    let mut hashes: Vec<ContentHash> = hashes.into_iter().flatten().copied().collect();
+    hashes.sort_by_key(|hash| (index.state.hash_map[hash].registered_at, *hash));
+    hashes
+}
+
+fn page<'a>(index: &'a Index, hashes: Vec<ContentHash>, filter: &Filter) -> Page<ImageSummary<'a>> {
+    let matching: Vec<(ContentHash, &ImageMetadata)> = hashes
+        .into_iter()
+        .filter_map(|hash| index.state.hash_map.get(&hash).map(|metadata| (hash, metadata)))
+        .filter(|(_, metadata)| filter.matches(metadata))
+        .collect();
+    Page {
+        total: matching.len(),
+        items: matching
+            .into_iter()
+            .skip(filter.offset)
+            .take(filter.limit)
+            .map(|(hash, metadata)| ImageSummary {
+                image_hash: hash,
+                status: status(index, hash),
+                previous_image_hash: metadata.previous_image_hash,
+                provenance: &metadata.provenance,
+                registered_at: metadata.registered_at,
+            })
+            .collect(),
+        offset: filter.offset,
+        limit: filter.limit,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+    use async_trait::async_trait;
+    use contract::hash;
+    use contract::ownership::OwnerKeys;
+    use contract::{ImageAction, ImageState};
+    use sdk::{BlobTransaction, ContractInput, Digestable, ProofData, ProofTransaction, StateDigest, TxHash};
+    use serde_json::{json, Value};
+    use warp::test::RequestBuilder;
+    use warp::Filter;
+    use crate::audit::AuditLog;
+    use crate::auth::Auth;
+    use crate::jobs::JobQueue;
+    use crate::pipeline::{Node, Pipeline, Prover, RetryPolicy};
+    use crate::state_cache::StateCache;
+    use super::*;
+
+    /// Bodies over this size are rejected by the routes under test
+    const MAX_BODY_BYTES: u64 = 1024;
+
+    /// Node serving an empty state but accepting no transaction, and a prover that always fails
+    struct Offline;
+
+    #[async_trait]
+    impl Node for Offline {
+        async fn contract_state(&self, _contract_name: &str) -> anyhow::Result<StateDigest> {
+            Ok(ImageState::default().as_digest())
+        }
+
+        async fn send_tx_blob(&self, _tx: &BlobTransaction) -> anyhow::Result<TxHash> {
+            anyhow::bail!("connection refused")
+        }
+
+        async fn send_tx_proof(&self, _tx: &ProofTransaction) -> anyhow::Result<TxHash> {
+            anyhow::bail!("connection refused")
+        }
+    }
+
+    #[async_trait]
+    impl Prover for Offline {
+        async fn prove(&self, _inputs: ContractInput) -> anyhow::Result<ProofData> {
+            anyhow::bail!("no prover")
+        }
+    }
+
+    /// The server routes on an empty index, with a `writer`, a `reader` and a `limited` API key
+    async fn routes(dir: &Path) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+        std::fs::create_dir_all(dir).unwrap();
+        let auth_file = dir.join("auth.json");
+        let keys = json!({"api_keys": [
+            {"name": "writer", "key": "writer", "scopes": ["read", "write"]},
+            {"name": "reader", "key": "reader", "scopes": ["read"]},
+            {"name": "limited", "key": "limited", "scopes": ["read"], "requests_per_minute": 1},
+        ]});
+        std::fs::write(&auth_file, keys.to_string()).unwrap();
+        let pipeline = Pipeline::new(
+            Arc::new(Offline),
+            Arc::new(Offline),
+            "image_registry".into(),
+            "none.image_registry".into(),
+            RetryPolicy::default(),
+        );
+        let jobs = JobQueue::open(pipeline, 1, dir.join("jobs")).await.unwrap();
+        let audit = Arc::new(AuditLog::open(&dir.join("audit.log")).unwrap());
+        crate::routes(jobs, StateCache::new(Index::default()), Auth::load(&auth_file).unwrap(), audit, MAX_BODY_BYTES)
+    }
+
+    fn as_key(key: &str, request: RequestBuilder) -> RequestBuilder {
+        request.header("authorization", format!("Bearer {}", key))
+    }
+
+    fn submit(action: ImageAction) -> RequestBuilder {
+        as_key("writer", warp::test::request().method("POST").path("/submit")).json(&json!({ "action": action }))
+    }
+
+    fn register(file: &str) -> ImageAction {
+        ImageAction::RegisterImage {
+            image_hash: hash::file_hash(file.as_bytes()),
+            canonical_hash: None,
+            perceptual_hash: None,
+            image_signature: String::new(),
+            owner_pk: OwnerKeys::single("owner".into()),
+        }
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("api-{}-{}", name, std::process::id()))
+    }
+
+    #[tokio::test]
+    async fn each_kind_replies_its_status_and_code() {
+        let dir = temp_dir("kinds");
+        let routes = routes(&dir).await;
+        let read = |path: &str| as_key("writer", warp::test::request().path(path));
+        let post_submit = || as_key("writer", warp::test::request().method("POST").path("/submit"));
+        let sha256 = format!("/images/sha256:{}", "ab".repeat(32));
+        let pause = ImageAction::SetPaused {
+            paused: true,
+            admin_signature: "admin:forged".parse().unwrap(),
+        };
+        let cases = [
+            (read("/images/not-a-hash"), 400, "invalid-input"),
+            (post_submit().body("{"), 400, "invalid-input"),
+            (warp::test::request().path(&sha256), 401, "unauthorized"),
+            (as_key("unknown", warp::test::request().path(&sha256)), 401, "unauthorized"),
+            (as_key("reader", submit(register("a"))), 403, "forbidden"),
+            (read(&sha256), 404, "not-found"),
+            (read("/jobs/7"), 404, "not-found"),
+            (read("/nowhere"), 404, "not-found"),
+            (read("/submit"), 405, "method-not-allowed"),
+            (post_submit(), 411, "length-required"),
+            (post_submit().body(vec![b' '; MAX_BODY_BYTES as usize + 1]), 413, "payload-too-large"),
+            // Rejected by the contract itself, as the pipeline executes it before sending anything
+            (submit(pause), 422, "contract-rejected"),
+            (submit(register("a")), 503, "node-unavailable"),
+        ];
+        for (request, expected_status, expected_code) in cases {
+            let response = request.reply(&routes).await;
+            let body: Value = serde_json::from_slice(response.body()).unwrap();
+            let reply = (response.status().as_u16(), body["code"].as_str());
+            assert_eq!(reply, (expected_status, Some(expected_code)), "{}", body);
+            assert!(body["message"].is_string());
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn rate_limited_keys_get_429() {
+        let dir = temp_dir("rate-limit");
+        let routes = routes(&dir).await;
+        let request = || as_key("limited", warp::test::request().path("/jobs/1"));
+        assert_eq!(request().reply(&routes).await.status(), StatusCode::NOT_FOUND);
+        let response = request().reply(&routes).await;
+        let body: Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!((response.status(), body["code"].as_str()), (StatusCode::TOO_MANY_REQUESTS, Some("rate-limited")));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    /// Kinds no route replies synchronously: proofs fail in the background, reported by `GET /jobs/{id}`, the
+    /// routes read their JSON bodies whatever their content type, and every warp rejection of the routes is mapped.
+    #[tokio::test]
+    async fn other_kinds_reply_their_status_and_code() {
+        #[derive(Debug)]
+        struct Unexpected;
+        impl Reject for Unexpected {}
+
+        let prover_failed = PipelineError::new(Stage::Prove, anyhow::anyhow!("failed"));
+        let unsupported = warp::test::request()
+            .method("POST")
+            .header("content-type", "text/plain")
+            .body("{}")
+            .filter(&warp::body::json::<Value>())
+            .await
+            .err()
+            .unwrap();
+        let cases = [
+            (warp::reject::custom(ApiError::from(prover_failed)), 500, "prover-failed"),
+            (unsupported, 415, "unsupported-media-type"),
+            (warp::reject::custom(Unexpected), 500, "internal"),
+        ];
+        for (rejection, expected_status, expected_code) in cases {
+            let response = recover(rejection).await.unwrap().into_response();
+            let (status, body) = (response.status(), warp::hyper::body::to_bytes(response.into_body()).await.unwrap());
+            let body: Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!((status.as_u16(), body["code"].as_str()), (expected_status, Some(expected_code)), "{}", body);
+        }
+    }
+}
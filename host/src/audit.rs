@@ -0,0 +1,61 @@
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Mutex;
+use anyhow::{Context, Result};
+use contract::ImageAction;
+use sdk::TxHash;
+use serde::Serialize;
+use crate::auth::{unix_time, Caller};
+use crate::jobs::Job;
+use crate::pipeline::PipelineError;
+
+/// Line of the audit log
+#[derive(Serialize)]
+struct Entry<'a> {
+    /// Unix timestamp, in seconds
+    at: u64,
+    caller: &'a str,
+    addr: Option<SocketAddr>,
+    action: &'a ImageAction,
+    job: Option<u64>,
+    blob_tx_hash: Option<&'a TxHash>,
+    error: Option<String>,
+}
+
+/// Append-only log of who submitted which action through the server, one JSON object per line.
+/// Private inputs are left out.
+pub struct AuditLog {
+    file: Mutex<File>,
+}
+
+impl AuditLog {
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Could not open audit log {}", path.display()))?;
+        Ok(AuditLog { file: Mutex::new(file) })
+    }
+
+    /// Records a submission, whether a job was created for it or it failed
+    pub fn record(&self, caller: &Caller, action: &ImageAction, outcome: &Result<Job, PipelineError>) {
+        let entry = Entry {
+            at: unix_time(),
+            caller: &caller.name,
+            addr: caller.addr,
+            action,
+            job: outcome.as_ref().ok().map(|job| job.id),
+            blob_tx_hash: outcome.as_ref().ok().map(|job| &job.blob_tx_hash),
+            error: outcome.as_ref().err().map(|error| error.to_string()),
+        };
+        let written = serde_json::to_string(&entry)
+            .map_err(anyhow::Error::from)
+            .and_then(|line| Ok(writeln!(self.file.lock().unwrap(), "{}", line)?));
+        if let Err(error) = written {
+            eprintln!("⚠️ Could not write the audit log: {:#}", error);
+        }
+    }
+}
@@ -0,0 +1,474 @@
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use anyhow::{Context, Result};
+use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use warp::filters::path::FullPath;
+use warp::filters::BoxedFilter;
+use warp::http::Method;
+use warp::hyper::body::Bytes;
+use warp::{Filter, Rejection};
+use crate::api::{ApiError, ErrorKind};
+
+/// Largest gap between the timestamp of a signed request and the server clock
+pub const SIGNATURE_WINDOW: Duration = Duration::from_secs(300);
+/// Signatures remembered at once. Once as many were accepted within the window, signed requests are refused
+/// until the oldest expire: forgetting one early would let it be replayed.
+const MAX_SEEN_SIGNATURES: usize = 100_000;
+
+/// Caller name of the requests when authentication is disabled
+const ANONYMOUS: &str = "anonymous";
+
+/// What a key may do: `read` the routes that don't submit anything, `write` the ones that submit actions
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Scope {
+    Read,
+    Write,
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Scope::Read => write!(f, "read"),
+            Scope::Write => write!(f, "write"),
+        }
+    }
+}
+
+/// `--auth-file` of the server
+#[derive(Deserialize)]
+struct AuthConfig {
+    #[serde(default)]
+    api_keys: Vec<ApiKeyConfig>,
+    #[serde(default)]
+    signing_keys: Vec<SigningKeyConfig>,
+}
+
+/// Static key sent as `Authorization: Bearer <key>`
+#[derive(Deserialize)]
+struct ApiKeyConfig {
+    name: String,
+    key: String,
+    scopes: Vec<Scope>,
+    requests_per_minute: Option<u32>,
+}
+
+/// Ed25519 public key, hex encoded, of a client signing its requests
+#[derive(Deserialize)]
+struct SigningKeyConfig {
+    name: String,
+    pk: String,
+    scopes: Vec<Scope>,
+    requests_per_minute: Option<u32>,
+}
+
+/// Client allowed by the auth file
+struct Principal {
+    name: String,
+    scopes: Vec<Scope>,
+    limit: Option<Mutex<TokenBucket>>,
+}
+
+impl Principal {
+    fn new(name: String, scopes: Vec<Scope>, requests_per_minute: Option<u32>) -> Arc<Self> {
+        Arc::new(Principal {
+            name,
+            scopes,
+            limit: requests_per_minute.map(|rate| Mutex::new(TokenBucket::new(rate))),
+        })
+    }
+}
+
+/// Allows bursts of `requests_per_minute` requests, refilled continuously
+struct TokenBucket {
+    capacity: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(requests_per_minute: u32) -> Self {
+        TokenBucket {
+            capacity: requests_per_minute as f64,
+            tokens: requests_per_minute as f64,
+            updated: Instant::now(),
+        }
+    }
+
+    fn take(&mut self) -> bool {
+        let now = Instant::now();
+        let refill = now.duration_since(self.updated).as_secs_f64() * self.capacity / 60.0;
+        self.tokens = (self.tokens + refill).min(self.capacity);
+        self.updated = now;
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+struct Keys {
+    /// SHA-256 of the API key -> its client
+    api_keys: HashMap<[u8; 32], Arc<Principal>>,
+    signing_keys: HashMap<[u8; 32], (VerifyingKey, Arc<Principal>)>,
+    seen: Mutex<SeenSignatures>,
+}
+
+/// Signatures accepted whose timestamp is still within `SIGNATURE_WINDOW`, so a signed request can't be replayed.
+/// A signature covers its timestamp: once the timestamp is out of the window, the signature is refused anyway.
+struct SeenSignatures {
+    /// Ordered by timestamp, to expire the oldest first
+    signatures: BTreeSet<(u64, [u8; 64])>,
+    capacity: usize,
+}
+
+impl SeenSignatures {
+    fn new(capacity: usize) -> Self {
+        SeenSignatures {
+            signatures: BTreeSet::new(),
+            capacity,
+        }
+    }
+
+    /// Records a signature made at `timestamp`, unless it was already used or too many are in the window
+    fn insert(&mut self, now: u64, timestamp: u64, signature: [u8; 64]) -> Result<(), ApiError> {
+        let oldest = now.saturating_sub(SIGNATURE_WINDOW.as_secs());
+        self.signatures = self.signatures.split_off(&(oldest, [0; 64]));
+        if self.signatures.contains(&(timestamp, signature)) {
+            return Err(unauthorized("Signature was already used"));
+        }
+        if self.signatures.len() >= self.capacity {
+            return Err(ApiError::new(ErrorKind::RateLimited, "Too many signed requests, retry in a few minutes"));
+        }
+        self.signatures.insert((timestamp, signature));
+        Ok(())
+    }
+}
+
+/// Who sent a request, as recorded by the audit log
+#[derive(Debug, Clone)]
+pub struct Caller {
+    pub name: String,
+    pub addr: Option<SocketAddr>,
+}
+
+/// Authenticates the requests of the server with the keys of `--auth-file`, either an API key or a signature
+/// of the request by a registered Ed25519 key, then checks the scope and the rate limit of the key.
+#[derive(Clone)]
+pub struct Auth {
+    /// `None` when authentication is disabled
+    keys: Option<Arc<Keys>>,
+}
+
+impl Auth {
+    /// Accepts every request, as `anonymous`
+    pub fn disabled() -> Self {
+        Auth { keys: None }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.keys.is_some()
+    }
+
+    /// Reads the keys of a JSON auth file: `{"api_keys": [{"name", "key", "scopes", "requests_per_minute"}],
+    /// "signing_keys": [{"name", "pk", "scopes", "requests_per_minute"}]}`
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path).with_context(|| format!("Could not read {}", path.display()))?;
+        let config: AuthConfig = serde_json::from_str(&text).with_context(|| format!("Invalid auth file {}", path.display()))?;
+        Self::from_config(config, MAX_SEEN_SIGNATURES)
+    }
+
+    fn from_config(config: AuthConfig, max_seen_signatures: usize) -> Result<Self> {
+        let mut api_keys = HashMap::new();
+        for key in config.api_keys {
+            let principal = Principal::new(key.name, key.scopes, key.requests_per_minute);
+            api_keys.insert(Sha256::digest(key.key.as_bytes()).into(), principal);
+        }
+        let mut signing_keys = HashMap::new();
+        for key in config.signing_keys {
+            let pk: [u8; 32] = hex::decode(&key.pk)
+                .ok()
+                .and_then(|bytes| bytes.try_into().ok())
+                .with_context(|| format!("Signing key of {} is not a hex encoded Ed25519 public key", key.name))?;
+            let verifying_key = VerifyingKey::from_bytes(&pk).with_context(|| format!("Invalid signing key of {}", key.name))?;
+            signing_keys.insert(pk, (verifying_key, Principal::new(key.name, key.scopes, key.requests_per_minute)));
+        }
+        Ok(Auth {
+            keys: Some(Arc::new(Keys {
+                api_keys,
+                signing_keys,
+                seen: Mutex::new(SeenSignatures::new(max_seen_signatures)),
+            })),
+        })
+    }
+
+    /// Authenticates the request for `scope` and hands its body over, which the signature covers. Bodies over
+    /// `max_bytes`, or without a `Content-Length`, are rejected.
+    pub fn bytes(&self, scope: Scope, max_bytes: u64) -> impl Filter<Extract = (Caller, Bytes), Error = Rejection> + Clone {
+        self.authenticated(scope, warp::body::content_length_limit(max_bytes).and(warp::body::bytes()).boxed())
+    }
+
+    /// Authenticates the request for `scope` and decodes its JSON body
+    pub fn json<T: DeserializeOwned + Send>(
+        &self,
+        scope: Scope,
+        max_bytes: u64,
+    ) -> impl Filter<Extract = (Caller, T), Error = Rejection> + Clone {
+        self.bytes(scope, max_bytes)
+            .and_then(|caller: Caller, body: Bytes| async move {
+                let value = serde_json::from_slice(&body)
+                    .map_err(|e| warp::reject::custom(ApiError::bad_request(format!("Request body deserialize error: {}", e))))?;
+                Ok::<_, Rejection>((caller, value))
+            })
+            .untuple_one()
+    }
+
+    /// Authenticates a request without body for `scope`
+    pub fn require(&self, scope: Scope) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+        self.authenticated(scope, warp::any().map(Bytes::new).boxed())
+            .map(|_: Caller, _: Bytes| ())
+            .untuple_one()
+    }
+
+    fn authenticated(&self, scope: Scope, body: BoxedFilter<(Bytes,)>) -> impl Filter<Extract = (Caller, Bytes), Error = Rejection> + Clone {
+        let auth = self.clone();
+        warp::method()
+            .and(warp::path::full())
+            .and(warp::query::raw().or(warp::any().map(String::new)).unify())
+            .and(warp::header::optional::<String>("authorization"))
+            .and(warp::addr::remote())
+            .and(body)
+            .and_then(
+                move |method: Method, path: FullPath, query: String, authorization: Option<String>, addr, body: Bytes| {
+                    let auth = auth.clone();
+                    async move {
+                        let target = match query.as_str() {
+                            "" => path.as_str().to_string(),
+                            query => format!("{}?{}", path.as_str(), query),
+                        };
+                        let name = auth.authenticate(scope, &method, &target, authorization.as_deref(), &body)?;
+                        Ok::<_, Rejection>((Caller { name, addr }, body))
+                    }
+                },
+            )
+            .untuple_one()
+    }
+
+    fn authenticate(
+        &self,
+        scope: Scope,
+        method: &Method,
+        target: &str,
+        authorization: Option<&str>,
+        body: &[u8],
+    ) -> Result<String, ApiError> {
+        let Some(keys) = &self.keys else {
+            return Ok(ANONYMOUS.to_string());
+        };
+        let principal = match authorization.and_then(|header| header.split_once(' ')) {
+            Some(("Bearer", key)) => keys
+                .api_keys
+                .get(&<[u8; 32]>::from(Sha256::digest(key.trim().as_bytes())))
+                .cloned()
+                .ok_or(unauthorized("Unknown API key"))?,
+            Some(("Signature", signature)) => keys.verify(method, target, signature.trim(), body)?,
+            _ => {
+                return Err(unauthorized(
+                    "Expected an `Authorization: Bearer <api key>` or `Authorization: Signature <pk>:<timestamp>:<signature>` header",
+                ))
+            }
+        };
+        if !principal.scopes.contains(&scope) {
+            return Err(ApiError::new(ErrorKind::Forbidden, format!("{} lacks the {} scope", principal.name, scope)));
+        }
+        if principal.limit.as_ref().is_some_and(|limit| !limit.lock().unwrap().take()) {
+            return Err(ApiError::new(ErrorKind::RateLimited, format!("Rate limit of {} exceeded", principal.name)));
+        }
+        Ok(principal.name.clone())
+    }
+}
+
+impl Keys {
+    /// Checks a `<pk>:<timestamp>:<signature>` header against `signed_message`
+    fn verify(&self, method: &Method, target: &str, header: &str, body: &[u8]) -> Result<Arc<Principal>, ApiError> {
+        let invalid = || unauthorized("Malformed signature, expected `<pk>:<timestamp>:<signature>` in hex");
+        let mut parts = header.splitn(3, ':');
+        let (Some(pk), Some(timestamp), Some(signature)) = (parts.next(), parts.next(), parts.next()) else {
+            return Err(invalid());
+        };
+        let pk: [u8; 32] = hex::decode(pk).ok().and_then(|bytes| bytes.try_into().ok()).ok_or_else(invalid)?;
+        let timestamp: u64 = timestamp.parse().map_err(|_| invalid())?;
+        let signature: [u8; 64] = hex::decode(signature).ok().and_then(|bytes| bytes.try_into().ok()).ok_or_else(invalid)?;
+
+        let (key, principal) = self.signing_keys.get(&pk).ok_or(unauthorized("Unknown signing key"))?;
+        let now = unix_time();
+        if now.abs_diff(timestamp) > SIGNATURE_WINDOW.as_secs() {
+            return Err(unauthorized("Signature timestamp is too far from the server clock"));
+        }
+        let message = signed_message(method, target, timestamp, body);
+        key.verify(message.as_bytes(), &Signature::from_bytes(&signature))
+            .map_err(|_| unauthorized("Invalid signature"))?;
+
+        self.seen.lock().unwrap().insert(now, timestamp, signature)?;
+        Ok(Arc::clone(principal))
+    }
+}
+
+/// Message a client signs: the method, the path with its query, the unix timestamp of the request and the
+/// SHA-256 of the body in hex, one per line
+pub fn signed_message(method: &Method, target: &str, timestamp: u64, body: &[u8]) -> String {
+    format!("{}\n{}\n{}\n{}", method, target, timestamp, hex::encode(Sha256::digest(body)))
+}
+
+pub fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_secs())
+}
+
+fn unauthorized(message: &str) -> ApiError {
+    ApiError::new(ErrorKind::Unauthorized, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use ed25519_dalek::{Signer, SigningKey};
+    use serde_json::json;
+    use super::*;
+
+    const BODY: &[u8] = b"{}";
+
+    fn newsroom() -> SigningKey {
+        SigningKey::from_bytes(&[7; 32])
+    }
+
+    /// `newsroom` signs with read and write scopes, `frontend` reads with a bearer key, `limited` gets 2 requests
+    fn auth(max_seen_signatures: usize) -> Auth {
+        let newsroom_pk = hex::encode(newsroom().verifying_key().as_bytes());
+        let config = json!({
+            "api_keys": [
+                {"name": "frontend", "key": "secret", "scopes": ["read"]},
+                {"name": "limited", "key": "limited", "scopes": ["read"], "requests_per_minute": 2},
+            ],
+            "signing_keys": [
+                {"name": "newsroom", "pk": newsroom_pk, "scopes": ["read", "write"]},
+            ],
+        });
+        Auth::from_config(serde_json::from_value(config).unwrap(), max_seen_signatures).unwrap()
+    }
+
+    /// `Authorization` header of `POST /submit` with `BODY`, signed by `key` at `timestamp`
+    fn signature(key: &SigningKey, timestamp: u64) -> String {
+        let signature = key.sign(signed_message(&Method::POST, "/submit", timestamp, BODY).as_bytes());
+        format!(
+            "Signature {}:{}:{}",
+            hex::encode(newsroom().verifying_key().as_bytes()),
+            timestamp,
+            hex::encode(signature.to_bytes())
+        )
+    }
+
+    fn submit(auth: &Auth, authorization: &str) -> Result<String, ErrorKind> {
+        auth.authenticate(Scope::Write, &Method::POST, "/submit", Some(authorization), BODY)
+            .map_err(|error| error.code)
+    }
+
+    fn read(auth: &Auth, authorization: Option<&str>) -> Result<String, ErrorKind> {
+        auth.authenticate(Scope::Read, &Method::GET, "/jobs/1", authorization, b"").map_err(|error| error.code)
+    }
+
+    #[test]
+    fn valid_signature_is_accepted_once() {
+        let auth = auth(MAX_SEEN_SIGNATURES);
+        let header = signature(&newsroom(), unix_time());
+        assert_eq!(submit(&auth, &header), Ok("newsroom".to_string()));
+        assert_eq!(submit(&auth, &header), Err(ErrorKind::Unauthorized));
+        // Over another body, method or path, the same signature doesn't verify
+        let other = auth.authenticate(Scope::Write, &Method::POST, "/submit?x", Some(&header), BODY);
+        assert_eq!(other.unwrap_err().code, ErrorKind::Unauthorized);
+        let header = signature(&newsroom(), unix_time() - 1);
+        let other = auth.authenticate(Scope::Write, &Method::POST, "/submit", Some(&header), b"{ }");
+        assert_eq!(other.unwrap_err().code, ErrorKind::Unauthorized);
+    }
+
+    #[test]
+    fn signature_timestamp_must_be_within_the_window() {
+        let auth = auth(MAX_SEEN_SIGNATURES);
+        let window = SIGNATURE_WINDOW.as_secs();
+        let now = unix_time();
+        assert!(submit(&auth, &signature(&newsroom(), now - window + 2)).is_ok());
+        assert!(submit(&auth, &signature(&newsroom(), now + window - 2)).is_ok());
+        assert_eq!(submit(&auth, &signature(&newsroom(), now - window - 2)), Err(ErrorKind::Unauthorized));
+        assert_eq!(submit(&auth, &signature(&newsroom(), now + window + 2)), Err(ErrorKind::Unauthorized));
+    }
+
+    #[test]
+    fn signature_of_another_key_is_rejected() {
+        let auth = auth(MAX_SEEN_SIGNATURES);
+        let thief = SigningKey::from_bytes(&[8; 32]);
+        assert_eq!(submit(&auth, &signature(&thief, unix_time())), Err(ErrorKind::Unauthorized));
+        let unknown = signature(&thief, unix_time()).replace(
+            &hex::encode(newsroom().verifying_key().as_bytes()),
+            &hex::encode(thief.verifying_key().as_bytes()),
+        );
+        let error = auth.authenticate(Scope::Write, &Method::POST, "/submit", Some(&unknown), BODY).unwrap_err();
+        assert_eq!((error.code, error.message.as_str()), (ErrorKind::Unauthorized, "Unknown signing key"));
+        for malformed in ["Signature ", "Signature ab:1:cd", "Signature nothex"] {
+            assert_eq!(submit(&auth, malformed), Err(ErrorKind::Unauthorized), "{}", malformed);
+        }
+    }
+
+    #[test]
+    fn bearer_keys_are_checked_for_their_scope() {
+        let auth = auth(MAX_SEEN_SIGNATURES);
+        assert_eq!(read(&auth, Some("Bearer secret")), Ok("frontend".to_string()));
+        assert_eq!(read(&auth, Some("Bearer  secret ")), Ok("frontend".to_string()));
+        assert_eq!(submit(&auth, "Bearer secret"), Err(ErrorKind::Forbidden));
+        assert_eq!(read(&auth, Some("Bearer guess")), Err(ErrorKind::Unauthorized));
+        assert_eq!(read(&auth, Some("bearer secret")), Err(ErrorKind::Unauthorized));
+        assert_eq!(read(&auth, Some("secret")), Err(ErrorKind::Unauthorized));
+        assert_eq!(read(&auth, None), Err(ErrorKind::Unauthorized));
+        assert_eq!(read(&Auth::disabled(), None), Ok(ANONYMOUS.to_string()));
+    }
+
+    #[test]
+    fn rate_limited_keys_run_out_of_tokens() {
+        let auth = auth(MAX_SEEN_SIGNATURES);
+        assert!(read(&auth, Some("Bearer limited")).is_ok());
+        assert!(read(&auth, Some("Bearer limited")).is_ok());
+        assert_eq!(read(&auth, Some("Bearer limited")), Err(ErrorKind::RateLimited));
+        // Other keys have their own bucket
+        assert!(read(&auth, Some("Bearer secret")).is_ok());
+
+        let mut bucket = TokenBucket::new(60);
+        bucket.tokens = 0.0;
+        assert!(!bucket.take());
+        bucket.updated -= Duration::from_secs(1);
+        assert!(bucket.take() && !bucket.take());
+    }
+
+    #[test]
+    fn seen_signatures_are_bounded_and_expire() {
+        let auth = auth(2);
+        let now = unix_time();
+        assert!(submit(&auth, &signature(&newsroom(), now)).is_ok());
+        assert!(submit(&auth, &signature(&newsroom(), now - 1)).is_ok());
+        assert_eq!(submit(&auth, &signature(&newsroom(), now - 2)), Err(ErrorKind::RateLimited));
+
+        let window = SIGNATURE_WINDOW.as_secs();
+        let mut seen = SeenSignatures::new(2);
+        seen.insert(now, now - window, [1; 64]).unwrap();
+        seen.insert(now, now + window, [2; 64]).unwrap();
+        assert_eq!(seen.insert(now, now, [3; 64]).unwrap_err().code, ErrorKind::RateLimited);
+        // A second later, the oldest is out of the window and can't be replayed anyway
+        seen.insert(now + 1, now, [3; 64]).unwrap();
+        assert_eq!(seen.signatures.len(), 2);
+        assert_eq!(seen.insert(now + 1, now + window, [2; 64]).unwrap_err().code, ErrorKind::Unauthorized);
+    }
+}
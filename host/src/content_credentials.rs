@@ -0,0 +1,342 @@
+use std::collections::HashMap;
+use std::io::Cursor;
+use anyhow::{anyhow, bail, Context, Result};
+use contract::hash::ContentHash;
+use contract::ownership::OwnerKeys;
+use contract::provenance::ProvenanceClass;
+use contract::transform::Transformation;
+use contract::{ImageAction, ImageMetadata, ImageState};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+/// IPTC digital source types used by C2PA to tell captures from generated media
+pub const DIGITAL_CAPTURE: &str = "http://cv.iptc.org/newscodes/digitalsourcetype/digitalCapture";
+pub const TRAINED_ALGORITHMIC_MEDIA: &str = "http://cv.iptc.org/newscodes/digitalsourcetype/trainedAlgorithmicMedia";
+pub const COMPOSITE_WITH_TRAINED_ALGORITHMIC_MEDIA: &str =
+    "http://cv.iptc.org/newscodes/digitalsourcetype/compositeWithTrainedAlgorithmicMedia";
+
+/// Assertion carrying the full on-chain lineage, which C2PA ingredients only describe one level deep
+pub const LINEAGE_ASSERTION: &str = "org.sthyle.lineage";
+
+/// Manifest store as printed by `c2pa::Reader::json`, reduced to what maps onto contract actions
+#[derive(Deserialize)]
+struct ManifestStore {
+    active_manifest: Option<String>,
+    #[serde(default)]
+    manifests: HashMap<String, Manifest>,
+}
+
+#[derive(Deserialize)]
+struct Manifest {
+    #[serde(default)]
+    ingredients: Vec<Ingredient>,
+    #[serde(default)]
+    assertions: Vec<Assertion>,
+    signature_info: Option<SignatureInfo>,
+}
+
+#[derive(Deserialize)]
+struct Ingredient {
+    instance_id: Option<String>,
+    relationship: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Assertion {
+    label: String,
+    #[serde(default)]
+    data: Value,
+}
+
+#[derive(Deserialize)]
+struct Actions {
+    actions: Vec<Action>,
+}
+
+#[derive(Deserialize)]
+struct Action {
+    action: String,
+    #[serde(rename = "digitalSourceType")]
+    digital_source_type: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct SignatureInfo {
+    issuer: Option<String>,
+}
+
+/// Claims of the active manifest of a file
+#[derive(Debug)]
+pub struct ImportedManifest {
+    /// Whether the manifest has a `parentOf` ingredient, i.e. describes an edit
+    pub has_parent: bool,
+    /// On-chain hash of the parent, when its ingredient `instance_id` is one (as in exported manifests)
+    pub parent: Option<ContentHash>,
+    /// `c2pa.*` action names, in manifest order
+    pub actions: Vec<String>,
+    /// Whether an action declares a generative digital source type
+    pub generative: bool,
+    /// Issuer of the manifest signing certificate, for display only: it is not a key that could own an image
+    pub signer: Option<String>,
+}
+
+/// Reads and validates the C2PA manifest embedded in a JPEG or PNG file. A manifest with validation errors
+/// (tampered assets or claims, bad signature or certificate) is rejected.
+pub fn read(bytes: &[u8]) -> Result<ImportedManifest> {
+    let format = image::guess_format(bytes).context("Unrecognised image format")?;
+    let reader = c2pa::Reader::from_stream(format.to_mime_type(), Cursor::new(bytes))
+        .map_err(|e| anyhow!("Could not read C2PA manifest: {}", e))?;
+    if let Some(errors) = reader.validation_status().filter(|errors| !errors.is_empty()) {
+        let codes: Vec<&str> = errors.iter().map(|status| status.code()).collect();
+        bail!("C2PA manifest failed validation: {}", codes.join(", "));
+    }
+    parse(&reader.json())
+}
+
+/// Claims of the active manifest of a store, as printed by `c2pa::Reader::json`
+fn parse(json: &str) -> Result<ImportedManifest> {
+    let store: ManifestStore = serde_json::from_str(json)?;
+    let label = store.active_manifest.ok_or(anyhow!("File has no active C2PA manifest"))?;
+    let manifest = store
+        .manifests
+        .get(&label)
+        .ok_or(anyhow!("Active manifest {} is missing from the store", label))?;
+
+    let parent = manifest
+        .ingredients
+        .iter()
+        .find(|ingredient| ingredient.relationship.as_deref() == Some("parentOf"));
+    let actions: Vec<Action> = manifest
+        .assertions
+        .iter()
+        .filter(|assertion| assertion.label == "c2pa.actions" || assertion.label.starts_with("c2pa.actions.v"))
+        .filter_map(|assertion| serde_json::from_value::<Actions>(assertion.data.clone()).ok())
+        .flat_map(|actions| actions.actions)
+        .collect();
+
+    Ok(ImportedManifest {
+        has_parent: parent.is_some(),
+        parent: parent.and_then(|p| p.instance_id.as_deref()).and_then(|id| id.parse().ok()),
+        generative: actions.iter().any(|a| {
+            matches!(
+                a.digital_source_type.as_deref(),
+                Some(TRAINED_ALGORITHMIC_MEDIA | COMPOSITE_WITH_TRAINED_ALGORITHMIC_MEDIA)
+            )
+        }),
+        actions: actions.into_iter().map(|a| a.action).collect(),
+        signer: manifest.signature_info.as_ref().and_then(|info| info.issuer.clone()),
+    })
+}
+
+/// Maps the manifest of a file onto the action registering it: `RegisterEdit` when it has a parent
+/// ingredient, `RegisterImage` otherwise. `original` overrides the parent hash, and an original needs its
+/// `owner_pk`: the manifest only names the issuer of its certificate.
+/// The canonical hash is only set when the guest can prove it from the file, passed as private input.
+pub fn to_action(
+    manifest: &ImportedManifest,
+    (image_hash, canonical_hash): (ContentHash, Option<ContentHash>),
+    perceptual_hash: u64,
+    signature: String,
+    owner_pk: Option<OwnerKeys>,
+    original: Option<ContentHash>,
+) -> Result<ImageAction> {
+    if manifest.has_parent || original.is_some() {
+        let original_image_hash = original.or(manifest.parent).ok_or(anyhow!(
+            "The parent ingredient has no on-chain hash as instance_id, pass it with --original"
+        ))?;
+        return Ok(ImageAction::RegisterEdit {
+            original_image_hash,
+            edited_image_hash: image_hash,
+            edited_canonical_hash: canonical_hash,
+            original_edit_signature: signature.parse().map_err(|e: String| anyhow!(e))?,
+            generative: manifest.generative,
+        });
+    }
+    if manifest.generative {
+        bail!("The manifest declares AI-generated content, register it with register-generated-image");
+    }
+    let owner_pk = owner_pk.ok_or(anyhow!("Pass the owner of the original with --owner-pk"))?;
+    Ok(ImageAction::RegisterImage {
+        image_hash,
+        canonical_hash,
+        perceptual_hash: Some(perceptual_hash),
+        image_signature: signature,
+        owner_pk,
+    })
+}
+
+/// C2PA manifest definition of a registered image, as consumed by `c2patool` to sign and embed it.
+/// The parent is the only ingredient, the whole lineage goes in the `org.sthyle.lineage` assertion.
+pub fn export(state: &ImageState, image_hash: ContentHash) -> Result<Value> {
+    let lineage = state.lineage(image_hash);
+    let Some(&key) = lineage.first() else {
+        bail!("Image {} is not registered", image_hash);
+    };
+    let metadata = &state.hash_map[&key];
+
+    let ingredients: Vec<Value> = metadata
+        .previous_image_hash
+        .iter()
+        .map(|parent| {
+            json!({
+                "title": parent.to_string(),
+                "instance_id": parent.to_string(),
+                "relationship": "parentOf",
+            })
+        })
+        .collect();
+    let entries: Vec<Value> = lineage
+        .iter()
+        .filter_map(|hash| state.hash_map.get(hash).map(|m| (hash, m)))
+        .map(|(hash, m)| {
+            json!({
+                "image_hash": hash.to_string(),
+                "previous_image_hash": m.previous_image_hash.map(|h| h.to_string()),
+                "provenance": m.provenance,
+                "registered_at": m.registered_at,
+                "owner_pk": m.owner_pk.to_string(),
+            })
+        })
+        .collect();
+
+    Ok(json!({
+        "claim_generator": concat!("sthyle/", env!("CARGO_PKG_VERSION")),
+        "title": key.to_string(),
+        "instance_id": key.to_string(),
+        "ingredients": ingredients,
+        "assertions": [
+            { "label": "c2pa.actions", "data": { "actions": [action(metadata)] } },
+            { "label": LINEAGE_ASSERTION, "data": { "lineage": entries } },
+        ],
+    }))
+}
+
+/// C2PA action describing how the image was registered
+fn action(metadata: &ImageMetadata) -> Value {
+    let name = match (&metadata.provenance, &metadata.transformation) {
+        (ProvenanceClass::Captured | ProvenanceClass::Declared | ProvenanceClass::AiGenerated { .. }, _) => {
+            "c2pa.created"
+        }
+        (_, Some(Transformation::Crop { .. })) => "c2pa.cropped",
+        (_, Some(Transformation::Downscale { .. })) => "c2pa.resized",
+        (_, Some(Transformation::Grayscale | Transformation::BrightnessContrast { .. })) => "c2pa.color_adjustments",
+        (_, Some(Transformation::JpegReencode { .. })) => "c2pa.transcoded",
+        _ => "c2pa.edited",
+    };
+    let source_type = match &metadata.provenance {
+        ProvenanceClass::Captured => Some(DIGITAL_CAPTURE),
+        ProvenanceClass::AiGenerated { .. } => Some(TRAINED_ALGORITHMIC_MEDIA),
+        ProvenanceClass::AiAssistedEdit => Some(COMPOSITE_WITH_TRAINED_ALGORITHMIC_MEDIA),
+        _ => None,
+    };
+    let mut action = json!({ "action": name });
+    if let Some(source_type) = source_type {
+        action["digitalSourceType"] = json!(source_type);
+    }
+    if let Some(transformation) = &metadata.transformation {
+        action["parameters"] = json!({ "transformation": transformation });
+    }
+    action
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use contract::hash::HashAlgorithm;
+
+    use super::*;
+
+    fn metadata(previous_image_hash: Option<ContentHash>, provenance: ProvenanceClass) -> ImageMetadata {
+        ImageMetadata {
+            previous_image_hash,
+            owner_pk: OwnerKeys::single("owner".to_string()),
+            publishers: HashMap::new(),
+            is_root: previous_image_hash.is_none(),
+            transformation: None,
+            perceptual_hash: None,
+            registered_at: 1,
+            signed_by: vec![],
+            capture: None,
+            provenance,
+        }
+    }
+
+    /// Store as `c2pa::Reader::json` prints it once c2patool signed the exported definition
+    fn signed(definition: Value) -> String {
+        json!({
+            "active_manifest": "urn:uuid:1",
+            "manifests": { "urn:uuid:1": definition },
+        })
+        .to_string()
+    }
+
+    fn state() -> (ImageState, ContentHash, ContentHash) {
+        let original = ContentHash::compute(HashAlgorithm::Sha256, b"original");
+        let edit = ContentHash::compute(HashAlgorithm::Sha256, b"edit");
+        let mut state = ImageState::default();
+        state.hash_map.insert(original, metadata(None, ProvenanceClass::Declared));
+        state.hash_map.insert(edit, metadata(Some(original), ProvenanceClass::AiAssistedEdit));
+        (state, original, edit)
+    }
+
+    #[test]
+    fn edit_round_trips_to_register_edit() {
+        let (state, original, edit) = state();
+        let manifest = parse(&signed(export(&state, edit).unwrap())).unwrap();
+        assert!(manifest.has_parent);
+        assert_eq!(manifest.parent, Some(original));
+        assert!(manifest.generative);
+        assert_eq!(manifest.actions, vec!["c2pa.edited".to_string()]);
+
+        match to_action(&manifest, (edit, None), 0, "signature".to_string(), None, None).unwrap() {
+            ImageAction::RegisterEdit {
+                original_image_hash,
+                edited_image_hash,
+                generative,
+                ..
+            } => {
+                assert_eq!(original_image_hash, original);
+                assert_eq!(edited_image_hash, edit);
+                assert!(generative);
+            }
+            other => panic!("Expected RegisterEdit, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn original_round_trips_to_register_image_with_its_owner() {
+        let (state, original, _) = state();
+        let manifest = parse(&signed(export(&state, original).unwrap())).unwrap();
+        assert!(!manifest.has_parent);
+        assert!(!manifest.generative);
+        assert_eq!(manifest.actions, vec!["c2pa.created".to_string()]);
+
+        assert!(to_action(&manifest, (original, None), 0, "signature".to_string(), None, None).is_err());
+        let owner = OwnerKeys::single("owner".to_string());
+        match to_action(&manifest, (original, None), 7, "signature".to_string(), Some(owner.clone()), None).unwrap() {
+            ImageAction::RegisterImage {
+                image_hash,
+                owner_pk,
+                perceptual_hash,
+                ..
+            } => {
+                assert_eq!(image_hash, original);
+                assert_eq!(owner_pk, owner);
+                assert_eq!(perceptual_hash, Some(7));
+            }
+            other => panic!("Expected RegisterImage, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn issuer_does_not_own_the_original() {
+        let manifest = parse(&signed(json!({
+            "assertions": [],
+            "signature_info": { "issuer": "Example CA" },
+        })))
+        .unwrap();
+        assert_eq!(manifest.signer.as_deref(), Some("Example CA"));
+        let image_hash = ContentHash::compute(HashAlgorithm::Sha256, b"image");
+        assert!(to_action(&manifest, (image_hash, None), 0, "signature".to_string(), None, None).is_err());
+    }
+}
@@ -0,0 +1,47 @@
+use std::path::Path;
+use anyhow::{anyhow, Result};
+use contract::hash::{self, ContentHash};
+use contract::transform::RawImage;
+
+/// Decodes an image file to RGBA8. Formats the guest understands go through the contract decoder, so
+/// that host and guest always agree on the canonical hash; anything else (WebP, GIF...) uses `image`.
+pub fn decode(bytes: &[u8]) -> Result<RawImage> {
+    if let Ok(image) = RawImage::decode(bytes) {
+        return Ok(image);
+    }
+    let image = image::load_from_memory(bytes)?.to_rgba8();
+    RawImage::new(image.width(), image.height(), image.into_raw()).map_err(|e| anyhow!(e))
+}
+
+/// File hash and canonical pixel hash of an image file, both tagged with their algorithm
+pub fn hashes(bytes: &[u8]) -> Result<(ContentHash, ContentHash)> {
+    Ok((hash::file_hash(bytes), hash::canonical_hash(&decode(bytes)?)))
+}
+
+/// Canonical hash of a file the guest can decode, and so recompute when the file is its private input
+pub fn provable_canonical_hash(bytes: &[u8]) -> Option<ContentHash> {
+    RawImage::decode(bytes).ok().map(|image| hash::canonical_hash(&image))
+}
+
+/// Canonical alias of an image file and the private input the guest proves it from: the file itself
+pub fn canonical_alias(path: Option<&Path>) -> Result<(Option<ContentHash>, Vec<u8>)> {
+    let Some(path) = path else {
+        return Ok((None, vec![]));
+    };
+    let bytes = std::fs::read(path)?;
+    let canonical_hash = provable_canonical_hash(&bytes)
+        .ok_or(anyhow!("{} is not a raw RGBA, PPM, PNG or JPEG file, its canonical hash can't be proven", path.display()))?;
+    Ok((Some(canonical_hash), bytes))
+}
+
+/// Writes an image in the format of the output extension. JPEG has no alpha channel, so it is dropped.
+pub fn save(image: &RawImage, path: &Path) -> Result<()> {
+    let buffer = image::RgbaImage::from_raw(image.width, image.height, image.pixels.clone())
+        .ok_or(anyhow!("Invalid image buffer"))?;
+    let image = image::DynamicImage::ImageRgba8(buffer);
+    match image::ImageFormat::from_path(path)? {
+        image::ImageFormat::Jpeg => image.to_rgb8().save(path)?,
+        _ => image.save(path)?,
+    }
+    Ok(())
+}
@@ -0,0 +1,495 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use anyhow::{Context, Result};
+use borsh::{BorshDeserialize, BorshSerialize};
+use contract::ImageAction;
+use sdk::{ContractInput, TxHash};
+use schemars::JsonSchema;
+use serde::Serialize;
+use tokio::sync::{Mutex, Semaphore};
+use crate::pipeline::{Executed, Pipeline, PipelineError, Stage};
+
+/// Progress of a proving job, as reported by `GET /jobs/{id}`
+#[derive(Serialize, JsonSchema, BorshSerialize, BorshDeserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum JobStage {
+    /// Blob sent, waiting for a free prover
+    Queued,
+    Proving,
+    ProofSent,
+    /// The node applied the action
+    Settled,
+    Failed,
+}
+
+#[derive(Serialize, JsonSchema, BorshSerialize, BorshDeserialize, Debug, Clone)]
+pub struct Job {
+    pub id: u64,
+    pub stage: JobStage,
+    #[schemars(with = "String")]
+    pub blob_tx_hash: TxHash,
+    pub program_output: String,
+    #[schemars(with = "Option<String>")]
+    pub proof_tx_hash: Option<TxHash>,
+    /// Failed stage and reason
+    pub error: Option<String>,
+}
+
+/// Journal entry of a job, rewritten at every stage change and deleted once the job settles or fails
+#[derive(BorshSerialize, BorshDeserialize)]
+struct JobRecord {
+    job: Job,
+    /// Inputs to prove the blob from, kept until the job settles or fails so that a resumed job can track its
+    /// action as pending again
+    inputs: Option<ContractInput>,
+}
+
+/// File journal of the outstanding jobs: one borsh encoded `JobRecord` per job, replaced atomically.
+/// Records hold the contract input of their action, private input included: the image files registered, originals
+/// of redacted edits among them. The journal directory must be kept as private as the host's keys.
+struct JobStore {
+    dir: PathBuf,
+}
+
+impl JobStore {
+    fn open(dir: PathBuf) -> Result<Self> {
+        std::fs::create_dir_all(&dir).with_context(|| format!("Could not create job directory {}", dir.display()))?;
+        Ok(JobStore { dir })
+    }
+
+    fn path(&self, id: u64) -> PathBuf {
+        self.dir.join(format!("{}.job", id))
+    }
+
+    fn save(&self, record: &JobRecord) -> Result<()> {
+        let path = self.path(record.job.id);
+        let tmp = path.with_extension("job.tmp");
+        std::fs::write(&tmp, borsh::to_vec(record)?)?;
+        std::fs::rename(&tmp, &path)?;
+        Ok(())
+    }
+
+    fn remove(&self, id: u64) -> Result<()> {
+        match std::fs::remove_file(self.path(id)) {
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            removed => Ok(removed?),
+        }
+    }
+
+    /// Every record of the journal. Files that can't be read are reported, their blobs won't be proven.
+    fn load(&self) -> Result<Vec<JobRecord>> {
+        let mut records = vec![];
+        for entry in std::fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().is_none_or(|ext| ext != "job") {
+                continue;
+            }
+            match std::fs::read(&path).map_err(anyhow::Error::from).and_then(|bytes| Ok(borsh::from_slice(&bytes)?)) {
+                Ok(record) => records.push(record),
+                Err(error) => eprintln!("❌ Job record {} can't be read: {:#}", path.display(), error),
+            }
+        }
+        Ok(records)
+    }
+}
+
+/// Proves the actions sent by the server in the background, at most `concurrency` at a time.
+/// Jobs are journaled to disk before their blob is sent, so that blobs sent before a restart still get proven.
+/// Settled and failed jobs are dropped from the journal and reported until the server restarts.
+#[derive(Clone)]
+pub struct JobQueue {
+    pipeline: Pipeline,
+    records: Arc<Mutex<HashMap<u64, JobRecord>>>,
+    store: Arc<JobStore>,
+    next_id: Arc<AtomicU64>,
+    provers: Arc<Semaphore>,
+}
+
+impl JobQueue {
+    /// Opens the journal in `dir` and resumes its outstanding jobs: their recorded inputs are executed again,
+    /// blobs not proven yet are proven and sent proofs are awaited. Jobs that can't be completed
+    /// anymore are reported and marked failed.
+    pub async fn open(pipeline: Pipeline, concurrency: usize, dir: PathBuf) -> Result<Self> {
+        let store = JobStore::open(dir)?;
+        let mut records = store.load()?;
+
+        let queue = JobQueue {
+            pipeline,
+            next_id: Arc::new(AtomicU64::new(records.iter().map(|r| r.job.id + 1).max().unwrap_or(1))),
+            records: Arc::new(Mutex::new(HashMap::new())),
+            store: Arc::new(store),
+            provers: Arc::new(Semaphore::new(concurrency.max(1))),
+        };
+
+        let mut outstanding = vec![];
+        // In the order the blobs were sent, for the pending state
+        records.sort_by_key(|record| record.job.id);
+        for record in records {
+            let (id, stage) = (record.job.id, record.job.stage);
+            let resumed = match (stage, &record.inputs) {
+                // Left by journals that kept finished jobs
+                (JobStage::Settled | JobStage::Failed, _) => {
+                    queue.forget(id);
+                    None
+                }
+                (_, Some(inputs)) => Some(queue.pipeline.reexecute(record.job.blob_tx_hash.clone(), inputs.clone())),
+                (_, None) => Some(Err(PipelineError::new(
+                    Stage::Execute,
+                    anyhow::anyhow!("the inputs of the blob were not recorded"),
+                ))),
+            };
+            queue.records.lock().await.insert(id, record);
+            match resumed {
+                Some(Ok(executed)) => {
+                    println!("♻️ Resuming job {} ({:?})", id, stage);
+                    queue.pipeline.track(&executed).await;
+                    let resume = match stage {
+                        JobStage::ProofSent => Resume::Settle(executed.blob_tx_hash),
+                        _ => Resume::Prove(Box::new(executed)),
+                    };
+                    outstanding.push((id, resume));
+                }
+                Some(Err(error)) => queue.fail(id, error).await,
+                None => {}
+            }
+        }
+        for (id, resume) in outstanding {
+            tokio::spawn(queue.clone().run(id, resume));
+        }
+        Ok(queue)
+    }
+
+    /// Executes `action`, journals its job and sends its blob, then leaves proving to a background worker.
+    /// Fails without creating a job when the action doesn't execute or the blob can't be sent.
+    pub async fn enqueue(&self, action: &ImageAction, private_input: Vec<u8>) -> Result<Job, PipelineError> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let mut journaled = None;
+        let sequenced = self
+            .pipeline
+            .sequence(action, private_input, |executed| {
+                let record = JobRecord {
+                    job: Job {
+                        id,
+                        stage: JobStage::Queued,
+                        blob_tx_hash: executed.blob_tx_hash.clone(),
+                        program_output: executed.program_output.clone(),
+                        proof_tx_hash: None,
+                        error: None,
+                    },
+                    inputs: Some(executed.inputs.clone()),
+                };
+                if let Err(error) = self.store.save(&record) {
+                    eprintln!("⚠️ Job {} is not journaled, it won't resume after a restart: {:#}", id, error);
+                }
+                journaled = Some(record);
+            })
+            .await;
+        let executed = match sequenced {
+            Ok(executed) => executed,
+            Err(error) => {
+                // The blob wasn't sent, there is nothing to resume
+                if journaled.is_some() {
+                    self.forget(id);
+                }
+                return Err(error);
+            }
+        };
+
+        let record = journaled.expect("the job is journaled before its blob is sent");
+        let job = record.job.clone();
+        self.records.lock().await.insert(id, record);
+        tokio::spawn(self.clone().run(id, Resume::Prove(Box::new(executed))));
+        Ok(job)
+    }
+
+    pub async fn get(&self, id: u64) -> Option<Job> {
+        self.records.lock().await.get(&id).map(|record| record.job.clone())
+    }
+
+    async fn run(self, id: u64, resume: Resume) {
+        let blob_tx_hash = match resume {
+            Resume::Prove(executed) => {
+                let blob_tx_hash = executed.blob_tx_hash.clone();
+                let _permit = self.provers.acquire().await.expect("the semaphore is never closed");
+                self.update(id, |record| record.job.stage = JobStage::Proving).await;
+                let sent = match self.pipeline.prove(*executed).await {
+                    Ok(proven) => self.pipeline.send_proof(proven).await,
+                    Err(error) => Err(error),
+                };
+                match sent {
+                    Ok(submitted) => {
+                        self.update(id, |record| {
+                            record.job.stage = JobStage::ProofSent;
+                            record.job.proof_tx_hash = Some(submitted.proof_tx_hash);
+                        })
+                        .await
+                    }
+                    Err(error) => return self.fail(id, error).await,
+                }
+                blob_tx_hash
+            }
+            Resume::Settle(blob_tx_hash) => blob_tx_hash,
+        };
+
+        match self.pipeline.wait_settled(&blob_tx_hash).await {
+            Ok(()) => self.finish(id, |job| job.stage = JobStage::Settled).await,
+            Err(error) => self.fail(id, error).await,
+        }
+    }
+
+    async fn fail(&self, id: u64, error: PipelineError) {
+        eprintln!("❌ Job {} failed: {}", id, error);
+        let blob_tx_hash = self.records.lock().await.get(&id).map(|record| record.job.blob_tx_hash.clone());
+        if let Some(blob_tx_hash) = blob_tx_hash {
+            self.pipeline.discard(&blob_tx_hash).await;
+        }
+        self.finish(id, |job| {
+            job.stage = JobStage::Failed;
+            job.error = Some(error.to_string());
+        })
+        .await;
+    }
+
+    /// Records the final stage of a job and deletes it from the journal, with the inputs it held
+    async fn finish(&self, id: u64, change: impl FnOnce(&mut Job)) {
+        if let Some(record) = self.records.lock().await.get_mut(&id) {
+            change(&mut record.job);
+            record.inputs = None;
+        }
+        self.forget(id);
+    }
+
+    fn forget(&self, id: u64) {
+        if let Err(error) = self.store.remove(id) {
+            eprintln!("⚠️ Could not delete job {} from the journal: {:#}", id, error);
+        }
+    }
+
+    async fn update(&self, id: u64, change: impl FnOnce(&mut JobRecord)) {
+        if let Some(record) = self.records.lock().await.get_mut(&id) {
+            change(record);
+            if let Err(error) = self.store.save(record) {
+                eprintln!("⚠️ Could not journal job {}: {:#}", id, error);
+            }
+        }
+    }
+}
+
+/// Where a job picks up in `JobQueue::run`
+enum Resume {
+    Prove(Box<Executed>),
+    Settle(TxHash),
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::Mutex as StdMutex;
+    use std::time::Duration;
+    use async_trait::async_trait;
+    use contract::hash;
+    use contract::ownership::OwnerKeys;
+    use contract::ImageState;
+    use sdk::{BlobTransaction, Digestable, Hashed, HyleContract, ProofData, ProofTransaction, StateDigest};
+    use crate::pipeline::{Node, Prover, RetryPolicy};
+    use super::*;
+
+    /// Node settling each proof as soon as the one it proves from settled, whatever order they arrive in
+    #[derive(Default)]
+    struct FakeNode {
+        settled: StdMutex<ImageState>,
+        proofs: StdMutex<Vec<ContractInput>>,
+    }
+
+    #[async_trait]
+    impl Node for FakeNode {
+        async fn contract_state(&self, _contract_name: &str) -> anyhow::Result<StateDigest> {
+            Ok(self.settled.lock().unwrap().as_digest())
+        }
+
+        async fn send_tx_blob(&self, tx: &BlobTransaction) -> anyhow::Result<TxHash> {
+            Ok(tx.hashed())
+        }
+
+        async fn send_tx_proof(&self, tx: &ProofTransaction) -> anyhow::Result<TxHash> {
+            let inputs: ContractInput = borsh::from_slice(&tx.proof.0)?;
+            let proof_tx_hash = TxHash(format!("proof-of-{}", inputs.tx_hash));
+            let mut proofs = self.proofs.lock().unwrap();
+            proofs.push(inputs);
+            let mut settled = self.settled.lock().unwrap();
+            while let Some(next) = proofs.iter().position(|inputs| inputs.state == settled.as_digest().0) {
+                settled.execute(&proofs.remove(next)).unwrap();
+            }
+            Ok(proof_tx_hash)
+        }
+    }
+
+    /// "Proves" by encoding the inputs after `delay`, recording how many proofs ran at once
+    #[derive(Default)]
+    struct FakeProver {
+        delay: Duration,
+        running: AtomicUsize,
+        max_running: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl Prover for FakeProver {
+        async fn prove(&self, inputs: ContractInput) -> anyhow::Result<ProofData> {
+            let running = self.running.fetch_add(1, Ordering::SeqCst) + 1;
+            self.max_running.fetch_max(running, Ordering::SeqCst);
+            tokio::time::sleep(self.delay).await;
+            self.running.fetch_sub(1, Ordering::SeqCst);
+            Ok(ProofData(borsh::to_vec(&inputs)?))
+        }
+    }
+
+    fn pipeline(node: &Arc<FakeNode>, prover: &Arc<FakeProver>) -> Pipeline {
+        let retry = RetryPolicy {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(5),
+        };
+        Pipeline::new(node.clone(), prover.clone(), "image_registry".into(), "none.image_registry".into(), retry)
+    }
+
+    fn register(file: &str) -> ImageAction {
+        ImageAction::RegisterImage {
+            image_hash: hash::file_hash(file.as_bytes()),
+            canonical_hash: None,
+            perceptual_hash: None,
+            image_signature: String::new(),
+            owner_pk: OwnerKeys::single("owner".into()),
+        }
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("jobs-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    /// Executes `action` on `state` as the server would have before journaling it as `id` at `stage`
+    fn journal(dir: &Path, pipeline: &Pipeline, id: u64, stage: JobStage, state: ImageState, action: &str) -> Executed {
+        let blob_tx = BlobTransaction::new(pipeline.identity.clone(), vec![register(action).as_blob("image_registry")]);
+        let executed = pipeline.execute(pipeline.prepare(state, &blob_tx, vec![]).unwrap()).unwrap();
+        let record = JobRecord {
+            job: Job {
+                id,
+                stage,
+                blob_tx_hash: executed.blob_tx_hash.clone(),
+                program_output: executed.program_output.clone(),
+                proof_tx_hash: None,
+                error: None,
+            },
+            inputs: Some(executed.inputs.clone()),
+        };
+        JobStore::open(dir.to_path_buf()).unwrap().save(&record).unwrap();
+        executed
+    }
+
+    /// Polls `id` until it settles or fails
+    async fn finished(queue: &JobQueue, id: u64) -> Job {
+        for _ in 0..1000 {
+            match queue.get(id).await {
+                Some(job) if matches!(job.stage, JobStage::Settled | JobStage::Failed) => return job,
+                _ => tokio::time::sleep(Duration::from_millis(2)).await,
+            }
+        }
+        panic!("job {} didn't finish", id)
+    }
+
+    fn journaled(dir: &Path) -> Vec<String> {
+        let mut files: Vec<String> =
+            std::fs::read_dir(dir).unwrap().map(|entry| entry.unwrap().file_name().into_string().unwrap()).collect();
+        files.sort();
+        files
+    }
+
+    #[tokio::test]
+    async fn reopening_resumes_every_unfinished_stage() {
+        let dir = temp_dir("resume");
+        let (node, prover) = (Arc::new(FakeNode::default()), Arc::new(FakeProver::default()));
+        let pipeline = pipeline(&node, &prover);
+
+        // Job 1's proof was sent and settled while the server was down, jobs 2 and 3 were waiting or proving
+        let first = journal(&dir, &pipeline, 1, JobStage::ProofSent, ImageState::default(), "a");
+        *node.settled.lock().unwrap() = first.state.clone();
+        let second = journal(&dir, &pipeline, 2, JobStage::Queued, first.state, "b");
+        journal(&dir, &pipeline, 3, JobStage::Proving, second.state, "c");
+        // Left finished by an older journal, and recorded without its inputs
+        journal(&dir, &pipeline, 4, JobStage::Settled, ImageState::default(), "d");
+        journal(&dir, &pipeline, 5, JobStage::Queued, ImageState::default(), "e");
+        let store = JobStore::open(dir.clone()).unwrap();
+        let mut record: JobRecord = borsh::from_slice(&std::fs::read(store.path(5)).unwrap()).unwrap();
+        record.inputs = None;
+        store.save(&record).unwrap();
+
+        let queue = JobQueue::open(pipeline, 1, dir.clone()).await.unwrap();
+        for id in 1..=3 {
+            assert_eq!(finished(&queue, id).await.stage, JobStage::Settled, "job {}", id);
+        }
+        assert_eq!(queue.get(4).await.unwrap().stage, JobStage::Settled);
+        let orphan = finished(&queue, 5).await;
+        assert_eq!(orphan.stage, JobStage::Failed);
+        assert!(orphan.error.unwrap().contains("not recorded"));
+        assert_eq!(node.settled.lock().unwrap().height, 3);
+        assert_eq!(prover.max_running.load(Ordering::SeqCst), 1);
+        assert!(journaled(&dir).is_empty());
+
+        // Ids go on after the highest journaled one
+        assert_eq!(queue.enqueue(&register("f"), vec![]).await.unwrap().id, 6);
+        assert_eq!(finished(&queue, 6).await.stage, JobStage::Settled);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn half_written_records_are_skipped() {
+        let dir = temp_dir("half-written");
+        let (node, prover) = (Arc::new(FakeNode::default()), Arc::new(FakeProver::default()));
+        let pipeline = pipeline(&node, &prover);
+        let executed = journal(&dir, &pipeline, 1, JobStage::Queued, ImageState::default(), "a");
+        // A crash in the middle of a save leaves the tmp file, the record it replaces being intact
+        let bytes = std::fs::read(dir.join("1.job")).unwrap();
+        std::fs::write(dir.join("1.job.tmp"), &bytes[..bytes.len() / 2]).unwrap();
+        std::fs::write(dir.join("2.job.tmp"), &bytes[..bytes.len() / 2]).unwrap();
+        // A record that can't be decoded is reported, and not resumed
+        std::fs::write(dir.join("3.job"), &bytes[..bytes.len() - 1]).unwrap();
+
+        let queue = JobQueue::open(pipeline, 1, dir.clone()).await.unwrap();
+        let job = finished(&queue, 1).await;
+        assert_eq!((job.stage, job.blob_tx_hash), (JobStage::Settled, executed.blob_tx_hash));
+        assert!(queue.get(2).await.is_none());
+        assert!(queue.get(3).await.is_none());
+        // Job 1's next save replaced its tmp file
+        assert_eq!(journaled(&dir), ["2.job.tmp", "3.job"]);
+
+        assert_eq!(queue.enqueue(&register("b"), vec![]).await.unwrap().id, 2);
+        assert_eq!(finished(&queue, 2).await.stage, JobStage::Settled);
+        assert_eq!(journaled(&dir), ["3.job"]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn provers_run_at_most_the_concurrency() {
+        let dir = temp_dir("provers");
+        let node = Arc::new(FakeNode::default());
+        let prover = Arc::new(FakeProver {
+            delay: Duration::from_millis(20),
+            ..FakeProver::default()
+        });
+        let queue = JobQueue::open(pipeline(&node, &prover), 2, dir.clone()).await.unwrap();
+        let mut ids = vec![];
+        for i in 0..6 {
+            ids.push(queue.enqueue(&register(&format!("file-{}", i)), vec![]).await.unwrap().id);
+        }
+        for id in ids {
+            assert_eq!(finished(&queue, id).await.stage, JobStage::Settled);
+        }
+        assert_eq!(prover.max_running.load(Ordering::SeqCst), 2);
+        assert_eq!(node.settled.lock().unwrap().height, 6);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,19 +1,49 @@
 use std::collections::HashMap;
-use anyhow::Result;
+use anyhow::{Context, Result};
 use clap::{Parser, Subcommand};
 use client_sdk::helpers::risc0::Risc0Prover;
+use client_sdk::rest_client::NodeApiHttpClient;
 use contract::ImageState;
 use contract::ImageAction;
+use contract::attestation::{AttesterRole, Claim};
+use contract::device::{CaptureAttestation, CaptureMetadata, TrustedDevice};
+use contract::governance::Revocation;
+use contract::hash::ContentHash;
+use contract::ownership::{MultiSignature, OwnerKeys, PartialSignature};
+use contract::provenance::{AiDisclosure, GeneratorModel, ProvenanceClass};
+use contract::transform::{Redaction, Transformation, TransformWitness};
+use ed25519_dalek::{Signer, SigningKey};
+use state_cache::StateCache;
+use jobs::JobQueue;
+use pipeline::{Pipeline, RetryPolicy};
+use similarity::SimilarityIndex;
+use std::path::PathBuf;
 use sdk::api::APIRegisterContract;
-use sdk::BlobTransaction;
-use sdk::ProofTransaction;
-use sdk::{ContractInput, Digestable, HyleContract};
+use sdk::Digestable;
 use warp::{Filter, Rejection, Reply};
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use std::time::Duration;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use warp::reject::Reject;
-use std::fmt;
+use api::{ApiError, ErrorKind};
+use audit::AuditLog;
+use auth::{Auth, Caller, Scope};
+use server::ServerOptions;
+
+mod api;
+mod audit;
+mod auth;
+mod content_credentials;
+mod files;
+mod jobs;
+mod openapi;
+mod pending;
+mod pipeline;
+mod server;
+mod similarity;
+mod stamp;
+mod state_cache;
+mod watermark;
 
 // These constants represent the RISC-V ELF and the image ID generated by risc0-build.
 // The ELF is used for proving and the ID is used for verification.
@@ -31,79 +61,362 @@ struct Cli {
 
     #[arg(long, default_value = "image_state")]
     pub contract_name: String,
-}
-
-#[derive(Debug)]
-struct CustomRejection(anyhow::Error);
 
-impl Reject for CustomRejection {}
+    /// Attempts of each node request before an action is given up
+    #[arg(long, default_value_t = 5)]
+    pub max_attempts: u32,
+}
 
-impl fmt::Display for CustomRejection {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.0)
-    }
+#[derive(Deserialize, JsonSchema)]
+struct SubmitRequest {
+    action: ImageAction,
+    /// Witness of actions proven over private data, e.g. the borsh encoded `TransformWitness`
+    #[serde(default)]
+    private_input: Vec<u8>,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, JsonSchema)]
 struct RegisterImageRequest {
-    image_hash: String,
+    image_hash: ContentHash,
+    /// Needs `file`, from which the guest recomputes it
+    #[serde(default)]
+    canonical_hash: Option<ContentHash>,
+    /// Image file bytes, passed as private input
+    #[serde(default)]
+    file: Vec<u8>,
+    #[serde(default)]
+    perceptual_hash: Option<u64>,
     image_signature: String,
-    owner_pk: String,
+    owner_pk: OwnerKeys,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, JsonSchema)]
 struct VerifyOriginalImageRequest {
-    image_hash: String,
+    image_hash: ContentHash,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, JsonSchema)]
 struct RegisterEditRequest {
-    original_image_hash: String,
-    edited_image_hash: String,
-    original_edit_signature: String,
+    original_image_hash: ContentHash,
+    edited_image_hash: ContentHash,
+    /// Needs `edited_file`, from which the guest recomputes it
+    #[serde(default)]
+    edited_canonical_hash: Option<ContentHash>,
+    /// Edited file bytes, passed as private input
+    #[serde(default)]
+    edited_file: Vec<u8>,
+    original_edit_signature: MultiSignature,
+    #[serde(default)]
+    generative: bool,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, JsonSchema)]
 struct AddPublisherRequest {
-    original_image_hash: String,
-    original_image_signature: String,
+    original_image_hash: ContentHash,
+    original_image_signature: MultiSignature,
     publisher_pk: String,
 }
 
 /// Request payload for verifying an edited image
-#[derive(Deserialize)]
+#[derive(Deserialize, JsonSchema)]
 struct VerifyEditImageRequest {
-    edit_image_hash: String,
+    edit_image_hash: ContentHash,
+}
+
+/// Request payload for the attestations of an image and its lineage
+#[derive(Deserialize, JsonSchema)]
+struct AttestationsRequest {
+    image_hash: ContentHash,
+}
+
+/// Query of the near-duplicate lookup, the image file itself being the request body
+#[derive(Deserialize)]
+struct FindSimilarQuery {
+    max_distance: Option<u32>,
 }
 
 /// Response payload for verifying an edited image
-#[derive(Serialize)]
+#[derive(Serialize, JsonSchema)]
 struct VerifyEditImageResponse {
-    original_image: Option<String>,
+    original_image: Option<ContentHash>,
     is_edited: bool,
+    /// Regions hidden by a proven redaction of this image
+    redactions: Vec<Redaction>,
+    /// Signed by a key after it was declared compromised, or through a grant such a key gave
+    flagged: bool,
+    /// Set when the image, or an image it was edited from, is AI-generated or an AI-assisted edit
+    ai_disclosure: Option<AiDisclosure>,
+    /// Set when the admin keys revoked the image or an image it was edited from
+    revocation: Option<Revocation>,
+}
+
+#[derive(Serialize, JsonSchema)]
+struct IsOriginalResponse {
+    is_original: bool,
+    /// Signed by a capture device that is still trusted, and not revoked
+    camera_attested: bool,
+    capture: Option<CaptureAttestation>,
+    ai_disclosure: Option<AiDisclosure>,
+    revocation: Option<Revocation>,
 }
 
 #[derive(Subcommand)]
 enum Commands {
-    Server {},
-    RegisterContract {},
+    Server {
+        /// Proofs generated at the same time by the background workers
+        #[arg(long, default_value_t = 1)]
+        provers: usize,
+        /// Journal of the proving jobs, resumed on startup
+        #[arg(long, default_value = "jobs")]
+        jobs_dir: PathBuf,
+        /// Delay between two polls of the settled state by the state cache
+        #[arg(long, default_value_t = 1000)]
+        index_interval_ms: u64,
+        /// API keys and signing keys allowed to call the server. Without it every request is accepted, which is
+        /// only allowed on a loopback address or with `--no-auth`.
+        #[arg(long)]
+        auth_file: Option<PathBuf>,
+        /// Accepts every request without `--auth-file`, whatever the address listened on
+        #[arg(long, conflicts_with = "auth_file")]
+        no_auth: bool,
+        /// Log of who submitted which action, appended to
+        #[arg(long, default_value = "audit.log")]
+        audit_log: PathBuf,
+        #[command(flatten)]
+        options: ServerOptions,
+    },
+    /// Prints the OpenAPI document of the server routes
+    Openapi {
+        /// Documents a server without `--auth-file`, leaving out the security schemes
+        #[arg(long)]
+        no_auth: bool,
+    },
+    RegisterContract {
+        /// Keys managing the contract registries, `pk` or `<threshold>/<pk>,<pk>,...`
+        #[arg(long)]
+        admin: Option<OwnerKeys>,
+    },
+    /// Registers the current guest with the state of the deployed contract, migrated to the current version
+    UpgradeContract {
+        /// New contract to register, which must differ from `--contract-name`
+        #[arg(long)]
+        to: String,
+    },
     RegisterImage {
-        image_hash: String,
+        image_hash: ContentHash,
         image_signature: String,
-        owner_pk: String,
+        owner_pk: OwnerKeys,
+        /// Image file, whose canonical pixel hash the guest proves and registers as an alias of `image_hash`
+        #[arg(long)]
+        file: Option<PathBuf>,
+        /// Perceptual hash (dHash) stored for near-duplicate lookups
+        #[arg(long)]
+        perceptual_hash: Option<u64>,
+    },
+    /// Registers a file under both its file hash and its canonical pixel hash
+    RegisterImageFile {
+        file: PathBuf,
+        image_signature: String,
+        owner_pk: OwnerKeys,
+    },
+    /// Registers a JPEG/PNG from its embedded C2PA manifest: as an edit of its parent ingredient, or as an original
+    ImportC2pa {
+        file: PathBuf,
+        /// Owner signature for an original, owner or publisher signature for an edit
+        signature: String,
+        /// Owner of an original, which the manifest doesn't name: it only carries its certificate issuer
+        #[arg(long)]
+        owner_pk: Option<OwnerKeys>,
+        /// On-chain hash of the parent ingredient, when the manifest doesn't carry it
+        #[arg(long)]
+        original: Option<ContentHash>,
+    },
+    /// Prints a C2PA manifest definition of the image and its on-chain lineage, to be signed with c2patool
+    ExportC2pa {
+        image_hash: ContentHash,
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
+    /// Writes where to verify a registered JPEG/PNG into its XMP metadata, keeping its pixels untouched
+    Stamp {
+        file: PathBuf,
+        /// Transaction that registered the image
+        #[arg(long)]
+        tx_hash: Option<String>,
+        /// Defaults to stamping the file in place
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
+    /// Reads the provenance stamp of a file and verifies it against the contract it points to
+    Inspect { file: PathBuf },
+    /// Embeds or reads the invisible watermark linking an image to its registered original
+    Watermark {
+        #[command(subcommand)]
+        command: WatermarkCommands,
+    },
+    /// Prints the file hash and the canonical pixel hash of a file
+    HashFile { file: PathBuf },
+    /// Lists registered originals that look like the given file
+    FindSimilar {
+        file: PathBuf,
+        #[arg(long, default_value_t = similarity::DEFAULT_MAX_DISTANCE)]
+        max_distance: u32,
+    },
+    /// Fetches and decodes the whole settled state, unlike the server's cached `/verify_original_image`
+    VerifyOriginalImage { image_hash: ContentHash },
+    /// Registers an original with the signature of a trusted capture device
+    RegisterCapturedImage {
+        image_hash: ContentHash,
+        owner_pk: OwnerKeys,
+        device_pk: String,
+        device_signature: String,
+        /// Unix timestamp of the capture, in seconds
+        #[arg(long)]
+        captured_at: u64,
+        #[arg(long)]
+        location: Option<String>,
+        /// Image file, whose canonical pixel hash the guest proves and registers as an alias, along with its
+        /// perceptual hash
+        #[arg(long)]
+        file: Option<PathBuf>,
+    },
+    /// Registers the output of an AI generator, signed by its registered model key
+    RegisterGeneratedImage {
+        image_hash: ContentHash,
+        owner_pk: OwnerKeys,
+        model_pk: String,
+        model_signature: String,
+        /// Image file, whose canonical pixel hash the guest proves and registers as an alias, along with its
+        /// perceptual hash
+        #[arg(long)]
+        file: Option<PathBuf>,
+    },
+    /// Registers a generator model key, signed by the contract admin
+    RegisterGenerator {
+        model_pk: String,
+        vendor: String,
+        model: String,
+        admin_signature: MultiSignature,
+    },
+    /// Removes a generator model key, signed by the contract admin
+    RemoveGenerator {
+        model_pk: String,
+        admin_signature: MultiSignature,
+    },
+    /// Trusts a capture-device attestation key, signed by the contract admin
+    TrustDevice {
+        device_pk: String,
+        manufacturer: String,
+        model: String,
+        admin_signature: MultiSignature,
+    },
+    /// Removes a capture-device attestation key from the registry, signed by the contract admin
+    DistrustDevice {
+        device_pk: String,
+        admin_signature: MultiSignature,
     },
-    VerifyOriginalImage { image_hash: String },
+    /// Grants an attester role, signed by the contract admin
+    AddAttester {
+        attester_pk: String,
+        /// fact-checker | editor
+        role: AttesterRole,
+        admin_signature: MultiSignature,
+    },
+    /// Revokes an attester, signed by the contract admin
+    RemoveAttester {
+        attester_pk: String,
+        admin_signature: MultiSignature,
+    },
+    /// Attaches a signed claim to an image
+    Attest {
+        image_hash: ContentHash,
+        /// verified-location | misleading-context | ai-generated | any other text
+        claim: Claim,
+        attester_pk: String,
+        signature: String,
+    },
+    /// Lists the attestations of an image and of the images it was edited from
+    Attestations { image_hash: ContentHash },
     RegisterEdit {
-        original_image_hash: String,
-        edited_image_hash: String,
-        original_edit_signature: String,
+        original_image_hash: ContentHash,
+        edited_image_hash: ContentHash,
+        original_edit_signature: MultiSignature,
+        /// Edited file, whose canonical pixel hash the guest proves and registers as an alias of `edited_image_hash`
+        #[arg(long)]
+        edited_file: Option<PathBuf>,
+        /// The edit was made with a generative tool
+        #[arg(long)]
+        generative: bool,
     },
     AddPublisher {
-        original_image_hash: String,
-        original_image_signature: String,
+        original_image_hash: ContentHash,
+        original_image_signature: MultiSignature,
         publisher_pk: String,
     },
-    VerifyEditImage { edit_image_hash: String },
+    /// Fetches and decodes the whole settled state, unlike the server's cached `/verify_edit_image`
+    VerifyEditImage { edit_image_hash: ContentHash },
+    /// Produces the partial signature of one co-owner, to be combined offline
+    Sign { pk: String, message: String },
+    /// Prints the `Authorization` header of a server request signed with an Ed25519 secret key
+    SignRequest {
+        /// Hex encoded 32-byte secret key, whose public key is in the server's `--auth-file`
+        secret_key: String,
+        method: String,
+        /// Path with its query, e.g. `/submit` or `/owners/pk/images?limit=10`
+        path: String,
+        /// File sent as the request body
+        #[arg(long)]
+        body: Option<PathBuf>,
+    },
+    /// Merges partial signatures into the single signature argument of a submission
+    CombineSignatures { partials: Vec<MultiSignature> },
+    /// Stops every non-admin action, signed by the contract admin
+    Pause { admin_signature: MultiSignature },
+    /// Resumes the actions stopped by `pause`, signed by the contract admin
+    Unpause { admin_signature: MultiSignature },
+    /// Hands the contract admin over to a new key set, signed by the current admin
+    RotateAdmin {
+        new_admin: OwnerKeys,
+        admin_signature: MultiSignature,
+    },
+    /// Revokes an abusive registration with a public reason, signed by the contract admin
+    ForceRevoke {
+        image_hash: ContentHash,
+        reason: String,
+        admin_signature: MultiSignature,
+    },
+    /// Prints the admin keys, the pause switch and the admin actions log
+    AdminLog {},
+    /// Pre-registers the keys allowed to rotate `owner_pk` if it is lost. Replacing them is also signed by them.
+    SetRecoveryKeys {
+        owner_pk: String,
+        recovery: OwnerKeys,
+        signature: MultiSignature,
+    },
+    /// Moves every image and publisher grant of `old_pk` to `new_pk`
+    RotateKey {
+        old_pk: String,
+        new_pk: String,
+        signature: MultiSignature,
+        /// Contract height from which edits signed by `old_pk`, and the publisher grants it gave, are flagged
+        #[arg(long)]
+        compromised_since: Option<u64>,
+    },
+    /// Registers an edit by proving it is an allowed transformation of the original file
+    RegisterProvenEdit {
+        original_file: PathBuf,
+        edited_file: PathBuf,
+        /// crop=x,y,w,h | downscale=w,h | grayscale | brightness-contrast=b,c | jpeg=max_error
+        transformation: Transformation,
+    },
+}
+
+#[derive(Subcommand)]
+enum WatermarkCommands {
+    /// Writes a copy of a registered image marked with the ID of its original
+    Embed { file: PathBuf, output: PathBuf },
+    /// Reads the mark of a suspect image, even stripped of metadata or recompressed
+    Identify { file: PathBuf },
 }
 
 #[tokio::main]
@@ -112,322 +425,54 @@ async fn main() -> Result<()> {
     let host = cli.host.clone();
 
     // Client to send requests to the node
-    let client = client_sdk::rest_client::NodeApiHttpClient::new(host.clone())?;
+    let client = Arc::new(NodeApiHttpClient::new(host.clone())?);
     let contract_name = &cli.contract_name;
 
-    // Will be used to generate zkProof of the execution.
-    let prover = Risc0Prover::new(GUEST_ELF);
-
-    // This dummy example doesn't use identities. But there are required fields & validation.
-    let identity = format!("none.{}", contract_name);
+    // Submits actions: executes them, sends the blob, proves the state transition with the guest and sends the proof
+    let pipeline = Pipeline::new(
+        client.clone(),
+        Arc::new(Risc0Prover::new(GUEST_ELF)),
+        contract_name.clone(),
+        // This dummy example doesn't use identities. But there are required fields & validation.
+        format!("none.{}", contract_name),
+        RetryPolicy {
+            max_attempts: cli.max_attempts,
+            ..RetryPolicy::default()
+        },
+    );
 
     match cli.command {
-        Commands::Server {} => {
-            // Client to send requests to the node
-            let client = Arc::new(client_sdk::rest_client::NodeApiHttpClient::new(host)?);
-            let contract_name = cli.contract_name.clone();
-
-            // Will be used to generate zkProof of the execution.
-            let prover = Arc::new(Risc0Prover::new(GUEST_ELF));
-
-            // This dummy example doesn't use identities. But there are required fields & validation.
-            let identity = format!("none.{}", contract_name);
-
-            let register_image_route = warp::post()
-                .and(warp::path("register_image"))
-                .and(warp::body::json())
-                .and_then({
-                    let client = Arc::clone(&client);
-                    let contract_name = contract_name.clone();
-                    let prover = Arc::clone(&prover);
-                    let identity = identity.clone();
-                    move |req: RegisterImageRequest| {
-                        let client = Arc::clone(&client);
-                        let contract_name = contract_name.clone();
-                        let prover = Arc::clone(&prover);
-                        let identity = identity.clone();
-                        async move {
-                            let mut initial_state: ImageState = client
-                                .get_contract(&contract_name.clone().into())
-                                .await
-                                .unwrap()
-                                .state
-                                .into();
-
-                            let action = ImageAction::RegisterImage {
-                                image_hash: req.image_hash,
-                                image_signature: req.image_signature,
-                                owner_pk: req.owner_pk,
-                            };
-                            let blobs = vec![action.as_blob(&contract_name)];
-                            let blob_tx = BlobTransaction::new(identity.clone(), blobs.clone());
-
-                            let blob_tx_hash = client.send_tx_blob(&blob_tx).await.unwrap();
-                            println!("✅ Blob tx sent. Tx hash: {}", blob_tx_hash);
-
-                            let inputs = ContractInput {
-                                state: initial_state.as_bytes().unwrap(),
-                                identity: identity.clone().into(),
-                                tx_hash: blob_tx_hash,
-                                private_input: vec![],
-                                tx_ctx: None,
-                                blobs: blobs.clone(),
-                                index: sdk::BlobIndex(0),
-                            };
-
-                            let (program_outputs, _, _) = initial_state.execute(&inputs).unwrap();
-                            println!("🚀 Executed: {}", program_outputs);
-
-                            let proof = prover.prove(inputs).await.unwrap();
-
-                            let proof_tx = ProofTransaction {
-                                proof,
-                                contract_name: contract_name.clone().into(),
-                            };
-
-                            let proof_tx_hash = client.send_tx_proof(&proof_tx).await.unwrap();
-                            println!("✅ Proof tx sent. Tx hash: {}", proof_tx_hash);
-
-                            Ok::<_, warp::Rejection>(warp::reply::json(&program_outputs))
-                        }
-                    }
-                });
-
-            #[derive(Serialize)]
-            struct IsOriginalResponse {
-                is_original: bool,
-            }
-
-            let verify_original_image_route = warp::post()
-                .and(warp::path("verify_original_image"))
-                .and(warp::body::json())
-                .and_then({
-                    let client = Arc::clone(&client);
-                    let contract_name = contract_name.clone();
-                    move |req: VerifyOriginalImageRequest| {
-                        let client = Arc::clone(&client);
-                        let contract_name = contract_name.clone();
-                        async move {
-                            let initial_state: ImageState = client
-                                .get_contract(&contract_name.clone().into())
-                                .await
-                                .unwrap()
-                                .state
-                                .into();
-                            let is_original_result = initial_state.is_original_image(req.image_hash);
-                            match is_original_result {
-                                Ok(is_original) => {
-                                    println!("✅ Is original Image ?: {:?}", is_original);
-                                    let response = IsOriginalResponse { is_original };
-                                    Ok::<_, warp::Rejection>(warp::reply::json(&response))
-                                }
-                                Err(e) => {
-                                    eprintln!("Failed to verify original image: {:?}", e);
-                                    Err(warp::reject::custom(CustomRejection(anyhow::anyhow!(e))))
-                                }
-                            }
-                        }
-                    }
-                });
-
-            let verify_image_edited_route = warp::post()
-                .and(warp::path("verify_edit_image"))
-                .and(warp::body::json())
-                .and_then({
-                    let client = Arc::clone(&client);
-                    let contract_name = contract_name.clone();
-                    move |req: VerifyEditImageRequest| {
-                        let client = Arc::clone(&client);
-                        let contract_name = contract_name.clone();
-                        async move {
-                            // Fetch contract state
-                            let initial_state: ImageState = match client.get_contract(&contract_name.clone().into()).await {
-                                Ok(contract) => contract.state.into(),
-                                Err(err) => {
-                                    eprintln!("Failed to fetch contract state: {:?}", err);
-                                    return Err(warp::reject::custom(CustomRejection(anyhow::anyhow!(err))));
-                                }
-                            };
-
-                            // Check if the image is an edited image
-                            match initial_state.is_edit_image(req.edit_image_hash.clone()) {
-                                Ok(is_edited) => {
-                                    println!("Is edited Image?: {:?}", is_edited);
-
-                                    if is_edited {
-                                        // Try to find the original image
-                                        match initial_state.find_original_image(req.edit_image_hash.clone()) {
-                                            Ok(original_image) => {
-                                                println!("Found Original Image: {}", original_image);
-                                                let response = VerifyEditImageResponse {
-                                                    original_image: Some(original_image),
-                                                    is_edited: true,
-                                                };
-                                                return Ok::<_, warp::Rejection>(warp::reply::json(&response));
-                                            }
-                                            Err(err) => {
-                                                eprintln!("Error finding original image: {:?}", err);
-                                                let response = VerifyEditImageResponse {
-                                                    original_image: None,
-                                                    is_edited: true,
-                                                };
-                                                return Ok::<_, warp::Rejection>(warp::reply::json(&response));
-                                            }
-                                        }
-                                    } else {
-                                        let response = VerifyEditImageResponse {
-                                            original_image: None,
-                                            is_edited: false,
-                                        };
-                                        return Ok::<_, warp::Rejection>(warp::reply::json(&response));
-                                    }
-                                }
-                                Err(e) => {
-                                    eprintln!("Error checking if image is edited: {:?}", e);
-                                    Err(warp::reject::custom(CustomRejection(anyhow::anyhow!(e))))
-                                }
-                            }
-                        }
-                    }
-                });
-
-            let register_edit_route = warp::post()
-                .and(warp::path("register_edit"))
-                .and(warp::body::json())
-                .and_then({
-                    let client = Arc::clone(&client);
-                    let contract_name = contract_name.clone();
-                    let prover = Arc::clone(&prover);
-                    let identity = identity.clone();
-                    move |req: RegisterEditRequest| {
-                        let client = Arc::clone(&client);
-                        let contract_name = contract_name.clone();
-                        let prover = Arc::clone(&prover);
-                        let identity = identity.clone();
-                        async move {
-                            let mut initial_state: ImageState = client
-                                .get_contract(&contract_name.clone().into())
-                                .await
-                                .unwrap()
-                                .state
-                                .into();
-
-                            let action = ImageAction::RegisterEdit {
-                                original_image_hash: req.original_image_hash,
-                                edited_image_hash: req.edited_image_hash,
-                                original_edit_signature: req.original_edit_signature,
-                            };
-                            let blobs = vec![action.as_blob(&contract_name)];
-                            let blob_tx = BlobTransaction::new(identity.clone(), blobs.clone());
-
-                            let blob_tx_hash = client.send_tx_blob(&blob_tx).await.unwrap();
-                            println!("✅ Blob tx sent. Tx hash: {}", blob_tx_hash);
-
-                            let inputs = ContractInput {
-                                state: initial_state.as_bytes().unwrap(),
-                                identity: identity.clone().into(),
-                                tx_hash: blob_tx_hash,
-                                private_input: vec![],
-                                tx_ctx: None,
-                                blobs: blobs.clone(),
-                                index: sdk::BlobIndex(0),
-                            };
-
-                            let (program_outputs, _, _) = initial_state.execute(&inputs).unwrap();
-                            println!("🚀 Executed: {}", program_outputs);
-
-                            let proof = prover.prove(inputs).await.unwrap();
-
-                            let proof_tx = ProofTransaction {
-                                proof,
-                                contract_name: contract_name.clone().into(),
-                            };
-
-                            let proof_tx_hash = client.send_tx_proof(&proof_tx).await.unwrap();
-                            println!("✅ Proof tx sent. Tx hash: {}", proof_tx_hash);
-
-                            Ok::<_, warp::Rejection>(warp::reply::json(&program_outputs))
-                        }
-                    }
-                });
-
-            let add_publisher_route = warp::post()
-                .and(warp::path("add_publisher"))
-                .and(warp::body::json())
-                .and_then({
-                    let client = Arc::clone(&client);
-                    let contract_name = contract_name.clone();
-                    let prover = Arc::clone(&prover);
-                    let identity = identity.clone();
-                    move |req: AddPublisherRequest| {
-                        let client = Arc::clone(&client);
-                        let contract_name = contract_name.clone();
-                        let prover = Arc::clone(&prover);
-                        let identity = identity.clone();
-                        async move {
-                            let mut initial_state: ImageState = client
-                                .get_contract(&contract_name.clone().into())
-                                .await
-                                .unwrap()
-                                .state
-                                .into();
-
-                            let action = ImageAction::AddPublisher {
-                                original_image_hash: req.original_image_hash,
-                                original_image_signature: req.original_image_signature,
-                                publisher_pk: req.publisher_pk,
-                            };
-                            let blobs = vec![action.as_blob(&contract_name)];
-                            let blob_tx = BlobTransaction::new(identity.clone(), blobs.clone());
-
-                            let blob_tx_hash = client.send_tx_blob(&blob_tx).await.unwrap();
-                            println!("✅ Blob tx sent. Tx hash: {}", blob_tx_hash);
-
-                            let inputs = ContractInput {
-                                state: initial_state.as_bytes().unwrap(),
-                                identity: identity.clone().into(),
-                                tx_hash: blob_tx_hash,
-                                private_input: vec![],
-                                tx_ctx: None,
-                                blobs: blobs.clone(),
-                                index: sdk::BlobIndex(0),
-                            };
-
-                            let (program_outputs, _, _) = initial_state.execute(&inputs).unwrap();
-                            println!("🚀 Executed: {}", program_outputs);
-
-                            let proof = prover.prove(inputs).await.unwrap();
-
-                            let proof_tx = ProofTransaction {
-                                proof,
-                                contract_name: contract_name.clone().into(),
-                            };
-
-                            let proof_tx_hash = client.send_tx_proof(&proof_tx).await.unwrap();
-                            println!("✅ Proof tx sent. Tx hash: {}", proof_tx_hash);
-
-                            Ok::<_, warp::Rejection>(warp::reply::json(&program_outputs))
-                        }
-                    }
-                });
-
-            let cors = warp::cors()
-                .allow_any_origin()
-                .allow_methods(vec!["POST", "GET", "PUT", "DELETE"])
-                .allow_headers(vec!["Content-Type", "Authorization"]);
-
-            let routes = register_image_route
-                .or(verify_original_image_route)
-                .or(add_publisher_route)
-                .or(register_edit_route)
-                .or(verify_image_edited_route)
-                .with(cors);
-
-            warp::serve(routes).run(([127, 0, 0, 1], 3030)).await;
+        Commands::Server {
+            provers,
+            jobs_dir,
+            index_interval_ms,
+            auth_file,
+            no_auth,
+            audit_log,
+            options,
+        } => {
+            let config = options.resolve()?;
+            let auth = config.auth(auth_file.as_deref(), no_auth)?;
+            let cache = StateCache::start(
+                client.clone(),
+                cli.contract_name.clone(),
+                Duration::from_millis(index_interval_ms),
+            )
+            .await?;
+            let jobs = JobQueue::open(pipeline.clone(), provers, jobs_dir).await?;
+            let audit = Arc::new(AuditLog::open(&audit_log)?);
+
+            config
+                .serve(config.with_cors(routes(jobs, cache, auth, audit, config.max_body_bytes)))
+                .await?;
         }
-        Commands::RegisterContract {} => {
+        Commands::Openapi { no_auth } => println!("{}", serde_json::to_string_pretty(&openapi::spec(!no_auth))?),
+        Commands::RegisterContract { admin } => {
             // Build initial state of contract
-            let initial_state = ImageState { hash_map: HashMap::new() };
+            let initial_state = ImageState {
+                admin_keys: admin,
+                ..Default::default()
+            };
 
             // Send the transaction to register the contract
             let res = client
@@ -440,220 +485,904 @@ async fn main() -> Result<()> {
                 .await?;
             println!("✅ Register contract tx sent. Tx hash: {}", res);
         }
+        Commands::UpgradeContract { to } => {
+            if &to == contract_name {
+                anyhow::bail!("--to must name a new contract, {} is already registered", contract_name);
+            }
+            // Decoding migrates older state versions forward
+            let state = pipeline.fetch_state().await?;
+
+            let res = client
+                .register_contract(&APIRegisterContract {
+                    verifier: "risc0".into(),
+                    program_id: sdk::ProgramId(sdk::to_u8_array(&GUEST_ID).to_vec()),
+                    state_digest: state.as_digest(),
+                    contract_name: to.clone().into(),
+                })
+                .await?;
+            println!(
+                "✅ Contract {} upgraded to state version {} as {}. Tx hash: {}",
+                contract_name,
+                contract::state::STATE_VERSION,
+                to,
+                res
+            );
+        }
         Commands::RegisterImage {
             image_hash,
             image_signature,
             owner_pk,
+            file,
+            perceptual_hash,
         } => {
-            // Fetch the initial state from the node
-            let mut initial_state: ImageState = client
-                .get_contract(&contract_name.clone().into())
-                .await
-                .unwrap()
-                .state
-                .into();
-
-            // ----
-            // Build the blob transaction
-            // ----
+            let (canonical_hash, private_input) = files::canonical_alias(file.as_deref())?;
             let action = ImageAction::RegisterImage {
                 image_hash,
+                canonical_hash,
+                perceptual_hash,
+                image_signature,
+                owner_pk,
+            };
+            pipeline.submit(action, private_input).await?;
+        }
+        // Registering a file is registering its hashes
+        Commands::RegisterImageFile {
+            file,
+            image_signature,
+            owner_pk,
+        } => {
+            let (canonical_hash, bytes) = files::canonical_alias(Some(&file))?;
+            let action = ImageAction::RegisterImage {
+                image_hash: contract::hash::file_hash(&bytes),
+                canonical_hash,
+                perceptual_hash: Some(similarity::dhash(&files::decode(&bytes)?)),
                 image_signature,
                 owner_pk,
             };
-            let blobs = vec![action.as_blob(contract_name)];
-            let blob_tx = BlobTransaction::new(identity.clone(), blobs.clone());
-
-            // Send the blob transaction
-            let blob_tx_hash = client.send_tx_blob(&blob_tx).await.unwrap();
-            println!("✅ Blob tx sent. Tx hash: {}", blob_tx_hash);
-
-            // ----
-            // Prove the state transition
-            // ----
-
-            // Build the contract input
-            let inputs = ContractInput {
-                state: initial_state.as_bytes().unwrap(),
-                identity: identity.clone().into(),
-                tx_hash: blob_tx_hash,
-                private_input: vec![],
-                tx_ctx: None,
-                blobs: blobs.clone(),
-                index: sdk::BlobIndex(0),
+            pipeline.submit(action, bytes).await?;
+        }
+        Commands::ImportC2pa {
+            file,
+            signature,
+            owner_pk,
+            original,
+        } => {
+            let bytes = std::fs::read(file)?;
+            let manifest = content_credentials::read(&bytes)?;
+            println!("📜 C2PA manifest: actions {:?}, signed by {:?}", manifest.actions, manifest.signer);
+            let hashes = (contract::hash::file_hash(&bytes), files::provable_canonical_hash(&bytes));
+            let perceptual_hash = similarity::dhash(&files::decode(&bytes)?);
+            let action = content_credentials::to_action(&manifest, hashes, perceptual_hash, signature, owner_pk, original)?;
+            // The guest proves the canonical hash from the file
+            pipeline.submit(action, bytes).await?;
+        }
+        Commands::ExportC2pa { image_hash, output } => {
+            let state: ImageState = pipeline.fetch_state().await?;
+            let manifest = serde_json::to_string_pretty(&content_credentials::export(&state, image_hash)?)?;
+            match output {
+                Some(path) => std::fs::write(path, manifest)?,
+                None => println!("{}", manifest),
+            }
+        }
+        Commands::Stamp { file, tx_hash, output } => {
+            let bytes = std::fs::read(&file)?;
+            let (file_hash, canonical_hash) = files::hashes(&bytes)?;
+            let state: ImageState = pipeline.fetch_state().await?;
+            let Some(image_hash) = state.resolve_hash(&file_hash).or(state.resolve_hash(&canonical_hash)) else {
+                anyhow::bail!("{} is not registered, register it before stamping", file.display());
             };
-
-            let (program_outputs, _, _) = initial_state.execute(&inputs).unwrap();
-            println!("🚀 Executed: {}", program_outputs);
-
-            // Generate the zk proof
-            let proof = prover.prove(inputs).await.unwrap();
-
-            // Build the Proof transaction
-            let proof_tx = ProofTransaction {
-                proof,
-                contract_name: contract_name.clone().into(),
+            // Stamping changes the file hash, only the canonical hash still ties the stamped file to the image
+            if state.resolve_hash(&canonical_hash) != Some(image_hash) {
+                anyhow::bail!(
+                    "{} has no canonical hash registered, register it with register-image-file before stamping",
+                    file.display()
+                );
+            }
+            let stamp = stamp::Stamp {
+                contract_name: contract_name.clone(),
+                image_hash,
+                root_hash: state.find_original_image(image_hash)?,
+                tx_hash,
+            };
+            std::fs::write(output.unwrap_or(file), stamp::write(&bytes, &stamp)?)?;
+            println!("✅ Stamped {} (root {})", stamp.image_hash, stamp.root_hash);
+        }
+        Commands::Inspect { file } => {
+            let bytes = std::fs::read(&file)?;
+            let Some(stamp) = stamp::read(&bytes)? else {
+                anyhow::bail!("{} has no provenance stamp", file.display());
             };
+            println!("📌 Contract: {}", stamp.contract_name);
+            println!("📌 Image:    {}", stamp.image_hash);
+            println!("📌 Root:     {}", stamp.root_hash);
+            if let Some(tx_hash) = &stamp.tx_hash {
+                println!("📌 Tx:       {}", tx_hash);
+            }
 
-            // Send the proof transaction
-            let proof_tx_hash = client.send_tx_proof(&proof_tx).await.unwrap();
-            println!("✅ Proof tx sent. Tx hash: {}", proof_tx_hash);
+            let state: ImageState = pipeline.fetch_state_of(&stamp.contract_name).await?;
+            let (_, canonical_hash) = files::hashes(&bytes)?;
+            match state.resolve_hash(&canonical_hash) {
+                Some(key) if key == stamp.image_hash => println!("✅ Pixels match the stamped image"),
+                _ => println!("❌ Pixels don't match the stamped image, the stamp may have been copied"),
+            }
+            match state.find_original_image(stamp.image_hash) {
+                Ok(root) if root == stamp.root_hash => println!("✅ Descends from the stamped root"),
+                Ok(root) => println!("❌ Registered root is {}, not the stamped one", root),
+                Err(_) => println!("❌ Stamped image is not registered"),
+            }
+            println!("✅ Is original Image ?: {:?}", state.is_original_image(stamp.image_hash));
+            println!("✅ Is edited Image?: {:?}", state.is_edit_image(stamp.image_hash));
+            if state.camera_attestation(stamp.image_hash).is_some() {
+                println!("📷 Camera-attested");
+            }
+            if state.is_flagged(stamp.image_hash) {
+                println!("⚠️ Edit was signed by a key after it was declared compromised, or through its grant");
+            }
+            if let Some(disclosure) = state.ai_disclosure(stamp.image_hash) {
+                print_ai_disclosure(&disclosure);
+            }
+            if let Some((revoked, revocation)) = state.revocation(stamp.image_hash) {
+                print_revocation(&revoked, revocation);
+            }
+        }
+        Commands::Watermark {
+            command: WatermarkCommands::Embed { file, output },
+        } => {
+            let bytes = std::fs::read(&file)?;
+            let (file_hash, canonical_hash) = files::hashes(&bytes)?;
+            let state: ImageState = pipeline.fetch_state().await?;
+            let Some(image_hash) = state.resolve_hash(&file_hash).or(state.resolve_hash(&canonical_hash)) else {
+                anyhow::bail!("{} is not registered, register it before watermarking", file.display());
+            };
+            let root_hash = state.find_original_image(image_hash)?;
+            let marked = watermark::embed(&files::decode(&bytes)?, watermark::id(&root_hash));
+            files::save(&marked, &output)?;
+            println!("✅ Watermarked with {:08x} (root {})", watermark::id(&root_hash), root_hash);
+        }
+        Commands::Watermark {
+            command: WatermarkCommands::Identify { file },
+        } => {
+            let image = files::decode(&std::fs::read(file)?)?;
+            let state: ImageState = pipeline.fetch_state().await?;
+            match watermark::identify(&image, |id| watermark::resolve(&state, id)) {
+                None => println!("❌ No watermark found"),
+                Some(identification) => {
+                    println!(
+                        "✅ Watermark {} (confidence {:.2})",
+                        identification.watermark_id, identification.confidence
+                    );
+                    if identification.matches.is_empty() {
+                        println!("❌ No registered original with this watermark");
+                    }
+                    for root_hash in identification.matches {
+                        println!("✅ Original: {}", root_hash);
+                    }
+                }
+            }
+        }
+        Commands::HashFile { file } => {
+            let (file_hash, canonical_hash) = files::hashes(&std::fs::read(file)?)?;
+            println!("File hash:      {}", file_hash);
+            println!("Canonical hash: {}", canonical_hash);
+        }
+        Commands::Sign { pk, message } => {
+            let partial = MultiSignature(vec![PartialSignature {
+                signature: contract::ownership::dummy_sign(&pk, &message),
+                pk,
+            }]);
+            println!("{}", partial);
+        }
+        Commands::SignRequest {
+            secret_key,
+            method,
+            path,
+            body,
+        } => {
+            let secret_key: [u8; 32] = hex::decode(secret_key)?
+                .try_into()
+                .map_err(|_| anyhow::anyhow!("Secret key must be 32 bytes"))?;
+            let key = SigningKey::from_bytes(&secret_key);
+            let method: warp::http::Method = method.to_uppercase().parse()?;
+            let body = body.map(std::fs::read).transpose()?.unwrap_or_default();
+            let timestamp = auth::unix_time();
+            let signature = key.sign(auth::signed_message(&method, &path, timestamp, &body).as_bytes());
+            println!(
+                "Authorization: Signature {}:{}:{}",
+                hex::encode(key.verifying_key().as_bytes()),
+                timestamp,
+                hex::encode(signature.to_bytes())
+            );
+        }
+        Commands::CombineSignatures { partials } => {
+            println!("{}", MultiSignature::combine(partials));
+        }
+        Commands::FindSimilar { file, max_distance } => {
+            let image = files::decode(&std::fs::read(file)?)?;
+            let state: ImageState = pipeline.fetch_state().await?;
+            let similar = SimilarityIndex::from_state(&state).search(similarity::dhash(&image), max_distance);
+            if similar.is_empty() {
+                println!("❌ No registered original within distance {}", max_distance);
+            }
+            for candidate in similar {
+                println!("✅ {} (distance {})", candidate.image_hash, candidate.distance);
+            }
         }
         Commands::VerifyOriginalImage { image_hash } => {
-            let initial_state: ImageState = client
-                .get_contract(&contract_name.clone().into())
-                .await
-                .unwrap()
-                .state
-                .into();
+            let initial_state: ImageState = pipeline.fetch_state().await?;
             let is_original = initial_state.is_original_image(image_hash);
             println!("✅ Is original Image ?: {:?}", is_original);
+            if let Some(capture) = initial_state.camera_attestation(image_hash) {
+                println!(
+                    "📷 Camera-attested by {} at {}{}",
+                    capture.device_pk,
+                    capture.metadata.captured_at,
+                    capture.metadata.location.as_deref().map(|l| format!(" ({})", l)).unwrap_or_default()
+                );
+            }
+            if let Some(disclosure) = initial_state.ai_disclosure(image_hash) {
+                print_ai_disclosure(&disclosure);
+            }
+            if let Some((revoked, revocation)) = initial_state.revocation(image_hash) {
+                print_revocation(&revoked, revocation);
+            }
         }
         Commands::VerifyEditImage { edit_image_hash } => {
-            let initial_state: ImageState = client
-                .get_contract(&contract_name.clone().into())
-                .await
-                .unwrap()
-                .state
-                .into();
-            let is_edited = initial_state.is_edit_image(edit_image_hash.clone()).unwrap();
+            let initial_state: ImageState = pipeline.fetch_state().await?;
+            let is_edited = initial_state.is_edit_image(edit_image_hash)?;
             println!("✅ Is edited Image?: {:?}", is_edited);
+            if initial_state.is_flagged(edit_image_hash) {
+                println!("⚠️ Edit was signed by a key after it was declared compromised, or through its grant");
+            }
+            if let Some(disclosure) = initial_state.ai_disclosure(edit_image_hash) {
+                print_ai_disclosure(&disclosure);
+            }
+            if let Some((revoked, revocation)) = initial_state.revocation(edit_image_hash) {
+                print_revocation(&revoked, revocation);
+            }
 
             if is_edited {
-                // Try to find the original image
-                match initial_state.find_original_image(edit_image_hash.clone()) {
-                    Ok(original_image) => {
-                        println!("✅ Found Original Image: {}", original_image);
-                        return Ok(()); // or handle accordingly
-                    }
-                    Err(err) => {
-                        eprintln!("❌ Error finding original image: {:?}", err);
-                        return Ok(()); // or handle accordingly
-                    }
-                }
-            } else {
-                return Ok(());
+                let original_image = initial_state
+                    .find_original_image(edit_image_hash)
+                    .context("Could not find the original image of the edit")?;
+                println!("✅ Found Original Image: {}", original_image);
             }
         }
         Commands::RegisterEdit {
             original_image_hash,
             edited_image_hash,
             original_edit_signature,
+            edited_file,
+            generative,
         } => {
-            // Fetch the initial state from the node
-            let mut initial_state: ImageState = client
-                .get_contract(&contract_name.clone().into())
-                .await
-                .unwrap()
-                .state
-                .into();
-
-            // ----
-            // Build the blob transaction
-            // ----
+            let (edited_canonical_hash, private_input) = files::canonical_alias(edited_file.as_deref())?;
             let action = ImageAction::RegisterEdit {
                 original_image_hash,
                 edited_image_hash,
+                edited_canonical_hash,
                 original_edit_signature,
+                generative,
             };
-            let blobs = vec![action.as_blob(contract_name)];
-            let blob_tx = BlobTransaction::new(identity.clone(), blobs.clone());
-
-            // Send the blob transaction
-            let blob_tx_hash = client.send_tx_blob(&blob_tx).await.unwrap();
-            println!("✅ Blob tx sent. Tx hash: {}", blob_tx_hash);
-
-            // ----
-            // Prove the state transition
-            // ----
-
-            // Build the contract input
-            let inputs = ContractInput {
-                state: initial_state.as_bytes().unwrap(),
-                identity: identity.clone().into(),
-                tx_hash: blob_tx_hash,
-                private_input: vec![],
-                tx_ctx: None,
-                blobs: blobs.clone(),
-                index: sdk::BlobIndex(0),
-            };
-
-            let (program_outputs, _, _) = initial_state.execute(&inputs).unwrap();
-            println!("🚀 Executed: {}", program_outputs);
-
-            // Generate the zk proof
-            let proof = prover.prove(inputs).await.unwrap();
+            pipeline.submit(action, private_input).await?;
+        }
+        Commands::RegisterProvenEdit {
+            original_file,
+            edited_file,
+            transformation,
+        } => {
+            let original_file = std::fs::read(original_file)?;
+            let edited_file = std::fs::read(edited_file)?;
 
-            // Build the Proof transaction
-            let proof_tx = ProofTransaction {
-                proof,
-                contract_name: contract_name.clone().into(),
+            let action = ImageAction::RegisterProvenEdit {
+                original_image_hash: contract::hash::file_hash(&original_file),
+                edited_image_hash: contract::hash::file_hash(&edited_file),
+                transformation,
             };
-
-            // Send the proof transaction
-            let proof_tx_hash = client.send_tx_proof(&proof_tx).await.unwrap();
-            println!("✅ Proof tx sent. Tx hash: {}", proof_tx_hash);
+            // Both files only travel in the private input, the proof never reveals them
+            let witness = TransformWitness {
+                original_file,
+                edited_file,
+            };
+            pipeline.submit(action, borsh::to_vec(&witness)?).await?;
+        }
+        Commands::RegisterCapturedImage {
+            image_hash,
+            owner_pk,
+            device_pk,
+            device_signature,
+            captured_at,
+            location,
+            file,
+        } => {
+            let (canonical_hash, private_input) = files::canonical_alias(file.as_deref())?;
+            let perceptual_hash = file.is_some().then(|| files::decode(&private_input)).transpose()?;
+            let action = ImageAction::RegisterCapturedImage {
+                image_hash,
+                canonical_hash,
+                perceptual_hash: perceptual_hash.map(|image| similarity::dhash(&image)),
+                owner_pk,
+                capture: CaptureMetadata { captured_at, location },
+                device_pk,
+                device_signature,
+            };
+            pipeline.submit(action, private_input).await?;
+        }
+        Commands::RegisterGeneratedImage {
+            image_hash,
+            owner_pk,
+            model_pk,
+            model_signature,
+            file,
+        } => {
+            let (canonical_hash, private_input) = files::canonical_alias(file.as_deref())?;
+            let perceptual_hash = file.is_some().then(|| files::decode(&private_input)).transpose()?;
+            let action = ImageAction::RegisterGeneratedImage {
+                image_hash,
+                canonical_hash,
+                perceptual_hash: perceptual_hash.map(|image| similarity::dhash(&image)),
+                owner_pk,
+                model_pk,
+                model_signature,
+            };
+            pipeline.submit(action, private_input).await?;
+        }
+        Commands::RegisterGenerator {
+            model_pk,
+            vendor,
+            model,
+            admin_signature,
+        } => {
+            let action = ImageAction::SetGeneratorModel {
+                model_pk,
+                model: Some(GeneratorModel { vendor, model }),
+                admin_signature,
+            };
+            pipeline.submit(action, vec![]).await?;
+        }
+        Commands::RemoveGenerator {
+            model_pk,
+            admin_signature,
+        } => {
+            let action = ImageAction::SetGeneratorModel {
+                model_pk,
+                model: None,
+                admin_signature,
+            };
+            pipeline.submit(action, vec![]).await?;
+        }
+        Commands::TrustDevice {
+            device_pk,
+            manufacturer,
+            model,
+            admin_signature,
+        } => {
+            let action = ImageAction::SetTrustedDevice {
+                device_pk,
+                device: Some(TrustedDevice { manufacturer, model }),
+                admin_signature,
+            };
+            pipeline.submit(action, vec![]).await?;
+        }
+        Commands::DistrustDevice {
+            device_pk,
+            admin_signature,
+        } => {
+            let action = ImageAction::SetTrustedDevice {
+                device_pk,
+                device: None,
+                admin_signature,
+            };
+            pipeline.submit(action, vec![]).await?;
+        }
+        Commands::AddAttester {
+            attester_pk,
+            role,
+            admin_signature,
+        } => {
+            let action = ImageAction::SetAttester {
+                attester_pk,
+                role: Some(role),
+                admin_signature,
+            };
+            pipeline.submit(action, vec![]).await?;
+        }
+        Commands::RemoveAttester {
+            attester_pk,
+            admin_signature,
+        } => {
+            let action = ImageAction::SetAttester {
+                attester_pk,
+                role: None,
+                admin_signature,
+            };
+            pipeline.submit(action, vec![]).await?;
+        }
+        Commands::Attest {
+            image_hash,
+            claim,
+            attester_pk,
+            signature,
+        } => {
+            let action = ImageAction::Attest {
+                image_hash,
+                claim,
+                attester_pk,
+                signature,
+            };
+            pipeline.submit(action, vec![]).await?;
+        }
+        Commands::Attestations { image_hash } => {
+            let state: ImageState = pipeline.fetch_state().await?;
+            let attestations = state.attestations_for(image_hash);
+            if attestations.is_empty() {
+                println!("❌ No attestation for {} or its lineage", image_hash);
+            }
+            for entry in attestations {
+                println!(
+                    "✅ {}: \"{}\" by {} ({}) at height {}",
+                    entry.image_hash,
+                    entry.attestation.claim,
+                    entry.attestation.attester_pk,
+                    entry.attestation.role,
+                    entry.attestation.height
+                );
+            }
+        }
+        Commands::Pause { admin_signature } => {
+            let action = ImageAction::SetPaused {
+                paused: true,
+                admin_signature,
+            };
+            pipeline.submit(action, vec![]).await?;
+        }
+        Commands::Unpause { admin_signature } => {
+            let action = ImageAction::SetPaused {
+                paused: false,
+                admin_signature,
+            };
+            pipeline.submit(action, vec![]).await?;
+        }
+        Commands::RotateAdmin {
+            new_admin,
+            admin_signature,
+        } => {
+            let action = ImageAction::RotateAdmin {
+                new_admin,
+                admin_signature,
+            };
+            pipeline.submit(action, vec![]).await?;
+        }
+        Commands::ForceRevoke {
+            image_hash,
+            reason,
+            admin_signature,
+        } => {
+            let action = ImageAction::ForceRevoke {
+                image_hash,
+                reason,
+                admin_signature,
+            };
+            pipeline.submit(action, vec![]).await?;
+        }
+        Commands::AdminLog {} => {
+            let state: ImageState = pipeline.fetch_state().await?;
+            match &state.admin_keys {
+                Some(admin) => println!("👮 Admin keys: {}", admin),
+                None => println!("👮 No admin keys"),
+            }
+            println!("⏸️ Paused: {}", state.paused);
+            for entry in &state.admin_log {
+                println!("📒 {} at height {} by {}", entry.action, entry.height, entry.signers.join(","));
+            }
+            println!("Next governance nonce: {}", state.admin_log.len());
+        }
+        Commands::SetRecoveryKeys {
+            owner_pk,
+            recovery,
+            signature,
+        } => {
+            let action = ImageAction::SetRecoveryKeys {
+                owner_pk,
+                recovery,
+                signature,
+            };
+            pipeline.submit(action, vec![]).await?;
+        }
+        Commands::RotateKey {
+            old_pk,
+            new_pk,
+            compromised_since,
+            signature,
+        } => {
+            let action = ImageAction::RotateKey {
+                old_pk,
+                new_pk,
+                compromised_since,
+                signature,
+            };
+            pipeline.submit(action, vec![]).await?;
         }
         Commands::AddPublisher {
             original_image_hash,
             original_image_signature,
             publisher_pk,
         } => {
-            // Fetch the initial state from the node
-            let mut initial_state: ImageState = client
-                .get_contract(&contract_name.clone().into())
-                .await
-                .unwrap()
-                .state
-                .into();
-
-            // ----
-            // Build the blob transaction
-            // ----
             let action = ImageAction::AddPublisher {
                 original_image_hash,
                 original_image_signature,
                 publisher_pk,
             };
-            let blobs = vec![action.as_blob(contract_name)];
-            let blob_tx = BlobTransaction::new(identity.clone(), blobs.clone());
-
-            // Send the blob transaction
-            let blob_tx_hash = client.send_tx_blob(&blob_tx).await.unwrap();
-            println!("✅ Blob tx sent. Tx hash: {}", blob_tx_hash);
-
-            // ----
-            // Prove the state transition
-            // ----
-
-            // Build the contract input
-            let inputs = ContractInput {
-                state: initial_state.as_bytes().unwrap(),
-                identity: identity.clone().into(),
-                tx_hash: blob_tx_hash,
-                private_input: vec![],
-                tx_ctx: None,
-                blobs: blobs.clone(),
-                index: sdk::BlobIndex(0),
-            };
+            pipeline.submit(action, vec![]).await?;
+        }
+    }
 
-            let (program_outputs, _, _) = initial_state.execute(&inputs).unwrap();
-            println!("🚀 Executed: {}", program_outputs);
+    Ok(())
+}
 
-            // Generate the zk proof
-            let proof = prover.prove(inputs).await.unwrap();
+/// Routes of the server, documented by `openapi::spec`
+fn routes(
+    jobs: JobQueue,
+    cache: StateCache,
+    auth: Auth,
+    audit: Arc<AuditLog>,
+    max_body_bytes: u64,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let register_image_route = warp::path("register_image")
+        .and(warp::post())
+        .and(auth.json(Scope::Write, max_body_bytes))
+        .and_then({
+            let jobs = jobs.clone();
+            let audit = Arc::clone(&audit);
+            move |caller: Caller, req: RegisterImageRequest| {
+                let jobs = jobs.clone();
+                let audit = Arc::clone(&audit);
+                async move {
+                    let action = ImageAction::RegisterImage {
+                        image_hash: req.image_hash,
+                        canonical_hash: req.canonical_hash,
+                        perceptual_hash: req.perceptual_hash,
+                        image_signature: req.image_signature,
+                        owner_pk: req.owner_pk,
+                    };
+                    let job = enqueue(&jobs, &audit, &caller, action, req.file).await?;
+                    Ok::<_, warp::Rejection>(warp::reply::json(&job))
+                }
+            }
+        });
+
+    let verify_original_image_route = warp::path("verify_original_image")
+        .and(warp::post())
+        .and(auth.json(Scope::Read, max_body_bytes))
+        .and_then({
+            let cache = cache.clone();
+            move |_: Caller, req: VerifyOriginalImageRequest| {
+                let cache = cache.clone();
+                async move {
+                    let index = cache.read().await;
+                    let initial_state = &index.state;
+                    let is_original_result = initial_state.is_original_image(req.image_hash);
+                    match is_original_result {
+                        Ok(is_original) => {
+                            let capture = initial_state.camera_attestation(req.image_hash).cloned();
+                            let response = IsOriginalResponse {
+                                is_original,
+                                camera_attested: capture.is_some(),
+                                capture,
+                                ai_disclosure: initial_state.ai_disclosure(req.image_hash),
+                                revocation: initial_state.revocation(req.image_hash).map(|(_, r)| r.clone()),
+                            };
+                            Ok::<_, warp::Rejection>(warp::reply::json(&response))
+                        }
+                        Err(e) => {
+                            eprintln!("Failed to verify original image: {:?}", e);
+                            Err(warp::reject::custom(ApiError::new(ErrorKind::Internal, e.to_string())))
+                        }
+                    }
+                }
+            }
+        });
+
+    let verify_image_edited_route = warp::path("verify_edit_image")
+        .and(warp::post())
+        .and(auth.json(Scope::Read, max_body_bytes))
+        .and_then({
+            let cache = cache.clone();
+            move |_: Caller, req: VerifyEditImageRequest| {
+                let cache = cache.clone();
+                async move {
+                    let index = cache.read().await;
+                    let initial_state = &index.state;
+
+                    let hash = req.edit_image_hash;
+                    match initial_state.is_edit_image(hash) {
+                        Ok(is_edited) => {
+                            let edit = initial_state.resolve_hash(&hash).filter(|_| is_edited);
+                            let response = VerifyEditImageResponse {
+                                // A broken lineage still answers, without its original
+                                original_image: edit.and_then(|_| {
+                                    initial_state
+                                        .find_original_image(hash)
+                                        .inspect_err(|err| eprintln!("Error finding original image: {:?}", err))
+                                        .ok()
+                                }),
+                                is_edited,
+                                redactions: edit
+                                    .map(|key| initial_state.hash_map[&key].redactions().to_vec())
+                                    .unwrap_or_default(),
+                                flagged: is_edited && initial_state.is_flagged(hash),
+                                ai_disclosure: initial_state.ai_disclosure(hash),
+                                revocation: initial_state.revocation(hash).map(|(_, r)| r.clone()),
+                            };
+                            Ok::<_, warp::Rejection>(warp::reply::json(&response))
+                        }
+                        Err(e) => {
+                            eprintln!("Error checking if image is edited: {:?}", e);
+                            Err(warp::reject::custom(ApiError::new(ErrorKind::Internal, e.to_string())))
+                        }
+                    }
+                }
+            }
+        });
+
+    let register_edit_route = warp::path("register_edit")
+        .and(warp::post())
+        .and(auth.json(Scope::Write, max_body_bytes))
+        .and_then({
+            let jobs = jobs.clone();
+            let audit = Arc::clone(&audit);
+            move |caller: Caller, req: RegisterEditRequest| {
+                let jobs = jobs.clone();
+                let audit = Arc::clone(&audit);
+                async move {
+                    let action = ImageAction::RegisterEdit {
+                        original_image_hash: req.original_image_hash,
+                        edited_image_hash: req.edited_image_hash,
+                        edited_canonical_hash: req.edited_canonical_hash,
+                        original_edit_signature: req.original_edit_signature,
+                        generative: req.generative,
+                    };
+                    let job = enqueue(&jobs, &audit, &caller, action, req.edited_file).await?;
+                    Ok::<_, warp::Rejection>(warp::reply::json(&job))
+                }
+            }
+        });
+
+    let add_publisher_route = warp::path("add_publisher")
+        .and(warp::post())
+        .and(auth.json(Scope::Write, max_body_bytes))
+        .and_then({
+            let jobs = jobs.clone();
+            let audit = Arc::clone(&audit);
+            move |caller: Caller, req: AddPublisherRequest| {
+                let jobs = jobs.clone();
+                let audit = Arc::clone(&audit);
+                async move {
+                    let action = ImageAction::AddPublisher {
+                        original_image_hash: req.original_image_hash,
+                        original_image_signature: req.original_image_signature,
+                        publisher_pk: req.publisher_pk,
+                    };
+                    let job = enqueue(&jobs, &audit, &caller, action, vec![]).await?;
+                    Ok::<_, warp::Rejection>(warp::reply::json(&job))
+                }
+            }
+        });
+
+    let find_similar_route = warp::path("find_similar")
+        .and(warp::post())
+        .and(warp::query::<FindSimilarQuery>())
+        .and(auth.bytes(Scope::Read, max_body_bytes))
+        .and_then({
+            let cache = cache.clone();
+            move |query: FindSimilarQuery, _: Caller, body: warp::hyper::body::Bytes| {
+                let cache = cache.clone();
+                async move {
+                    let image = match files::decode(&body) {
+                        Ok(image) => image,
+                        Err(err) => {
+                            eprintln!("Failed to decode uploaded image: {:?}", err);
+                            return Err(warp::reject::custom(ApiError::bad_request(format!("{:#}", err))));
+                        }
+                    };
+                    let index = cache.read().await;
+                    let max_distance = query.max_distance.unwrap_or(similarity::DEFAULT_MAX_DISTANCE);
+                    let similar = index.indexes.similarity.search(similarity::dhash(&image), max_distance);
+                    Ok::<_, warp::Rejection>(warp::reply::json(&similar))
+                }
+            }
+        });
+
+    let attestations_route = warp::path("attestations")
+        .and(warp::post())
+        .and(auth.json(Scope::Read, max_body_bytes))
+        .and_then({
+            let cache = cache.clone();
+            move |_: Caller, req: AttestationsRequest| {
+                let cache = cache.clone();
+                async move {
+                    let index = cache.read().await;
+                    let state = &index.state;
+                    Ok::<_, warp::Rejection>(warp::reply::json(&state.attestations_for(req.image_hash)))
+                }
+            }
+        });
+
+    let identify_route = warp::path("identify")
+        .and(warp::post())
+        .and(auth.bytes(Scope::Read, max_body_bytes))
+        .and_then({
+            let cache = cache.clone();
+            move |_: Caller, body: warp::hyper::body::Bytes| {
+                let cache = cache.clone();
+                async move {
+                    let image = match files::decode(&body) {
+                        Ok(image) => image,
+                        Err(err) => {
+                            eprintln!("Failed to decode uploaded image: {:?}", err);
+                            return Err(warp::reject::custom(ApiError::bad_request(format!("{:#}", err))));
+                        }
+                    };
+                    let index = cache.read().await;
+                    let identification = watermark::identify(&image, |id| {
+                        index.indexes.watermarks.get(&id).into_iter().flatten().copied().collect()
+                    });
+                    Ok::<_, warp::Rejection>(warp::reply::json(&identification))
+                }
+            }
+        });
+
+    // Any action, as JSON, so new actions are served without a dedicated route
+    let submit_route = warp::path("submit")
+        .and(warp::post())
+        .and(auth.json(Scope::Write, max_body_bytes))
+        .and_then({
+            let jobs = jobs.clone();
+            let audit = Arc::clone(&audit);
+            move |caller: Caller, req: SubmitRequest| {
+                let jobs = jobs.clone();
+                let audit = Arc::clone(&audit);
+                async move {
+                    let job = enqueue(&jobs, &audit, &caller, req.action, req.private_input).await?;
+                    Ok::<_, warp::Rejection>(warp::reply::json(&job))
+                }
+            }
+        });
+
+    let job_route = warp::path!("jobs" / u64).and(warp::get()).and(auth.require(Scope::Read)).and_then({
+        let jobs = jobs.clone();
+        move |id: u64| {
+            let jobs = jobs.clone();
+            async move {
+                match jobs.get(id).await {
+                    Some(job) => Ok::<_, warp::Rejection>(warp::reply::json(&job)),
+                    None => Err(api::ApiError::not_found(format!("No job {}", id)).into()),
+                }
+            }
+        }
+    });
+
+    let image_route = warp::path!("images" / String).and(warp::get()).and(auth.require(Scope::Read)).and_then({
+        let cache = cache.clone();
+        move |hash: String| {
+            let cache = cache.clone();
+            async move {
+                let index = cache.read().await;
+                let image = api::image(&index, &hash)?;
+                Ok::<_, warp::Rejection>(warp::reply::json(&image))
+            }
+        }
+    });
+
+    let lineage_route = warp::path!("images" / String / "lineage")
+        .and(warp::get())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(auth.require(Scope::Read))
+        .and_then({
+            let cache = cache.clone();
+            move |hash: String, query: HashMap<String, String>| {
+                let cache = cache.clone();
+                async move {
+                    let filter = api::Filter::parse(&query)?;
+                    let index = cache.read().await;
+                    let lineage = api::lineage(&index, &hash, &filter)?;
+                    Ok::<_, warp::Rejection>(warp::reply::json(&lineage))
+                }
+            }
+        });
+
+    let owner_images_route = warp::path!("owners" / String / "images")
+        .and(warp::get())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(auth.require(Scope::Read))
+        .and_then({
+            let cache = cache.clone();
+            move |pk: String, query: HashMap<String, String>| {
+                let cache = cache.clone();
+                async move {
+                    let filter = api::Filter::parse(&query)?;
+                    let index = cache.read().await;
+                    Ok::<_, warp::Rejection>(warp::reply::json(&api::owner_images(&index, &pk, &filter)))
+                }
+            }
+        });
+
+    let publisher_grants_route = warp::path!("publishers" / String / "grants")
+        .and(warp::get())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(auth.require(Scope::Read))
+        .and_then({
+            let cache = cache.clone();
+            move |pk: String, query: HashMap<String, String>| {
+                let cache = cache.clone();
+                async move {
+                    let filter = api::Filter::parse(&query)?;
+                    let index = cache.read().await;
+                    Ok::<_, warp::Rejection>(warp::reply::json(&api::publisher_grants(&index, &pk, &filter)))
+                }
+            }
+        });
+
+    // Read-only resources
+    let rest_routes = job_route
+        .or(image_route)
+        .or(lineage_route)
+        .or(owner_images_route)
+        .or(publisher_grants_route);
+
+    // Document of these routes, to generate clients from
+    let openapi_route = warp::path!("openapi.json").and(warp::get()).map({
+        let spec = Arc::new(openapi::spec(auth.enabled()));
+        move || warp::reply::json(&*spec)
+    });
+
+    register_image_route
+        .or(verify_original_image_route)
+        .or(add_publisher_route)
+        .or(register_edit_route)
+        .or(verify_image_edited_route)
+        .or(find_similar_route)
+        .or(attestations_route)
+        .or(identify_route)
+        .or(submit_route)
+        .or(rest_routes)
+        .or(openapi_route)
+        .recover(api::recover)
+}
 
-            // Build the Proof transaction
-            let proof_tx = ProofTransaction {
-                proof,
-                contract_name: contract_name.clone().into(),
-            };
+/// Sends the blob of an action and queues its proof, rejecting with the failed stage. Either way the submission
+/// is audited.
+async fn enqueue(
+    jobs: &JobQueue,
+    audit: &AuditLog,
+    caller: &Caller,
+    action: ImageAction,
+    private_input: Vec<u8>,
+) -> Result<jobs::Job, warp::Rejection> {
+    let outcome = jobs.enqueue(&action, private_input).await;
+    audit.record(caller, &action, &outcome);
+    outcome.map_err(|err| {
+        eprintln!("Failed to submit action: {}", err);
+        warp::reject::custom(ApiError::from(err))
+    })
+}
 
-            // Send the proof transaction
-            let proof_tx_hash = client.send_tx_proof(&proof_tx).await.unwrap();
-            println!("✅ Proof tx sent. Tx hash: {}", proof_tx_hash);
+/// Synthetic-media label printed by the verify commands
+fn print_ai_disclosure(disclosure: &AiDisclosure) {
+    let inherited = match disclosure.inherited {
+        true => format!(", inherited from {}", disclosure.image_hash),
+        false => String::new(),
+    };
+    match &disclosure.provenance {
+        ProvenanceClass::AiGenerated { model_pk } => {
+            println!("🤖 AI-generated by model {}{}", model_pk, inherited)
         }
+        _ => println!("🤖 AI-assisted edit{}", inherited),
     }
+}
 
-    Ok(())
+/// Public reason of a revocation, printed by the verify commands
+fn print_revocation(revoked: &ContentHash, revocation: &Revocation) {
+    println!(
+        "⛔ {} was revoked by the admin at height {}: {}",
+        revoked, revocation.height, revocation.reason
+    );
 }
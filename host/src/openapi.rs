@@ -0,0 +1,411 @@
+use contract::attestation::LineageAttestation;
+use schemars::generate::SchemaSettings;
+use schemars::{JsonSchema, SchemaGenerator};
+use serde_json::{json, Map, Value};
+use warp::http::Method;
+use crate::api::{self, ApiError, ImageResource, ImageSummary, Page};
+use crate::auth::Scope;
+use crate::jobs::Job;
+use crate::similarity::SimilarImage;
+use crate::watermark::Identification;
+use crate::{
+    AddPublisherRequest, AttestationsRequest, IsOriginalResponse, RegisterEditRequest, RegisterImageRequest,
+    SubmitRequest, VerifyEditImageRequest, VerifyEditImageResponse, VerifyOriginalImageRequest,
+};
+
+/// Operation served by `routes`, as documented in `/openapi.json`
+struct Operation {
+    method: Method,
+    /// Path template, e.g. `/images/{hash}`
+    path: &'static str,
+    summary: &'static str,
+    /// Scope the key must have, `None` for public operations
+    scope: Option<Scope>,
+    parameters: Vec<Value>,
+    body: Option<Value>,
+    response: Value,
+}
+
+impl Operation {
+    fn new(method: Method, path: &'static str, summary: &'static str, response: Value) -> Self {
+        Operation {
+            method,
+            path,
+            summary,
+            scope: Some(Scope::Read),
+            parameters: vec![],
+            body: None,
+            response,
+        }
+    }
+
+    fn scope(mut self, scope: Option<Scope>) -> Self {
+        self.scope = scope;
+        self
+    }
+
+    fn body(mut self, content: Value) -> Self {
+        self.body = Some(content);
+        self
+    }
+
+    fn param(mut self, location: &str, name: &str, description: &str, schema: Value) -> Self {
+        self.parameters.push(json!({
+            "in": location,
+            "name": name,
+            "description": description,
+            "required": location == "path",
+            "schema": schema,
+        }));
+        self
+    }
+
+    /// Query of the collection routes, see `api::Filter`
+    fn filter(self) -> Self {
+        let limit = json!({"type": "integer", "minimum": 1, "maximum": api::MAX_LIMIT, "default": api::DEFAULT_LIMIT});
+        self.param("query", "offset", "Items skipped", json!({"type": "integer", "minimum": 0, "default": 0}))
+            .param("query", "limit", "Page size", limit)
+            .param("query", "kind", "Only originals or only edits", json!({"type": "string", "enum": ["original", "edit"]}))
+            .param("query", "since", "Lowest registration height", json!({"type": "integer", "minimum": 0}))
+            .param("query", "until", "Highest registration height", json!({"type": "integer", "minimum": 0}))
+    }
+
+    fn to_json(&self, error: &Value, authenticated: bool) -> Value {
+        let mut operation = json!({
+            "summary": self.summary,
+            "parameters": self.parameters,
+            "responses": {
+                "200": {"description": "Success", "content": {"application/json": {"schema": self.response}}},
+                "default": {"description": "Error, with the status of its code", "content": {"application/json": {"schema": error}}},
+            },
+        });
+        match self.scope {
+            _ if !authenticated => {}
+            Some(scope) => operation["description"] = json!(format!("Requires the `{}` scope", scope)),
+            None => operation["security"] = json!([]),
+        }
+        if let Some(content) = &self.body {
+            operation["requestBody"] = json!({"required": true, "content": content});
+        }
+        operation
+    }
+}
+
+fn schema<T: JsonSchema>(generator: &mut SchemaGenerator) -> Value {
+    generator.subschema_for::<T>().to_value()
+}
+
+fn json_body<T: JsonSchema>(generator: &mut SchemaGenerator) -> Value {
+    json!({"application/json": {"schema": schema::<T>(generator)}})
+}
+
+/// JPEG or PNG file sent as the raw body
+fn image_body() -> Value {
+    json!({"application/octet-stream": {"schema": {"type": "string", "format": "binary"}}})
+}
+
+fn operations(generator: &mut SchemaGenerator) -> Vec<Operation> {
+    let hash = || json!({"type": "string"});
+    let job = schema::<Job>(generator);
+    vec![
+        Operation::new(Method::POST, "/register_image", "Registers an original", job.clone())
+            .scope(Some(Scope::Write))
+            .body(json_body::<RegisterImageRequest>(generator)),
+        Operation::new(Method::POST, "/register_edit", "Registers an edit signed by the owners", job.clone())
+            .scope(Some(Scope::Write))
+            .body(json_body::<RegisterEditRequest>(generator)),
+        Operation::new(Method::POST, "/add_publisher", "Grants a publisher key on an original", job.clone())
+            .scope(Some(Scope::Write))
+            .body(json_body::<AddPublisherRequest>(generator)),
+        Operation::new(Method::POST, "/submit", "Submits any contract action", job.clone())
+            .scope(Some(Scope::Write))
+            .body(json_body::<SubmitRequest>(generator)),
+        Operation::new(
+            Method::POST,
+            "/verify_original_image",
+            "Tells whether an image is a registered original",
+            schema::<IsOriginalResponse>(generator),
+        )
+        .body(json_body::<VerifyOriginalImageRequest>(generator)),
+        Operation::new(
+            Method::POST,
+            "/verify_edit_image",
+            "Tells whether an image is a registered edit, and of which original",
+            schema::<VerifyEditImageResponse>(generator),
+        )
+        .body(json_body::<VerifyEditImageRequest>(generator)),
+        Operation::new(
+            Method::POST,
+            "/find_similar",
+            "Lists registered originals that look like the uploaded file",
+            schema::<Vec<SimilarImage>>(generator),
+        )
+        .param("query", "max_distance", "Largest Hamming distance between dHashes", json!({"type": "integer", "minimum": 0}))
+        .body(image_body()),
+        Operation::new(
+            Method::POST,
+            "/attestations",
+            "Lists the attestations of an image and of the images it was edited from",
+            schema::<Vec<LineageAttestation>>(generator),
+        )
+        .body(json_body::<AttestationsRequest>(generator)),
+        Operation::new(
+            Method::POST,
+            "/identify",
+            "Reads the watermark of the uploaded file, null when it has none",
+            schema::<Option<Identification>>(generator),
+        )
+        .body(image_body()),
+        Operation::new(Method::GET, "/jobs/{id}", "Progress of a proving job", job)
+            .param("path", "id", "Job ID", json!({"type": "integer", "minimum": 0})),
+        Operation::new(Method::GET, "/images/{hash}", "Registered image", schema::<ImageResource>(generator))
+            .param("path", "hash", "File, canonical or any typed form of the hash", hash()),
+        Operation::new(
+            Method::GET,
+            "/images/{hash}/lineage",
+            "The image then every image it was edited from, up to its original",
+            schema::<Page<ImageSummary>>(generator),
+        )
+        .param("path", "hash", "File, canonical or any typed form of the hash", hash())
+        .filter(),
+        Operation::new(
+            Method::GET,
+            "/owners/{pk}/images",
+            "Images the key owns or co-owns, by registration height",
+            schema::<Page<ImageSummary>>(generator),
+        )
+        .param("path", "pk", "Owner key", hash())
+        .filter(),
+        Operation::new(
+            Method::GET,
+            "/publishers/{pk}/grants",
+            "Originals the key was granted to publish, by registration height",
+            schema::<Page<ImageSummary>>(generator),
+        )
+        .param("path", "pk", "Publisher key", hash())
+        .filter(),
+        Operation::new(Method::GET, "/openapi.json", "This document", json!({"type": "object"})).scope(None),
+    ]
+}
+
+/// OpenAPI 3 document of the server, its schemas derived from the request and response types. Security schemes
+/// and scopes are only documented when the server is `authenticated`.
+pub fn spec(authenticated: bool) -> Value {
+    let mut generator = SchemaSettings::openapi3().into_generator();
+    let operations = operations(&mut generator);
+    let error = schema::<ApiError>(&mut generator);
+
+    let mut paths = Map::new();
+    for operation in &operations {
+        let item = paths.entry(operation.path).or_insert_with(|| json!({}));
+        item[operation.method.as_str().to_lowercase()] = operation.to_json(&error, authenticated);
+    }
+    let mut spec = json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "Image provenance host",
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "paths": paths,
+        "components": {
+            "schemas": generator.take_definitions(true),
+        },
+    });
+    if authenticated {
+        spec["components"]["securitySchemes"] = json!({
+            "apiKey": {"type": "http", "scheme": "bearer", "description": "API key of the server's auth file"},
+            "signedRequest": {
+                "type": "apiKey",
+                "in": "header",
+                "name": "Authorization",
+                "description": "`Signature <pk>:<timestamp>:<signature>`: Ed25519 signature, in hex, of the method, \
+                    the path with its query, the unix timestamp and the SHA-256 of the body in hex, one per line",
+            },
+        });
+        spec["security"] = json!([{"apiKey": []}, {"signedRequest": []}]);
+    }
+    spec
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+    use std::path::Path;
+    use std::sync::Arc;
+    use async_trait::async_trait;
+    use sdk::{BlobTransaction, ContractInput, ProofData, ProofTransaction, StateDigest, TxHash};
+    use warp::http::StatusCode;
+    use warp::{Filter, Rejection, Reply};
+    use crate::audit::AuditLog;
+    use crate::auth::Auth;
+    use crate::jobs::JobQueue;
+    use crate::pipeline::{Node, Pipeline, Prover, RetryPolicy};
+    use crate::server::DEFAULT_MAX_BODY_BYTES;
+    use crate::state_cache::{Index, StateCache};
+    use super::*;
+
+    /// Node and prover of the checked routes, which the requests never reach
+    struct Unreachable;
+
+    #[async_trait]
+    impl Node for Unreachable {
+        async fn contract_state(&self, _contract_name: &str) -> anyhow::Result<StateDigest> {
+            anyhow::bail!("no node")
+        }
+
+        async fn send_tx_blob(&self, _tx: &BlobTransaction) -> anyhow::Result<TxHash> {
+            anyhow::bail!("no node")
+        }
+
+        async fn send_tx_proof(&self, _tx: &ProofTransaction) -> anyhow::Result<TxHash> {
+            anyhow::bail!("no node")
+        }
+    }
+
+    #[async_trait]
+    impl Prover for Unreachable {
+        async fn prove(&self, _inputs: ContractInput) -> anyhow::Result<ProofData> {
+            anyhow::bail!("no prover")
+        }
+    }
+
+    /// The server routes, without auth, on an empty index and with their journals in `dir`
+    async fn routes(dir: &Path) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+        let pipeline = Pipeline::new(
+            Arc::new(Unreachable),
+            Arc::new(Unreachable),
+            "image_registry".into(),
+            "none.image_registry".into(),
+            RetryPolicy::default(),
+        );
+        let jobs = JobQueue::open(pipeline, 1, dir.join("jobs")).await.unwrap();
+        let audit = Arc::new(AuditLog::open(&dir.join("audit.log")).unwrap());
+        crate::routes(jobs, StateCache::new(Index::default()), Auth::disabled(), audit, DEFAULT_MAX_BODY_BYTES)
+    }
+
+    /// Path of `operation` with sample values in place of its parameters
+    fn sample_path(operation: &Operation) -> String {
+        operation
+            .path
+            .split('/')
+            .map(|segment| match segment {
+                "{id}" => "1",
+                _ if segment.starts_with('{') => "sample",
+                _ => segment,
+            })
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+
+    /// Path template with its parameters as `{}`
+    fn template(path: &str) -> String {
+        path.split('/')
+            .map(|segment| if segment.starts_with('{') { "{}" } else { segment })
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+
+    /// Method and path template of every route `crate::routes` declares, read from its source
+    fn declared() -> BTreeSet<(String, String)> {
+        let source = include_str!("main.rs");
+        let routes = &source[source.find("\nfn routes(").unwrap()..];
+        routes
+            .split("_route = ")
+            .skip(1)
+            .map(|route| {
+                let method = ["get", "post"]
+                    .into_iter()
+                    .filter_map(|method| route.find(&format!(".and(warp::{}())", method)).map(|at| (at, method)))
+                    .min()
+                    .expect("every route has a method")
+                    .1
+                    .to_uppercase();
+                let literal = route.find("warp::path(\"");
+                let path = match route.find("warp::path!(") {
+                    Some(start) if literal.is_none_or(|literal| start < literal) => {
+                        let segments = &route[start + "warp::path!(".len()..];
+                        segments[..segments.find(')').unwrap()]
+                            .split('/')
+                            .map(|segment| match segment.trim() {
+                                quoted if quoted.starts_with('"') => format!("/{}", quoted.trim_matches('"')),
+                                _ => "/{}".to_string(),
+                            })
+                            .collect()
+                    }
+                    _ => {
+                        let name = &route[literal.expect("every route has a path") + "warp::path(\"".len()..];
+                        format!("/{}", &name[..name.find('"').unwrap()])
+                    }
+                };
+                (method, path)
+            })
+            .collect()
+    }
+
+    fn documented() -> Vec<Operation> {
+        operations(&mut SchemaSettings::openapi3().into_generator())
+    }
+
+    #[test]
+    fn security_is_only_documented_with_authentication() {
+        let authenticated = spec(true);
+        assert_eq!(authenticated["security"].as_array().map(Vec::len), Some(2));
+        assert!(authenticated["components"]["securitySchemes"].is_object());
+        assert_eq!(authenticated["paths"]["/openapi.json"]["get"]["security"], json!([]));
+
+        let open = spec(false);
+        assert!(open.get("security").is_none());
+        assert!(open["components"].get("securitySchemes").is_none());
+        assert!(open["paths"]["/submit"]["post"].get("description").is_none());
+    }
+
+    #[test]
+    fn every_declared_route_is_documented() {
+        let declared = declared();
+        let documented: BTreeSet<_> = documented()
+            .iter()
+            .map(|operation| (operation.method.to_string(), template(operation.path)))
+            .collect();
+        assert!(declared.len() > 10, "routes not found in main.rs: {:?}", declared);
+        assert_eq!(
+            declared.difference(&documented).collect::<Vec<_>>(),
+            Vec::<&(String, String)>::new(),
+            "served but not documented"
+        );
+        assert_eq!(
+            documented.difference(&declared).collect::<Vec<_>>(),
+            Vec::<&(String, String)>::new(),
+            "documented but not declared"
+        );
+    }
+
+    /// Sends a request to every documented operation, failing on those the routes don't serve: a path answered
+    /// with `api::NO_SUCH_ROUTE` or a method answered with 405. Requests carry an empty body, so they are
+    /// rejected by their route before reaching the node or the prover.
+    #[tokio::test]
+    async fn every_documented_operation_is_served() {
+        let dir = std::env::temp_dir().join(format!("openapi-check-{}", std::process::id()));
+        let routes = routes(&dir).await;
+        let mut missing = vec![];
+        for operation in documented() {
+            let response = warp::test::request()
+                .method(operation.method.as_str())
+                .path(&sample_path(&operation))
+                .header("content-type", "application/json")
+                .body("{}")
+                .reply(&routes)
+                .await;
+            let served = match response.status() {
+                StatusCode::METHOD_NOT_ALLOWED => false,
+                StatusCode::NOT_FOUND => serde_json::from_slice::<Value>(response.body())
+                    .is_ok_and(|error| error["message"] != api::NO_SUCH_ROUTE),
+                _ => true,
+            };
+            if !served {
+                missing.push(format!("{} {}", operation.method, operation.path));
+            }
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+        assert!(missing.is_empty(), "documented but not served: {}", missing.join(", "));
+    }
+}
@@ -0,0 +1,153 @@
+use std::collections::{HashMap, VecDeque};
+use contract::ImageState;
+use sdk::{Digestable, StateDigest, TxHash};
+
+/// Action whose blob is sent but not settled yet
+struct Pending {
+    blob_tx_hash: TxHash,
+    /// State the action was executed on
+    base: StateDigest,
+    /// State once the action is applied, and its digest
+    state: ImageState,
+    digest: StateDigest,
+}
+
+/// Optimistic state of the host: the last settled state with the pending actions applied in the order their
+/// blobs were sent. Each action is proven against the output of its predecessor, so actions sent in a row
+/// don't all prove from the same settled state.
+#[derive(Default)]
+pub struct PendingState {
+    actions: VecDeque<Pending>,
+    /// Blob -> whether the node applied its action, for the actions reconciled but not awaited yet
+    outcomes: HashMap<TxHash, bool>,
+}
+
+impl PendingState {
+    /// Reconciles the pending actions with the state settled by the node (see `reconcile`) and returns the state
+    /// the next action applies to
+    pub fn base(&mut self, settled: ImageState) -> ImageState {
+        self.reconcile(&settled);
+        match self.actions.back() {
+            Some(last) => last.state.clone(),
+            None => settled,
+        }
+    }
+
+    /// The node applied every action up to the one leaving the settled state, those are dropped. When no pending
+    /// action leaves the settled state and the next one wasn't executed on it (an action sent by another host, or
+    /// a pending action that failed on chain), the pending actions can't settle anymore and are dropped too.
+    pub fn reconcile(&mut self, settled: &ImageState) {
+        let digest = settled.as_digest();
+        if let Some(applied) = self.actions.iter().position(|pending| pending.digest == digest) {
+            for pending in self.actions.drain(..=applied) {
+                self.outcomes.insert(pending.blob_tx_hash, true);
+            }
+        } else if let Some(next) = self.actions.front().filter(|next| next.base != digest) {
+            eprintln!(
+                "⚠️ Settled state diverged at height {}, dropping {} pending action(s) from blob {}",
+                settled.height,
+                self.actions.len(),
+                next.blob_tx_hash
+            );
+            for pending in self.actions.drain(..) {
+                self.outcomes.insert(pending.blob_tx_hash, false);
+            }
+        }
+    }
+
+    /// Whether the node applied the action of `blob_tx_hash`, once reconciled. Reported once.
+    pub fn outcome(&mut self, blob_tx_hash: &TxHash) -> Option<bool> {
+        self.outcomes.remove(blob_tx_hash)
+    }
+
+    pub fn contains(&self, blob_tx_hash: &TxHash) -> bool {
+        self.actions.iter().any(|pending| pending.blob_tx_hash == *blob_tx_hash)
+    }
+
+    /// Records an action executed on `base`, leaving `state`
+    pub fn push(&mut self, blob_tx_hash: TxHash, base: StateDigest, state: ImageState) {
+        self.actions.push_back(Pending {
+            blob_tx_hash,
+            base,
+            digest: state.as_digest(),
+            state,
+        });
+    }
+
+    /// Drops the action of `blob_tx_hash`, which won't settle, and every action applied on top of it
+    pub fn discard(&mut self, blob_tx_hash: &TxHash) {
+        if let Some(failed) = self.actions.iter().position(|pending| pending.blob_tx_hash == *blob_tx_hash) {
+            self.actions.truncate(failed);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.actions.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(height: u64) -> ImageState {
+        ImageState {
+            height,
+            ..ImageState::default()
+        }
+    }
+
+    fn tx(name: &str) -> TxHash {
+        TxHash(name.to_string())
+    }
+
+    /// Actions a: 0 -> 1, b: 1 -> 2 and c: 2 -> 3, the states named by their height
+    fn pending() -> PendingState {
+        let mut pending = PendingState::default();
+        for (name, height) in [("a", 0), ("b", 1), ("c", 2)] {
+            pending.push(tx(name), state(height).as_digest(), state(height + 1));
+        }
+        pending
+    }
+
+    #[test]
+    fn drops_the_actions_up_to_the_settled_state() {
+        let mut pending = pending();
+        assert_eq!(pending.base(state(0)).height, 3);
+        assert_eq!(pending.len(), 3);
+
+        // Two actions settled between fetches
+        assert_eq!(pending.base(state(2)).height, 3);
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending.outcome(&tx("a")), Some(true));
+        assert_eq!(pending.outcome(&tx("b")), Some(true));
+        assert_eq!(pending.outcome(&tx("b")), None);
+        assert_eq!(pending.outcome(&tx("c")), None);
+        assert!(pending.contains(&tx("c")));
+    }
+
+    #[test]
+    fn drops_every_action_once_the_settled_state_diverged() {
+        let mut pending = pending();
+        // At the height of b's state, but not b's state
+        let foreign = ImageState {
+            paused: true,
+            ..state(2)
+        };
+        assert_eq!(pending.base(foreign.clone()).as_digest(), foreign.as_digest());
+        assert_eq!(pending.len(), 0);
+        for name in ["a", "b", "c"] {
+            assert_eq!(pending.outcome(&tx(name)), Some(false));
+        }
+    }
+
+    #[test]
+    fn discards_a_failed_action_and_those_sent_after_it() {
+        let mut pending = pending();
+        pending.discard(&tx("b"));
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending.base(state(0)).height, 1);
+        pending.discard(&tx("unknown"));
+        assert_eq!(pending.len(), 1);
+    }
+}
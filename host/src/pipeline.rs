@@ -0,0 +1,645 @@
+use std::fmt;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use async_trait::async_trait;
+use client_sdk::helpers::risc0::Risc0Prover;
+use client_sdk::rest_client::NodeApiHttpClient;
+use contract::{ImageAction, ImageState};
+use serde::Serialize;
+use tokio::sync::Mutex;
+use sdk::{BlobTransaction, ContractInput, Hashed, HyleContract, ProofData, ProofTransaction, StateDigest, TxHash};
+use crate::pending::PendingState;
+
+/// Backoff between attempts of the stages talking to the node
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 5,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(10),
+        }
+    }
+}
+
+/// How long a sent proof is waited for before its settlement is given up
+pub const SETTLE_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Node calls of the pipeline
+#[async_trait]
+pub trait Node: Send + Sync {
+    /// State of `contract_name` as settled by the node
+    async fn contract_state(&self, contract_name: &str) -> anyhow::Result<StateDigest>;
+    async fn send_tx_blob(&self, tx: &BlobTransaction) -> anyhow::Result<TxHash>;
+    async fn send_tx_proof(&self, tx: &ProofTransaction) -> anyhow::Result<TxHash>;
+
+    /// Blob transactions that settled successfully on `contract_name`, in order, skipping the first `from`: the
+    /// contract height counts them. `None` when the node can't list them, `contract_state` being the only source.
+    async fn settled_blobs(&self, _contract_name: &str, _from: u64) -> anyhow::Result<Option<Vec<BlobTransaction>>> {
+        Ok(None)
+    }
+}
+
+#[async_trait]
+pub trait Prover: Send + Sync {
+    async fn prove(&self, inputs: ContractInput) -> anyhow::Result<ProofData>;
+}
+
+/// Stage of the submission that failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    FetchState,
+    SendBlob,
+    Execute,
+    Prove,
+    SendProof,
+    Settle,
+}
+
+#[derive(Debug)]
+pub struct PipelineError {
+    pub stage: Stage,
+    pub error: anyhow::Error,
+}
+
+/// Blob transaction hashed locally but not sent yet, with the state it applies to
+pub struct Prepared {
+    pub state: ImageState,
+    pub blob_tx_hash: TxHash,
+    pub inputs: ContractInput,
+}
+
+/// Action executed locally, as the guest will
+pub struct Executed {
+    pub blob_tx_hash: TxHash,
+    pub program_output: String,
+    pub inputs: ContractInput,
+    /// State once the action is applied
+    pub state: ImageState,
+}
+
+pub struct Proven {
+    pub blob_tx_hash: TxHash,
+    pub program_output: String,
+    pub proof: ProofData,
+}
+
+/// Both transactions of an action accepted by the node
+#[derive(Serialize, Debug, Clone)]
+pub struct Submitted {
+    pub blob_tx_hash: TxHash,
+    pub program_output: String,
+    pub proof_tx_hash: TxHash,
+}
+
+/// Submission of any `ImageAction`: fetch state → execute → send blob → prove → send proof.
+/// Node calls are retried with `retry`, except sending the blob: the node doesn't deduplicate blob transactions,
+/// so an attempt that timed out after reaching it would be sequenced twice. Proving and execution aren't retried.
+/// Actions are executed on the optimistic state left by the actions sent before them, see `PendingState`.
+#[derive(Clone)]
+pub struct Pipeline {
+    pub node: Arc<dyn Node>,
+    pub prover: Arc<dyn Prover>,
+    pub contract_name: String,
+    pub identity: String,
+    pub retry: RetryPolicy,
+    pending: Arc<Mutex<PendingState>>,
+}
+
+impl Pipeline {
+    pub fn new(
+        node: Arc<dyn Node>,
+        prover: Arc<dyn Prover>,
+        contract_name: String,
+        identity: String,
+        retry: RetryPolicy,
+    ) -> Self {
+        Pipeline {
+            node,
+            prover,
+            contract_name,
+            identity,
+            retry,
+            pending: Arc::default(),
+        }
+    }
+
+    /// Last state settled by the node
+    pub async fn fetch_state(&self) -> Result<ImageState, PipelineError> {
+        self.fetch_state_of(&self.contract_name).await
+    }
+
+    /// Last state of `contract_name` settled by the node, decoding any previous state version
+    pub async fn fetch_state_of(&self, contract_name: &str) -> Result<ImageState, PipelineError> {
+        self.with_retry(Stage::FetchState, || self.settled_state_of(contract_name)).await
+    }
+
+    async fn settled_state(&self) -> anyhow::Result<ImageState> {
+        self.settled_state_of(&self.contract_name).await
+    }
+
+    async fn settled_state_of(&self, contract_name: &str) -> anyhow::Result<ImageState> {
+        let state = self.node.contract_state(contract_name).await?;
+        ImageState::try_from(state).map_err(anyhow::Error::msg)
+    }
+
+    /// Executes `action` on the optimistic state and sends its blob once it succeeds, so that a rejected action
+    /// never reaches the node. `journal` gets the executed action before its blob is sent, so that a blob sent
+    /// right before a crash can still be proven.
+    ///
+    /// Holds the pending state throughout, retries of the fetch included, so concurrent actions queue up:
+    /// - the settled state must be fetched under the lock: one fetched before an action sent meanwhile may
+    ///   predate it, and reconciling with it would drop that action as diverged
+    /// - blobs must reach the node in the order their actions are applied, each proving from its predecessor
+    ///
+    /// The blob is sent in a single attempt, so the lock is held for at most one fetch with its retries.
+    pub async fn sequence(
+        &self,
+        action: &ImageAction,
+        private_input: Vec<u8>,
+        journal: impl FnOnce(&Executed),
+    ) -> Result<Executed, PipelineError> {
+        let mut pending = self.pending.lock().await;
+        let base = pending.base(self.fetch_state().await?);
+        let blob_tx = BlobTransaction::new(self.identity.clone(), vec![action.as_blob(&self.contract_name)]);
+        let executed = self.execute(self.prepare(base, &blob_tx, private_input)?)?;
+        journal(&executed);
+        self.send_blob(&blob_tx, &executed.blob_tx_hash).await?;
+        pending.push(
+            executed.blob_tx_hash.clone(),
+            StateDigest(executed.inputs.state.clone()),
+            executed.state.clone(),
+        );
+        Ok(executed)
+    }
+
+    /// Tracks a blob executed again after a restart as pending
+    pub async fn track(&self, executed: &Executed) {
+        self.pending.lock().await.push(
+            executed.blob_tx_hash.clone(),
+            StateDigest(executed.inputs.state.clone()),
+            executed.state.clone(),
+        );
+    }
+
+    /// Forgets the action of `blob_tx_hash` and those sent after it, as it won't settle
+    pub async fn discard(&self, blob_tx_hash: &TxHash) {
+        let mut pending = self.pending.lock().await;
+        let before = pending.len();
+        pending.discard(blob_tx_hash);
+        if pending.len() < before {
+            eprintln!("⚠️ Dropped {} pending action(s) from blob {}", before - pending.len(), blob_tx_hash);
+        }
+    }
+
+    /// Builds the contract input of `blob_tx` on `state`, with the hash the node will give the transaction
+    pub fn prepare(
+        &self,
+        state: ImageState,
+        blob_tx: &BlobTransaction,
+        private_input: Vec<u8>,
+    ) -> Result<Prepared, PipelineError> {
+        let blob_tx_hash = blob_tx.hashed();
+        let inputs = ContractInput {
+            state: state.as_bytes().map_err(|e| PipelineError::new(Stage::Execute, e.into()))?,
+            identity: self.identity.clone().into(),
+            tx_hash: blob_tx_hash.clone(),
+            private_input,
+            tx_ctx: None,
+            blobs: blob_tx.blobs.clone(),
+            index: sdk::BlobIndex(0),
+        };
+        Ok(Prepared {
+            state,
+            blob_tx_hash,
+            inputs,
+        })
+    }
+
+    /// Sends `blob_tx` in a single attempt and checks the node hashed it as `blob_tx_hash`, the hash it was
+    /// executed and will be proven with
+    pub async fn send_blob(&self, blob_tx: &BlobTransaction, blob_tx_hash: &TxHash) -> Result<(), PipelineError> {
+        let sent = self
+            .node
+            .send_tx_blob(blob_tx)
+            .await
+            .map_err(|e| PipelineError::new(Stage::SendBlob, e))?;
+        if sent != *blob_tx_hash {
+            return Err(PipelineError::new(
+                Stage::SendBlob,
+                anyhow::anyhow!("node hashed the blob tx as {}, expected {}", sent, blob_tx_hash),
+            ));
+        }
+        println!("✅ Blob tx sent. Tx hash: {}", sent);
+        Ok(())
+    }
+
+    pub fn execute(&self, prepared: Prepared) -> Result<Executed, PipelineError> {
+        let Prepared {
+            mut state,
+            blob_tx_hash,
+            inputs,
+        } = prepared;
+        let (program_output, _, _) = state
+            .execute(&inputs)
+            .map_err(|e| PipelineError::new(Stage::Execute, anyhow::Error::msg(e)))?;
+        println!("🚀 Executed: {}", program_output);
+        Ok(Executed {
+            blob_tx_hash,
+            program_output,
+            inputs,
+            state,
+        })
+    }
+
+    /// Executes again the inputs of a blob sent earlier, as recorded before a restart
+    pub fn reexecute(&self, blob_tx_hash: TxHash, inputs: ContractInput) -> Result<Executed, PipelineError> {
+        let state = ImageState::from_versioned_bytes(&inputs.state)
+            .map_err(|e| PipelineError::new(Stage::Execute, anyhow::Error::msg(e)))?;
+        self.execute(Prepared {
+            state,
+            blob_tx_hash,
+            inputs,
+        })
+    }
+
+    pub async fn prove(&self, executed: Executed) -> Result<Proven, PipelineError> {
+        let proof = self
+            .prover
+            .prove(executed.inputs)
+            .await
+            .map_err(|e| PipelineError::new(Stage::Prove, e))?;
+        Ok(Proven {
+            blob_tx_hash: executed.blob_tx_hash,
+            program_output: executed.program_output,
+            proof,
+        })
+    }
+
+    pub async fn send_proof(&self, proven: Proven) -> Result<Submitted, PipelineError> {
+        let proof_tx = ProofTransaction {
+            proof: proven.proof,
+            contract_name: self.contract_name.clone().into(),
+        };
+        let proof_tx_hash = self
+            .with_retry(Stage::SendProof, || self.node.send_tx_proof(&proof_tx))
+            .await?;
+        println!("✅ Proof tx sent. Tx hash: {}", proof_tx_hash);
+        Ok(Submitted {
+            blob_tx_hash: proven.blob_tx_hash,
+            program_output: proven.program_output,
+            proof_tx_hash,
+        })
+    }
+
+    /// Waits until the node settled the action of `blob_tx_hash`: the settled state is the one the action leads
+    /// to, or one of the pending actions sent after it. Fails once the settled state diverged from the pending
+    /// actions, as the action can't settle anymore.
+    pub async fn wait_settled(&self, blob_tx_hash: &TxHash) -> Result<(), PipelineError> {
+        let deadline = Instant::now() + SETTLE_TIMEOUT;
+        let mut backoff = self.retry.initial_backoff;
+        loop {
+            match self.settled_state().await {
+                Ok(settled) => {
+                    let mut pending = self.pending.lock().await;
+                    pending.reconcile(&settled);
+                    match pending.outcome(blob_tx_hash) {
+                        Some(true) => return Ok(()),
+                        Some(false) => {
+                            return Err(PipelineError::new(
+                                Stage::Settle,
+                                anyhow::anyhow!(
+                                    "settled state at height {} isn't the one blob {} leads to",
+                                    settled.height,
+                                    blob_tx_hash
+                                ),
+                            ))
+                        }
+                        None if !pending.contains(blob_tx_hash) => {
+                            return Err(PipelineError::new(
+                                Stage::Settle,
+                                anyhow::anyhow!("blob {} isn't pending", blob_tx_hash),
+                            ))
+                        }
+                        None => {}
+                    }
+                }
+                Err(error) => eprintln!("⚠️ {} failed: {:#}, retrying in {:?}", Stage::Settle, error, backoff),
+            }
+            if Instant::now() + backoff > deadline {
+                return Err(PipelineError::new(
+                    Stage::Settle,
+                    anyhow::anyhow!("blob {} not settled after {:?}", blob_tx_hash, SETTLE_TIMEOUT),
+                ));
+            }
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(self.retry.max_backoff);
+        }
+    }
+
+    /// Runs every stage of the submission of `action`
+    pub async fn submit(&self, action: ImageAction, private_input: Vec<u8>) -> Result<Submitted, PipelineError> {
+        let executed = self.sequence(&action, private_input, |_| {}).await?;
+        let blob_tx_hash = executed.blob_tx_hash.clone();
+        let submitted = match self.prove(executed).await {
+            Ok(proven) => self.send_proof(proven).await,
+            Err(error) => Err(error),
+        };
+        if submitted.is_err() {
+            self.discard(&blob_tx_hash).await;
+        }
+        submitted
+    }
+
+    async fn with_retry<T, F, Fut>(&self, stage: Stage, mut call: F) -> Result<T, PipelineError>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = anyhow::Result<T>>,
+    {
+        let mut backoff = self.retry.initial_backoff;
+        let mut attempt = 1;
+        loop {
+            match call().await {
+                Ok(value) => return Ok(value),
+                Err(error) if attempt >= self.retry.max_attempts => return Err(PipelineError::new(stage, error)),
+                Err(error) => {
+                    eprintln!("⚠️ {} failed (attempt {}): {:#}, retrying in {:?}", stage, attempt, error, backoff);
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(self.retry.max_backoff);
+                    attempt += 1;
+                }
+            }
+        }
+    }
+}
+
+impl PipelineError {
+    pub fn new(stage: Stage, error: anyhow::Error) -> Self {
+        PipelineError { stage, error }
+    }
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Stage::FetchState => write!(f, "fetch state"),
+            Stage::SendBlob => write!(f, "send blob"),
+            Stage::Execute => write!(f, "execute"),
+            Stage::Prove => write!(f, "prove"),
+            Stage::SendProof => write!(f, "send proof"),
+            Stage::Settle => write!(f, "settle"),
+        }
+    }
+}
+
+impl fmt::Display for PipelineError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} failed: {:#}", self.stage, self.error)
+    }
+}
+
+impl std::error::Error for PipelineError {}
+
+/// The node's REST API doesn't list the transactions settled on a contract, so `settled_blobs` keeps its default
+#[async_trait]
+impl Node for NodeApiHttpClient {
+    async fn contract_state(&self, contract_name: &str) -> anyhow::Result<StateDigest> {
+        Ok(self.get_contract(&contract_name.into()).await?.state)
+    }
+
+    async fn send_tx_blob(&self, tx: &BlobTransaction) -> anyhow::Result<TxHash> {
+        NodeApiHttpClient::send_tx_blob(self, tx).await
+    }
+
+    async fn send_tx_proof(&self, tx: &ProofTransaction) -> anyhow::Result<TxHash> {
+        NodeApiHttpClient::send_tx_proof(self, tx).await
+    }
+}
+
+#[async_trait]
+impl Prover for Risc0Prover<'static> {
+    async fn prove(&self, inputs: ContractInput) -> anyhow::Result<ProofData> {
+        Risc0Prover::prove(self, inputs).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex as StdMutex;
+    use contract::hash;
+    use contract::ownership::{MultiSignature, OwnerKeys};
+    use sdk::Digestable;
+    use super::*;
+
+    /// Node applying the proofs it received when told to `settle`, if they prove from its settled state
+    #[derive(Default)]
+    struct FakeNode {
+        settled: StdMutex<ImageState>,
+        blobs: StdMutex<Vec<TxHash>>,
+        proofs: StdMutex<Vec<ContractInput>>,
+        refuse_blobs: bool,
+        /// Fetches failing before one succeeds
+        failed_fetches: StdMutex<u32>,
+        /// Delay of every node call, for concurrent actions to interleave
+        latency: Duration,
+    }
+
+    impl FakeNode {
+        fn settle(&self) {
+            let mut settled = self.settled.lock().unwrap();
+            for inputs in self.proofs.lock().unwrap().drain(..) {
+                if inputs.state == settled.as_digest().0 {
+                    settled.execute(&inputs).unwrap();
+                }
+            }
+        }
+    }
+
+    #[async_trait]
+    impl Node for FakeNode {
+        async fn contract_state(&self, _contract_name: &str) -> anyhow::Result<StateDigest> {
+            tokio::time::sleep(self.latency).await;
+            {
+                let mut failed_fetches = self.failed_fetches.lock().unwrap();
+                if *failed_fetches > 0 {
+                    *failed_fetches -= 1;
+                    anyhow::bail!("timed out");
+                }
+            }
+            Ok(self.settled.lock().unwrap().as_digest())
+        }
+
+        async fn send_tx_blob(&self, tx: &BlobTransaction) -> anyhow::Result<TxHash> {
+            tokio::time::sleep(self.latency).await;
+            self.blobs.lock().unwrap().push(tx.hashed());
+            if self.refuse_blobs {
+                anyhow::bail!("connection reset");
+            }
+            Ok(tx.hashed())
+        }
+
+        async fn send_tx_proof(&self, tx: &ProofTransaction) -> anyhow::Result<TxHash> {
+            let inputs: ContractInput = borsh::from_slice(&tx.proof.0)?;
+            let proof_tx_hash = TxHash(format!("proof-of-{}", inputs.tx_hash));
+            self.proofs.lock().unwrap().push(inputs);
+            Ok(proof_tx_hash)
+        }
+    }
+
+    /// "Proves" by encoding the inputs, for `FakeNode` to execute them
+    struct FakeProver;
+
+    #[async_trait]
+    impl Prover for FakeProver {
+        async fn prove(&self, inputs: ContractInput) -> anyhow::Result<ProofData> {
+            Ok(ProofData(borsh::to_vec(&inputs)?))
+        }
+    }
+
+    fn pipeline(node: &Arc<FakeNode>) -> Pipeline {
+        let retry = RetryPolicy {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(1),
+        };
+        Pipeline::new(node.clone(), Arc::new(FakeProver), "image_registry".into(), "none.image_registry".into(), retry)
+    }
+
+    fn register(file: &str) -> ImageAction {
+        ImageAction::RegisterImage {
+            image_hash: hash::file_hash(file.as_bytes()),
+            canonical_hash: None,
+            perceptual_hash: None,
+            image_signature: String::new(),
+            owner_pk: OwnerKeys::single("owner".into()),
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_actions_chain_in_the_order_their_blobs_are_sent() {
+        let node = Arc::new(FakeNode {
+            // Fewer than `max_attempts`, so the first action to fetch retries while the others wait
+            failed_fetches: StdMutex::new(2),
+            latency: Duration::from_millis(2),
+            ..FakeNode::default()
+        });
+        let pipeline = pipeline(&node);
+        let tasks = (0..8).map(|i| {
+            let pipeline = pipeline.clone();
+            tokio::spawn(async move { pipeline.sequence(&register(&format!("file-{}", i)), vec![], |_| {}).await })
+        });
+        let mut executed = vec![];
+        for task in tasks.collect::<Vec<_>>() {
+            executed.push(task.await.unwrap().unwrap());
+        }
+        assert_eq!(*node.failed_fetches.lock().unwrap(), 0);
+
+        // Each action executed on the output of the one whose blob was sent right before it
+        executed.sort_by_key(|executed| executed.state.height);
+        let sent: Vec<TxHash> = executed.iter().map(|executed| executed.blob_tx_hash.clone()).collect();
+        assert_eq!(*node.blobs.lock().unwrap(), sent);
+        assert_eq!(executed[0].inputs.state, ImageState::default().as_digest().0);
+        for (previous, next) in executed.iter().zip(&executed[1..]) {
+            assert_eq!(next.inputs.state, previous.state.as_digest().0);
+        }
+        assert_eq!(executed.last().unwrap().state.height, 8);
+        assert_eq!(pipeline.pending.lock().await.len(), 8);
+
+        for executed in executed {
+            pipeline.send_proof(pipeline.prove(executed).await.unwrap()).await.unwrap();
+        }
+        node.settle();
+        assert_eq!(node.settled.lock().unwrap().height, 8);
+        for blob_tx_hash in &sent {
+            pipeline.wait_settled(blob_tx_hash).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn rejected_actions_fail_before_the_blob() {
+        let node = Arc::new(FakeNode::default());
+        let pipeline = pipeline(&node);
+        // Without admin keys, nobody can pause the contract
+        let pause = ImageAction::SetPaused {
+            paused: true,
+            admin_signature: MultiSignature(vec![]),
+        };
+        let error = pipeline.sequence(&pause, vec![], |_| panic!("journaled a rejected action")).await.err().unwrap();
+        assert_eq!(error.stage, Stage::Execute);
+        assert!(node.blobs.lock().unwrap().is_empty());
+        assert_eq!(pipeline.pending.lock().await.len(), 0);
+
+        let edit = ImageAction::AddPublisher {
+            original_image_hash: hash::file_hash(b"unknown"),
+            original_image_signature: MultiSignature(vec![]),
+            publisher_pk: "publisher".into(),
+        };
+        let error = pipeline.submit(edit, vec![]).await.err().unwrap();
+        assert_eq!(error.stage, Stage::Execute);
+        assert_eq!(node.settled.lock().unwrap().height, 0);
+    }
+
+    #[tokio::test]
+    async fn sends_a_blob_once() {
+        let node = Arc::new(FakeNode {
+            refuse_blobs: true,
+            ..FakeNode::default()
+        });
+        let pipeline = pipeline(&node);
+        let error = pipeline.sequence(&register("a"), vec![], |_| {}).await.err().unwrap();
+        assert_eq!(error.stage, Stage::SendBlob);
+        assert_eq!(node.blobs.lock().unwrap().len(), 1);
+        assert_eq!(pipeline.pending.lock().await.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn journals_before_sending_the_blob() {
+        let node = Arc::new(FakeNode::default());
+        let pipeline = pipeline(&node);
+        let mut journaled = None;
+        let executed = pipeline
+            .sequence(&register("a"), vec![], |executed| {
+                journaled = Some((executed.blob_tx_hash.clone(), node.blobs.lock().unwrap().len()))
+            })
+            .await
+            .unwrap();
+        assert_eq!(journaled, Some((executed.blob_tx_hash.clone(), 0)));
+        assert_eq!(*node.blobs.lock().unwrap(), vec![executed.blob_tx_hash]);
+    }
+
+    #[tokio::test]
+    async fn settles_actions_applied_between_polls() {
+        let node = Arc::new(FakeNode::default());
+        let pipeline = pipeline(&node);
+        let first = pipeline.submit(register("a"), vec![]).await.unwrap();
+        let second = pipeline.submit(register("b"), vec![]).await.unwrap();
+        node.settle();
+        assert_eq!(node.settled.lock().unwrap().height, 2);
+
+        pipeline.wait_settled(&first.blob_tx_hash).await.unwrap();
+        pipeline.wait_settled(&second.blob_tx_hash).await.unwrap();
+        assert_eq!(pipeline.pending.lock().await.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn fails_settlement_once_the_state_diverged() {
+        let node = Arc::new(FakeNode::default());
+        let pipeline = pipeline(&node);
+        let submitted = pipeline.submit(register("a"), vec![]).await.unwrap();
+        // Another host's action settles first, at the height the pending action would have reached
+        node.settled.lock().unwrap().height += 1;
+        node.settle();
+
+        let error = pipeline.wait_settled(&submitted.blob_tx_hash).await.err().unwrap();
+        assert_eq!(error.stage, Stage::Settle);
+        let executed = pipeline.sequence(&register("b"), vec![], |_| {}).await.unwrap();
+        assert_eq!(executed.inputs.state, node.settled.lock().unwrap().as_digest().0);
+    }
+}
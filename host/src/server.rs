@@ -0,0 +1,332 @@
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use anyhow::{bail, Context, Result};
+use clap::Args;
+use serde::Deserialize;
+use warp::http::uri::{Authority, Scheme};
+use warp::http::Method;
+use warp::{Filter, Rejection, Reply};
+use crate::api;
+use crate::auth::Auth;
+
+pub const DEFAULT_BIND: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+pub const DEFAULT_PORT: u16 = 3030;
+/// Fits a `RegisterProvenEdit` submission, whose private input carries both image files
+pub const DEFAULT_MAX_BODY_BYTES: u64 = 32 * 1024 * 1024;
+
+/// Listening and HTTP policy of the server. Flags and their environment variables override the `--config` file,
+/// which overrides the defaults.
+#[derive(Args, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct ServerOptions {
+    /// JSON file with any of the options below, e.g. `{"bind": "0.0.0.0", "port": 8443}`
+    #[arg(long, env = "SERVER_CONFIG")]
+    #[serde(skip)]
+    config: Option<PathBuf>,
+    /// Address to listen on [default: 127.0.0.1]
+    #[arg(long, env = "SERVER_BIND")]
+    bind: Option<IpAddr>,
+    /// Port to listen on [default: 3030]
+    #[arg(long, env = "SERVER_PORT")]
+    port: Option<u16>,
+    /// PEM certificate chain, served over HTTPS along with `--tls-key`
+    #[arg(long, env = "SERVER_TLS_CERT")]
+    tls_cert: Option<PathBuf>,
+    /// PEM private key of the certificate
+    #[arg(long, env = "SERVER_TLS_KEY")]
+    tls_key: Option<PathBuf>,
+    /// Origins allowed by CORS, comma separated, or `*` for any [default: none]
+    #[arg(long, env = "SERVER_CORS_ORIGINS", value_delimiter = ',')]
+    cors_origins: Option<Vec<String>>,
+    /// Methods allowed by CORS, comma separated [default: GET,POST]
+    #[arg(long, env = "SERVER_CORS_METHODS", value_delimiter = ',')]
+    cors_methods: Option<Vec<String>>,
+    /// Largest request body, in bytes [default: 32 MiB]
+    #[arg(long, env = "SERVER_MAX_BODY_BYTES")]
+    max_body_bytes: Option<u64>,
+}
+
+pub struct Tls {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+/// Resolved `ServerOptions`
+pub struct ServerConfig {
+    pub addr: SocketAddr,
+    pub tls: Option<Tls>,
+    /// `None` allows any origin, an empty list none
+    pub cors_origins: Option<Vec<String>>,
+    pub cors_methods: Vec<Method>,
+    pub max_body_bytes: u64,
+}
+
+impl ServerOptions {
+    pub fn resolve(self) -> Result<ServerConfig> {
+        let file = match &self.config {
+            Some(path) => {
+                let text = std::fs::read_to_string(path).with_context(|| format!("Could not read {}", path.display()))?;
+                serde_json::from_str(&text).with_context(|| format!("Invalid server config {}", path.display()))?
+            }
+            None => ServerOptions::default(),
+        };
+
+        let tls = match (self.tls_cert.or(file.tls_cert), self.tls_key.or(file.tls_key)) {
+            (Some(cert), Some(key)) => Some(Tls { cert, key }),
+            (None, None) => None,
+            _ => bail!("TLS needs both a certificate and a key"),
+        };
+        let cors_origins = match self.cors_origins.or(file.cors_origins) {
+            Some(origins) if origins.iter().any(|origin| origin == "*") => None,
+            Some(origins) => {
+                origins.iter().try_for_each(|origin| validate_origin(origin))?;
+                Some(origins)
+            }
+            // Cross-origin access is opt-in
+            None => Some(vec![]),
+        };
+        let cors_methods = match self.cors_methods.or(file.cors_methods) {
+            Some(methods) => methods
+                .iter()
+                .map(|method| Method::from_str(&method.to_uppercase()).with_context(|| format!("Invalid method '{}'", method)))
+                .collect::<Result<_>>()?,
+            None => vec![Method::GET, Method::POST],
+        };
+        Ok(ServerConfig {
+            addr: SocketAddr::new(
+                self.bind.or(file.bind).unwrap_or(DEFAULT_BIND),
+                self.port.or(file.port).unwrap_or(DEFAULT_PORT),
+            ),
+            tls,
+            cors_origins,
+            cors_methods,
+            max_body_bytes: self.max_body_bytes.or(file.max_body_bytes).unwrap_or(DEFAULT_MAX_BODY_BYTES),
+        })
+    }
+}
+
+/// `<scheme>://<host>[:<port>]`, the only form warp accepts
+fn validate_origin(origin: &str) -> Result<()> {
+    let valid = origin
+        .split_once("://")
+        .is_some_and(|(scheme, rest)| Scheme::from_str(scheme).is_ok() && Authority::from_str(rest).is_ok());
+    if !valid {
+        bail!("Invalid CORS origin '{}', expected <scheme>://<host>[:<port>]", origin);
+    }
+    Ok(())
+}
+
+impl ServerConfig {
+    /// Keys of `auth_file`. Without one every request is accepted, which is only allowed on a loopback address
+    /// unless `no_auth` is set.
+    pub fn auth(&self, auth_file: Option<&Path>, no_auth: bool) -> Result<Auth> {
+        match auth_file {
+            Some(path) => Auth::load(path),
+            None if no_auth || self.addr.ip().is_loopback() => {
+                eprintln!("⚠️ No --auth-file given, every request is accepted");
+                Ok(Auth::disabled())
+            }
+            None => bail!(
+                "Refusing to serve {} without authentication: pass --auth-file, or --no-auth to accept anyone",
+                self.addr
+            ),
+        }
+    }
+
+    /// Applies the CORS policy to `routes`. Requests it forbids are rejected outside of the routes' own
+    /// `recover`, so they are recovered again to reply an `ApiError` too.
+    pub fn with_cors<F>(&self, routes: F) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone
+    where
+        F: Filter<Error = Rejection> + Clone + Send + Sync + 'static,
+        F::Extract: Reply,
+    {
+        routes.with(self.cors()).recover(api::recover)
+    }
+
+    pub fn cors(&self) -> warp::cors::Builder {
+        let cors = warp::cors()
+            .allow_methods(self.cors_methods.clone())
+            .allow_headers(vec!["Content-Type", "Authorization"]);
+        match &self.cors_origins {
+            Some(origins) => cors.allow_origins(origins.iter().map(String::as_str)),
+            None => cors.allow_any_origin(),
+        }
+    }
+
+    /// Serves `routes` over HTTPS when a certificate is configured, HTTP otherwise, until Ctrl-C
+    pub async fn serve<F>(&self, routes: F) -> Result<()>
+    where
+        F: Filter<Error = Rejection> + Clone + Send + Sync + 'static,
+        F::Extract: Reply,
+    {
+        let shutdown = async {
+            tokio::signal::ctrl_c().await.ok();
+        };
+        let server = warp::serve(routes);
+        match &self.tls {
+            Some(tls) => {
+                let (addr, serving) = server
+                    .tls()
+                    .cert_path(&tls.cert)
+                    .key_path(&tls.key)
+                    .try_bind_with_graceful_shutdown(self.addr, shutdown)
+                    .context("Could not start the HTTPS server")?;
+                println!("🚀 Serving on https://{}", addr);
+                serving.await;
+            }
+            None => {
+                let (addr, serving) = server
+                    .try_bind_with_graceful_shutdown(self.addr, shutdown)
+                    .context("Could not start the server")?;
+                println!("🚀 Serving on http://{}", addr);
+                serving.await;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use serde_json::json;
+
+    #[derive(Parser)]
+    struct Cli {
+        #[command(flatten)]
+        options: ServerOptions,
+    }
+
+    fn resolve(args: &[&str]) -> Result<ServerConfig> {
+        Cli::try_parse_from(std::iter::once("server").chain(args.iter().copied()))?.options.resolve()
+    }
+
+    fn temp_file(name: &str, contents: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("server-{}-{}.json", name, std::process::id()));
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    fn config(addr: &str) -> ServerConfig {
+        ServerConfig {
+            addr: addr.parse().unwrap(),
+            tls: None,
+            cors_origins: Some(vec![]),
+            cors_methods: vec![Method::GET, Method::POST],
+            max_body_bytes: DEFAULT_MAX_BODY_BYTES,
+        }
+    }
+
+    #[test]
+    fn defaults_listen_on_loopback_without_cors() {
+        let config = resolve(&[]).unwrap();
+        assert_eq!(config.addr, SocketAddr::new(DEFAULT_BIND, DEFAULT_PORT));
+        assert!(config.tls.is_none());
+        assert_eq!(config.cors_origins, Some(vec![]));
+        assert_eq!(config.cors_methods, vec![Method::GET, Method::POST]);
+        assert_eq!(config.max_body_bytes, DEFAULT_MAX_BODY_BYTES);
+    }
+
+    // The only test touching the environment, so that tests running in parallel don't see its variables
+    #[test]
+    fn flags_override_the_environment_which_overrides_the_file() {
+        let file = temp_file(
+            "precedence",
+            &json!({"bind": "0.0.0.0", "port": 8000, "max_body_bytes": 1024, "cors_methods": ["put"]}).to_string(),
+        );
+        let file = file.to_str().unwrap();
+
+        let config = resolve(&["--config", file]).unwrap();
+        assert_eq!(config.addr, "0.0.0.0:8000".parse().unwrap());
+        assert_eq!(config.max_body_bytes, 1024);
+        assert_eq!(config.cors_methods, vec![Method::PUT]);
+
+        std::env::set_var("SERVER_PORT", "9000");
+        std::env::set_var("SERVER_MAX_BODY_BYTES", "2048");
+        let from_env = resolve(&["--config", file]);
+        let from_flags = resolve(&["--config", file, "--port", "9500", "--bind", "127.0.0.2"]);
+        std::env::remove_var("SERVER_PORT");
+        std::env::remove_var("SERVER_MAX_BODY_BYTES");
+
+        let config = from_env.unwrap();
+        assert_eq!(config.addr, "0.0.0.0:9000".parse().unwrap());
+        assert_eq!(config.max_body_bytes, 2048);
+        let config = from_flags.unwrap();
+        assert_eq!(config.addr, "127.0.0.2:9500".parse().unwrap());
+        assert_eq!(config.max_body_bytes, 2048);
+        assert_eq!(config.cors_methods, vec![Method::PUT]);
+    }
+
+    #[test]
+    fn invalid_files_are_rejected() {
+        let unknown = temp_file("unknown", &json!({"prot": 8000}).to_string());
+        assert!(resolve(&["--config", unknown.to_str().unwrap()]).is_err());
+        let config = temp_file("config", &json!({"config": "other.json"}).to_string());
+        assert!(resolve(&["--config", config.to_str().unwrap()]).is_err());
+        let missing = std::env::temp_dir().join(format!("server-missing-{}.json", std::process::id()));
+        assert!(resolve(&["--config", missing.to_str().unwrap()]).is_err());
+    }
+
+    #[test]
+    fn cors_is_opt_in() {
+        let config = resolve(&["--cors-origins", "https://a.example,http://localhost:8080"]).unwrap();
+        assert_eq!(
+            config.cors_origins,
+            Some(vec!["https://a.example".to_string(), "http://localhost:8080".to_string()])
+        );
+        assert_eq!(resolve(&["--cors-origins", "https://a.example,*"]).unwrap().cors_origins, None);
+        assert!(resolve(&["--cors-origins", "a.example"]).is_err());
+        assert!(resolve(&["--cors-origins", "https://"]).is_err());
+        assert!(resolve(&["--cors-methods", "get,not a method"]).is_err());
+    }
+
+    #[test]
+    fn tls_needs_both_a_certificate_and_a_key() {
+        let config = resolve(&["--tls-cert", "cert.pem", "--tls-key", "key.pem"]).unwrap();
+        let tls = config.tls.unwrap();
+        assert_eq!((tls.cert, tls.key), (PathBuf::from("cert.pem"), PathBuf::from("key.pem")));
+        assert!(resolve(&["--tls-cert", "cert.pem"]).is_err());
+        assert!(resolve(&["--tls-key", "key.pem"]).is_err());
+    }
+
+    #[test]
+    fn serving_without_auth_is_refused_unless_on_loopback_or_explicit() {
+        assert!(!config("127.0.0.1:3030").auth(None, false).unwrap().enabled());
+        assert!(!config("[::1]:3030").auth(None, false).unwrap().enabled());
+        assert!(config("0.0.0.0:3030").auth(None, false).is_err());
+        assert!(!config("0.0.0.0:3030").auth(None, true).unwrap().enabled());
+
+        let keys = json!({"api_keys": [{"name": "ci", "key": "secret", "scopes": ["read"]}]});
+        let file = temp_file("auth", &keys.to_string());
+        assert!(config("0.0.0.0:3030").auth(Some(&file), false).unwrap().enabled());
+        assert!(config("0.0.0.0:3030").auth(Some(Path::new("missing.json")), true).is_err());
+    }
+
+    #[tokio::test]
+    async fn forbidden_origins_get_an_api_error() {
+        let mut config = config("127.0.0.1:3030");
+        config.cors_origins = Some(vec!["https://a.example".to_string()]);
+        let routes = config.with_cors(warp::path("ping").map(|| "pong"));
+
+        let allowed = warp::test::request()
+            .path("/ping")
+            .header("Origin", "https://a.example")
+            .reply(&routes)
+            .await;
+        assert_eq!(allowed.status(), 200);
+        assert_eq!(allowed.headers()["access-control-allow-origin"], "https://a.example");
+
+        let forbidden = warp::test::request()
+            .method("OPTIONS")
+            .path("/ping")
+            .header("Origin", "https://b.example")
+            .header("Access-Control-Request-Method", "GET")
+            .reply(&routes)
+            .await;
+        assert_eq!(forbidden.status(), 403);
+        let body: serde_json::Value = serde_json::from_slice(forbidden.body()).unwrap();
+        assert_eq!(body["code"], "forbidden");
+    }
+}
@@ -0,0 +1,117 @@
+use std::collections::HashMap;
+
+use contract::transform::RawImage;
+use contract::hash::ContentHash;
+use contract::ImageState;
+use schemars::JsonSchema;
+use serde::Serialize;
+
+/// Default Hamming distance under which two dHashes are considered the same picture
+pub const DEFAULT_MAX_DISTANCE: u32 = 10;
+
+/// 64-bit difference hash: the image is reduced to a 9x8 grayscale grid and every bit tells whether
+/// a cell is darker than its right neighbour. It survives resizing and recompression.
+pub fn dhash(image: &RawImage) -> u64 {
+    let mut sums = [[0u64; 9]; 8];
+    let mut counts = [[0u64; 9]; 8];
+    for y in 0..image.height as usize {
+        let row = y * 8 / image.height as usize;
+        for x in 0..image.width as usize {
+            let col = x * 9 / image.width as usize;
+            let p = &image.pixels[(y * image.width as usize + x) * 4..][..4];
+            sums[row][col] += (299 * p[0] as u64 + 587 * p[1] as u64 + 114 * p[2] as u64) / 1000;
+            counts[row][col] += 1;
+        }
+    }
+    let mut hash = 0u64;
+    for row in 0..8 {
+        for col in 0..8 {
+            // Compare means without dividing: a/b < c/d <=> a*d < c*b
+            let left = sums[row][col] * counts[row][col + 1].max(1);
+            let right = sums[row][col + 1] * counts[row][col].max(1);
+            hash = (hash << 1) | (left < right) as u64;
+        }
+    }
+    hash
+}
+
+#[derive(Serialize, JsonSchema, Debug, Clone)]
+pub struct SimilarImage {
+    pub image_hash: ContentHash,
+    pub distance: u32,
+}
+
+struct Node {
+    perceptual_hash: u64,
+    images: Vec<ContentHash>,
+    children: HashMap<u32, Node>,
+}
+
+/// BK-tree over perceptual hashes, searched by Hamming distance
+#[derive(Default)]
+pub struct SimilarityIndex {
+    root: Option<Node>,
+}
+
+impl SimilarityIndex {
+    /// Indexes every registered original that carries a perceptual hash
+    pub fn from_state(state: &ImageState) -> Self {
+        let mut index = SimilarityIndex::default();
+        for (image_hash, metadata) in &state.hash_map {
+            if let (true, Some(perceptual_hash)) = (metadata.is_root, metadata.perceptual_hash) {
+                index.insert(perceptual_hash, *image_hash);
+            }
+        }
+        index
+    }
+
+    pub fn insert(&mut self, perceptual_hash: u64, image_hash: ContentHash) {
+        let mut node = match &mut self.root {
+            Some(root) => root,
+            None => {
+                self.root = Some(Node {
+                    perceptual_hash,
+                    images: vec![image_hash],
+                    children: HashMap::new(),
+                });
+                return;
+            }
+        };
+        loop {
+            let distance = (node.perceptual_hash ^ perceptual_hash).count_ones();
+            if distance == 0 {
+                node.images.push(image_hash);
+                return;
+            }
+            node = node.children.entry(distance).or_insert_with(|| Node {
+                perceptual_hash,
+                images: vec![],
+                children: HashMap::new(),
+            });
+            if node.perceptual_hash == perceptual_hash && node.images.is_empty() {
+                node.images.push(image_hash);
+                return;
+            }
+        }
+    }
+
+    /// Registered images within `max_distance` of `perceptual_hash`, closest first
+    pub fn search(&self, perceptual_hash: u64, max_distance: u32) -> Vec<SimilarImage> {
+        let mut found = vec![];
+        let mut stack: Vec<&Node> = self.root.iter().collect();
+        while let Some(node) = stack.pop() {
+            let distance = (node.perceptual_hash ^ perceptual_hash).count_ones();
+            if distance <= max_distance {
+                found.extend(node.images.iter().map(|image_hash| SimilarImage {
+                    image_hash: *image_hash,
+                    distance,
+                }));
+            }
+            // Triangle inequality: only children at distance d ± max_distance can match
+            let range = distance.saturating_sub(max_distance)..=distance + max_distance;
+            stack.extend(node.children.iter().filter(|(d, _)| range.contains(d)).map(|(_, child)| child));
+        }
+        found.sort_by(|a, b| a.distance.cmp(&b.distance).then_with(|| a.image_hash.cmp(&b.image_hash)));
+        found
+    }
+}
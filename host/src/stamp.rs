@@ -0,0 +1,306 @@
+use anyhow::{anyhow, bail, Context, Result};
+use contract::hash::ContentHash;
+
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+/// iTXt keyword under which PNG files carry XMP
+const PNG_XMP_KEYWORD: &[u8] = b"XML:com.adobe.xmp";
+/// Header of the APP1 segment carrying XMP in JPEG files
+const JPEG_XMP_HEADER: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+const XMP_NAMESPACE: &str = "https://github.com/BlockChainSthyle/Sthyle-risc0/ns/1.0/";
+/// Start of the `rdf:Description` holding a stamp, as written by `to_description`
+const STAMP_DESCRIPTION: &str = "<rdf:Description rdf:about=\"\" xmlns:sthyle=";
+
+/// Where to verify a file, written into its XMP metadata
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Stamp {
+    pub contract_name: String,
+    /// Registered hash of the file before stamping
+    pub image_hash: ContentHash,
+    /// Original the image descends from
+    pub root_hash: ContentHash,
+    /// Transaction that registered the image
+    pub tx_hash: Option<String>,
+}
+
+/// Writes `stamp` as XMP into a JPEG (APP1 segment) or a PNG (iTXt chunk). XMP readers expect a single packet, so
+/// the stamp is merged into the packet the file already has, replacing any previous stamp.
+/// Only metadata is touched, so the canonical pixel hash of the file is unchanged.
+pub fn write(bytes: &[u8], stamp: &Stamp) -> Result<Vec<u8>> {
+    if bytes.starts_with(PNG_SIGNATURE) {
+        let chunks = png_chunks(bytes)?;
+        let existing = chunks.iter().position(|(_, chunk)| png_xmp(chunk).is_some());
+        let xmp = merge(existing.and_then(|i| png_xmp(chunks[i].1)), stamp)?;
+        let mut data = PNG_XMP_KEYWORD.to_vec();
+        // Null separator, uncompressed, empty language tag and translated keyword
+        data.extend_from_slice(&[0, 0, 0, 0, 0]);
+        data.extend_from_slice(xmp.as_bytes());
+        let itxt = png_chunk(b"iTXt", &data);
+
+        let mut out = PNG_SIGNATURE.to_vec();
+        for (i, (kind, chunk)) in chunks.iter().enumerate() {
+            if existing == Some(i) {
+                out.extend_from_slice(&itxt);
+                continue;
+            }
+            if existing.is_none() && *kind == b"IEND" {
+                out.extend_from_slice(&itxt);
+            }
+            // Stamp packets written next to the file's own packet by earlier versions
+            if !is_stamp(png_xmp(chunk)) {
+                out.extend_from_slice(chunk);
+            }
+        }
+        Ok(out)
+    } else if bytes.starts_with(&[0xff, 0xd8]) {
+        let (headers, rest) = jpeg_headers(bytes)?;
+        let existing = headers.iter().position(|header| jpeg_xmp(header).is_some());
+        let xmp = merge(existing.and_then(|i| jpeg_xmp(headers[i])), stamp)?;
+        let mut payload = JPEG_XMP_HEADER.to_vec();
+        payload.extend_from_slice(xmp.as_bytes());
+        if payload.len() + 2 > u16::MAX as usize {
+            bail!("XMP packet is too large for a JPEG segment");
+        }
+        let mut segment = vec![0xff, 0xe1];
+        segment.extend_from_slice(&((payload.len() + 2) as u16).to_be_bytes());
+        segment.extend_from_slice(&payload);
+
+        // In place of the existing packet, or after SOI and any JFIF/EXIF header segments, as XMP readers expect
+        let mut out = bytes[..2].to_vec();
+        for (i, header) in headers.iter().enumerate() {
+            if existing == Some(i) {
+                out.extend_from_slice(&segment);
+            } else if !is_stamp(jpeg_xmp(header)) {
+                out.extend_from_slice(header);
+            }
+        }
+        if existing.is_none() {
+            out.extend_from_slice(&segment);
+        }
+        out.extend_from_slice(rest);
+        Ok(out)
+    } else {
+        bail!("Only JPEG and PNG files can be stamped")
+    }
+}
+
+/// Stamp of a JPEG or PNG file, if it has one
+pub fn read(bytes: &[u8]) -> Result<Option<Stamp>> {
+    let packets: Vec<&[u8]> = if bytes.starts_with(PNG_SIGNATURE) {
+        png_chunks(bytes)?.into_iter().filter_map(|(_, chunk)| png_xmp(chunk)).collect()
+    } else if bytes.starts_with(&[0xff, 0xd8]) {
+        jpeg_headers(bytes)?.0.into_iter().filter_map(jpeg_xmp).collect()
+    } else {
+        bail!("Only JPEG and PNG files can be inspected")
+    };
+    let Some(packet) = packets.into_iter().find(|p| is_stamp(Some(p))) else {
+        return Ok(None);
+    };
+    let xmp = String::from_utf8_lossy(packet);
+    let field = |name: &str| xmp_attribute(&xmp, name);
+    let (Some(contract_name), Some(image_hash), Some(root_hash)) =
+        (field("ContractName"), field("ImageHash"), field("RootHash"))
+    else {
+        bail!("Incomplete provenance stamp");
+    };
+    Ok(Some(Stamp {
+        contract_name,
+        image_hash: image_hash.parse().map_err(anyhow::Error::msg)?,
+        root_hash: root_hash.parse().map_err(anyhow::Error::msg)?,
+        tx_hash: field("TxHash"),
+    }))
+}
+
+/// XMP packet carrying `stamp`: the file's `packet` with its previous stamp swapped for this one, or a new packet
+fn merge(packet: Option<&[u8]>, stamp: &Stamp) -> Result<String> {
+    let Some(packet) = packet else {
+        return Ok(format!(
+            r#"<?xpacket begin="" id="W5M0MpCehiHzreSzNTczkc9d"?>
+<x:xmpmeta xmlns:x="adobe:ns:meta/">
+ <rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+  {}
+ </rdf:RDF>
+</x:xmpmeta>
+<?xpacket end="r"?>"#,
+            to_description(stamp)
+        ));
+    };
+    let mut xmp = String::from_utf8(packet.to_vec()).context("XMP packet of the file is not UTF-8")?;
+    if let Some(start) = xmp.find(STAMP_DESCRIPTION) {
+        let mut end = start + xmp[start..].find("/>").ok_or(anyhow!("Unterminated provenance stamp"))? + 2;
+        end += xmp[end..].len() - xmp[end..].trim_start().len();
+        xmp.replace_range(start..end, "");
+    }
+    let end = xmp.rfind("</rdf:RDF>").ok_or(anyhow!("XMP packet of the file has no rdf:RDF element"))?;
+    xmp.insert_str(end, &format!("{}\n ", to_description(stamp)));
+    Ok(xmp)
+}
+
+fn to_description(stamp: &Stamp) -> String {
+    let tx_hash = stamp
+        .tx_hash
+        .as_ref()
+        .map(|tx| format!("\n    sthyle:TxHash=\"{}\"", escape(tx)))
+        .unwrap_or_default();
+    format!(
+        r#"{}"{}"
+    sthyle:ContractName="{}"
+    sthyle:ImageHash="{}"
+    sthyle:RootHash="{}"{}/>"#,
+        STAMP_DESCRIPTION,
+        XMP_NAMESPACE,
+        escape(&stamp.contract_name),
+        stamp.image_hash,
+        stamp.root_hash,
+        tx_hash
+    )
+}
+
+fn is_stamp(xmp: Option<&[u8]>) -> bool {
+    xmp.is_some_and(|xmp| String::from_utf8_lossy(xmp).contains(XMP_NAMESPACE))
+}
+
+fn xmp_attribute(xmp: &str, name: &str) -> Option<String> {
+    let start = xmp.find(&format!("sthyle:{}=\"", name))? + name.len() + 9;
+    let end = start + xmp[start..].find('"')?;
+    Some(unescape(&xmp[start..end]))
+}
+
+fn escape(value: &str) -> String {
+    value.replace('&', "&amp;").replace('"', "&quot;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+fn unescape(value: &str) -> String {
+    value.replace("&quot;", "\"").replace("&lt;", "<").replace("&gt;", ">").replace("&amp;", "&")
+}
+
+/// Chunk types and whole chunks (length, type, data and CRC) of a PNG file
+fn png_chunks(bytes: &[u8]) -> Result<Vec<(&[u8], &[u8])>> {
+    let mut chunks = vec![];
+    let mut rest = &bytes[PNG_SIGNATURE.len()..];
+    while !rest.is_empty() {
+        if rest.len() < 12 {
+            bail!("Truncated PNG chunk");
+        }
+        let len = u32::from_be_bytes(rest[..4].try_into()?) as usize;
+        if rest.len() < len + 12 {
+            bail!("Truncated PNG chunk");
+        }
+        let (chunk, tail) = rest.split_at(len + 12);
+        chunks.push((&chunk[4..8], chunk));
+        rest = tail;
+    }
+    Ok(chunks)
+}
+
+fn png_chunk(kind: &[u8; 4], data: &[u8]) -> Vec<u8> {
+    let mut chunk = (data.len() as u32).to_be_bytes().to_vec();
+    chunk.extend_from_slice(kind);
+    chunk.extend_from_slice(data);
+    chunk.extend_from_slice(&crc32fast::hash(&chunk[4..]).to_be_bytes());
+    chunk
+}
+
+/// XMP packet of an uncompressed iTXt chunk
+fn png_xmp(chunk: &[u8]) -> Option<&[u8]> {
+    let data = chunk.get(8..chunk.len() - 4)?;
+    let text = data.strip_prefix(PNG_XMP_KEYWORD)?.strip_prefix(&[0, 0, 0][..])?;
+    // Skip the language tag and the translated keyword
+    let text = &text[text.iter().position(|&b| b == 0)? + 1..];
+    Some(&text[text.iter().position(|&b| b == 0)? + 1..])
+}
+
+/// Leading APPn/COM segments of a JPEG file, and everything after them
+fn jpeg_headers(bytes: &[u8]) -> Result<(Vec<&[u8]>, &[u8])> {
+    let mut headers = vec![];
+    let mut rest = &bytes[2..];
+    while rest.len() >= 4 && rest[0] == 0xff && ((0xe0..=0xef).contains(&rest[1]) || rest[1] == 0xfe) {
+        let len = u16::from_be_bytes([rest[2], rest[3]]) as usize;
+        if len < 2 || rest.len() < len + 2 {
+            bail!("Truncated JPEG segment");
+        }
+        let (segment, tail) = rest.split_at(len + 2);
+        headers.push(segment);
+        rest = tail;
+    }
+    Ok((headers, rest))
+}
+
+/// XMP packet of an APP1 segment
+fn jpeg_xmp(segment: &[u8]) -> Option<&[u8]> {
+    match segment.get(..2) {
+        Some([0xff, 0xe1]) => segment[4..].strip_prefix(JPEG_XMP_HEADER),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use contract::hash::HashAlgorithm;
+
+    use super::*;
+
+    const FOREIGN_XMP: &str = r#"<?xpacket begin="" id="W5M0MpCehiHzreSzNTczkc9d"?>
+<x:xmpmeta xmlns:x="adobe:ns:meta/">
+ <rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+  <rdf:Description rdf:about="" xmlns:dc="http://purl.org/dc/elements/1.1/" dc:format="image/jpeg"/>
+ </rdf:RDF>
+</x:xmpmeta>
+<?xpacket end="w"?>"#;
+
+    fn stamp(tx_hash: &str) -> Stamp {
+        Stamp {
+            contract_name: "image_registry".to_string(),
+            image_hash: ContentHash::compute(HashAlgorithm::Sha256, b"image"),
+            root_hash: ContentHash::compute(HashAlgorithm::Sha256, b"root"),
+            tx_hash: Some(tx_hash.to_string()),
+        }
+    }
+
+    /// SOI, an APP1 XMP segment holding `xmp`, and a stand-in for the image data
+    fn jpeg(xmp: &str) -> Vec<u8> {
+        let mut payload = JPEG_XMP_HEADER.to_vec();
+        payload.extend_from_slice(xmp.as_bytes());
+        let mut bytes = vec![0xff, 0xd8, 0xff, 0xe1];
+        bytes.extend_from_slice(&((payload.len() + 2) as u16).to_be_bytes());
+        bytes.extend_from_slice(&payload);
+        bytes.extend_from_slice(&[0xff, 0xdb, 0x00, 0x02, 0xff, 0xd9]);
+        bytes
+    }
+
+    fn packets(bytes: &[u8]) -> Vec<String> {
+        let headers = jpeg_headers(bytes).unwrap().0;
+        headers.into_iter().filter_map(jpeg_xmp).map(|p| String::from_utf8_lossy(p).into_owned()).collect()
+    }
+
+    #[test]
+    fn stamp_is_merged_into_the_existing_packet() {
+        let stamped = write(&jpeg(FOREIGN_XMP), &stamp("tx1")).unwrap();
+        let packets = packets(&stamped);
+        assert_eq!(packets.len(), 1);
+        assert!(packets[0].contains(r#"dc:format="image/jpeg""#));
+        assert_eq!(read(&stamped).unwrap(), Some(stamp("tx1")));
+        assert!(stamped.ends_with(&[0xff, 0xdb, 0x00, 0x02, 0xff, 0xd9]));
+    }
+
+    #[test]
+    fn restamping_replaces_the_previous_stamp() {
+        let once = write(&jpeg(FOREIGN_XMP), &stamp("tx1")).unwrap();
+        let twice = write(&once, &stamp("tx2")).unwrap();
+        let packets = packets(&twice);
+        assert_eq!(packets.len(), 1);
+        assert_eq!(packets[0].matches(XMP_NAMESPACE).count(), 1);
+        assert_eq!(read(&twice).unwrap(), Some(stamp("tx2")));
+        assert_eq!(write(&twice, &stamp("tx1")).unwrap(), once);
+    }
+
+    #[test]
+    fn png_without_xmp_gets_a_packet_before_iend() {
+        let mut png = PNG_SIGNATURE.to_vec();
+        png.extend_from_slice(&png_chunk(b"IHDR", &[0; 13]));
+        png.extend_from_slice(&png_chunk(b"IEND", &[]));
+        let stamped = write(&png, &stamp("tx1")).unwrap();
+        let kinds: Vec<&[u8]> = png_chunks(&stamped).unwrap().into_iter().map(|(kind, _)| kind).collect();
+        assert_eq!(kinds, vec![&b"IHDR"[..], b"iTXt", b"IEND"]);
+        assert_eq!(read(&stamped).unwrap(), Some(stamp("tx1")));
+    }
+}
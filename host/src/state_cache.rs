@@ -0,0 +1,409 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::Arc;
+use std::time::Duration;
+use anyhow::Result;
+use contract::hash::ContentHash;
+use contract::ownership::normalize_key;
+use contract::{ImageMetadata, ImageState};
+use sdk::{BlobTransaction, ContractInput, Hashed, HyleContract, StateDigest};
+use tokio::sync::{RwLock, RwLockReadGuard};
+use crate::pipeline::Node;
+use crate::similarity::SimilarityIndex;
+use crate::watermark;
+
+/// Secondary indexes over the registered images
+#[derive(Default)]
+pub struct Indexes {
+    /// Owner key, normalized -> images it owns or co-owns
+    pub owners: HashMap<String, BTreeSet<ContentHash>>,
+    /// Publisher key, normalized -> originals it was granted
+    pub publishers: HashMap<String, BTreeSet<ContentHash>>,
+    /// Image -> edits registered directly from it
+    pub descendants: HashMap<ContentHash, BTreeSet<ContentHash>>,
+    /// Contract height of the registration -> images
+    pub registered_at: BTreeMap<u64, BTreeSet<ContentHash>>,
+    /// Watermark ID (see `watermark::id`) -> originals it maps to
+    pub watermarks: HashMap<u32, BTreeSet<ContentHash>>,
+    pub similarity: SimilarityIndex,
+}
+
+/// Last settled state and its indexes
+#[derive(Default)]
+pub struct Index {
+    pub state: ImageState,
+    pub indexes: Indexes,
+}
+
+impl Indexes {
+    fn insert(&mut self, hash: ContentHash, metadata: &ImageMetadata) {
+        for key in &metadata.owner_pk.keys {
+            self.owners.entry(normalize_key(key)).or_default().insert(hash);
+        }
+        for publisher in metadata.publishers.keys() {
+            self.publishers.entry(publisher.clone()).or_default().insert(hash);
+        }
+        if let Some(parent) = metadata.previous_image_hash {
+            self.descendants.entry(parent).or_default().insert(hash);
+        }
+        self.registered_at.entry(metadata.registered_at).or_default().insert(hash);
+        if let (true, Some(perceptual_hash)) = (metadata.is_root, metadata.perceptual_hash) {
+            self.similarity.insert(perceptual_hash, hash);
+        }
+        if metadata.is_root {
+            self.watermarks.entry(watermark::id(&hash)).or_default().insert(hash);
+        }
+    }
+
+    /// Drops the ownership and publication entries of an image, the only ones that change after registration
+    fn remove_grants(&mut self, hash: &ContentHash, metadata: &ImageMetadata) {
+        for key in &metadata.owner_pk.keys {
+            remove(&mut self.owners, &normalize_key(key), hash);
+        }
+        for publisher in metadata.publishers.keys() {
+            remove(&mut self.publishers, publisher, hash);
+        }
+    }
+
+    fn insert_grants(&mut self, hash: ContentHash, metadata: &ImageMetadata) {
+        for key in &metadata.owner_pk.keys {
+            self.owners.entry(normalize_key(key)).or_default().insert(hash);
+        }
+        for publisher in metadata.publishers.keys() {
+            self.publishers.entry(publisher.clone()).or_default().insert(hash);
+        }
+    }
+}
+
+impl Index {
+    /// Brings the index to `settled`, indexing only the images that were registered or changed since the
+    /// previous settled state. Returns how many images were (re)indexed.
+    pub fn apply(&mut self, settled: ImageState) -> usize {
+        let previous = std::mem::replace(&mut self.state, settled);
+        let mut changed = 0;
+        for (hash, metadata) in &self.state.hash_map {
+            match previous.hash_map.get(hash) {
+                None => self.indexes.insert(*hash, metadata),
+                Some(old) if old.owner_pk != metadata.owner_pk || old.publishers != metadata.publishers => {
+                    self.indexes.remove_grants(hash, old);
+                    self.indexes.insert_grants(*hash, metadata);
+                }
+                Some(_) => continue,
+            }
+            changed += 1;
+        }
+        changed
+    }
+
+    /// Executes the actions of `blobs`, settled right after the indexed state, and indexes the state they lead to.
+    /// Fails, leaving the index as is, when one of them doesn't execute: the private input of an action isn't
+    /// on-chain, so those proven with one (e.g. a redaction) can't be replayed.
+    pub fn replay(&mut self, contract_name: &str, blobs: &[BlobTransaction]) -> Result<usize, String> {
+        let mut state = self.state.clone();
+        for blob_tx in blobs {
+            let index = blob_tx
+                .blobs
+                .iter()
+                .position(|blob| blob.contract_name.0 == contract_name)
+                .ok_or(format!("Blob tx {} has no blob for {}", blob_tx.hashed(), contract_name))?;
+            let inputs = ContractInput {
+                state: state.as_bytes().map_err(|e| e.to_string())?,
+                identity: blob_tx.identity.clone(),
+                tx_hash: blob_tx.hashed(),
+                private_input: vec![],
+                tx_ctx: None,
+                blobs: blob_tx.blobs.clone(),
+                index: sdk::BlobIndex(index),
+            };
+            state.execute(&inputs).map_err(|e| format!("Blob tx {} doesn't replay: {}", inputs.tx_hash, e))?;
+        }
+        Ok(self.apply(state))
+    }
+}
+
+fn remove(index: &mut HashMap<String, BTreeSet<ContentHash>>, key: &String, hash: &ContentHash) {
+    if let Some(hashes) = index.get_mut(key) {
+        hashes.remove(hash);
+        if hashes.is_empty() {
+            index.remove(key);
+        }
+    }
+}
+
+/// Polling cache of the settled state: keeps an `Index` of it for the read routes, which then don't fetch and
+/// decode the whole state per request. Each poll replays the transactions settled since the indexed height when the
+/// node lists them (see `Node::settled_blobs`). Otherwise, or when one doesn't replay, the whole state is fetched;
+/// it is only decoded when its digest changed, and only the images it changed are reindexed.
+#[derive(Clone)]
+pub struct StateCache {
+    index: Arc<RwLock<Index>>,
+}
+
+impl StateCache {
+    /// Serves `index` as is, see `start` to keep it up to date
+    pub fn new(index: Index) -> Self {
+        StateCache {
+            index: Arc::new(RwLock::new(index)),
+        }
+    }
+
+    /// Indexes the current settled state, then polls the node every `interval` for newer ones
+    pub async fn start(node: Arc<dyn Node>, contract_name: String, interval: Duration) -> Result<Self> {
+        let mut digest = node.contract_state(&contract_name).await?;
+        let mut index = Index::default();
+        index.apply(ImageState::try_from(digest.clone()).map_err(anyhow::Error::msg)?);
+        println!("📇 Indexed {} images at height {}", index.state.hash_map.len(), index.state.height);
+        let cache = StateCache::new(index);
+
+        let index = Arc::clone(&cache.index);
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                poll(node.as_ref(), &contract_name, &index, &mut digest).await;
+            }
+        });
+        Ok(cache)
+    }
+
+    pub async fn read(&self) -> RwLockReadGuard<'_, Index> {
+        self.index.read().await
+    }
+}
+
+/// Brings `index` to the settled state, by replaying the transactions settled since or else from the whole state.
+/// `digest` is the last whole state fetched.
+async fn poll(node: &dyn Node, contract_name: &str, index: &RwLock<Index>, digest: &mut StateDigest) {
+    let height = index.read().await.state.height;
+    match node.settled_blobs(contract_name, height).await {
+        Ok(Some(blobs)) if blobs.is_empty() => return,
+        Ok(Some(blobs)) => match index.write().await.replay(contract_name, &blobs) {
+            Ok(changed) => {
+                println!("📇 Replayed up to height {} ({} images changed)", height + blobs.len() as u64, changed);
+                return;
+            }
+            Err(error) => eprintln!("⚠️ State cache could not replay the settled transactions: {}", error),
+        },
+        Ok(None) => {}
+        Err(error) => eprintln!("⚠️ State cache could not list the settled transactions: {:#}", error),
+    }
+
+    let settled = match node.contract_state(contract_name).await {
+        Ok(settled) if settled == *digest => return,
+        Ok(settled) => settled,
+        Err(error) => return eprintln!("⚠️ State cache could not fetch the settled state: {:#}", error),
+    };
+    match ImageState::try_from(settled.clone()) {
+        Ok(state) => {
+            let height = state.height;
+            let changed = index.write().await.apply(state);
+            println!("📇 Indexed height {} ({} images changed)", height, changed);
+        }
+        Err(error) => eprintln!("⚠️ State cache could not decode the settled state: {}", error),
+    }
+    *digest = settled;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex as StdMutex;
+    use async_trait::async_trait;
+    use contract::hash;
+    use contract::ownership::OwnerKeys;
+    use contract::ImageAction;
+    use sdk::{Digestable, ProofTransaction, TxHash};
+    use super::*;
+
+    const CONTRACT: &str = "image_registry";
+
+    fn image(name: &str) -> ContentHash {
+        hash::file_hash(name.as_bytes())
+    }
+
+    fn register(name: &str, owner: &str) -> ImageAction {
+        ImageAction::RegisterImage {
+            image_hash: image(name),
+            canonical_hash: None,
+            perceptual_hash: Some(0xf0f0),
+            image_signature: String::new(),
+            owner_pk: OwnerKeys::single(owner.into()),
+        }
+    }
+
+    /// Registration of `name` with its canonical hash, proven by the file in the private input
+    fn proven(name: &str) -> ImageAction {
+        ImageAction::RegisterImage {
+            image_hash: image(name),
+            canonical_hash: Some(image("pixels")),
+            perceptual_hash: None,
+            image_signature: String::new(),
+            owner_pk: OwnerKeys::single("owner".into()),
+        }
+    }
+
+    fn add_publisher(name: &str, publisher_pk: &str) -> ImageAction {
+        ImageAction::AddPublisher {
+            original_image_hash: image(name),
+            original_image_signature: "owner:owner".parse().unwrap(),
+            publisher_pk: publisher_pk.into(),
+        }
+    }
+
+    fn blobs(actions: Vec<ImageAction>) -> Vec<BlobTransaction> {
+        let blob_tx = |action: ImageAction| BlobTransaction::new("none.image_registry", vec![action.as_blob(CONTRACT)]);
+        actions.into_iter().map(blob_tx).collect()
+    }
+
+    /// `a` owned by `owner`, with `Publisher` granted
+    fn indexed() -> Index {
+        let mut index = Index::default();
+        let actions = vec![register("a", "Owner"), add_publisher("a", "Publisher")];
+        assert_eq!(index.replay(CONTRACT, &blobs(actions)), Ok(1));
+        index
+    }
+
+    fn keys(index: &HashMap<String, BTreeSet<ContentHash>>, key: &str) -> Vec<ContentHash> {
+        index.get(key).into_iter().flatten().copied().collect()
+    }
+
+    #[test]
+    fn apply_reindexes_added_changed_and_removed_grants() {
+        let mut index = indexed();
+        assert_eq!(keys(&index.indexes.owners, "owner"), [image("a")]);
+        assert_eq!(keys(&index.indexes.publishers, "publisher"), [image("a")]);
+
+        let mut settled = index.state.clone();
+        settled.height += 1;
+        let a = settled.hash_map.get_mut(&image("a")).unwrap();
+        a.owner_pk = "2/owner,CoOwner".parse().unwrap();
+        let grant = a.publishers.remove("publisher").unwrap();
+        a.publishers.insert("other".into(), grant);
+        assert_eq!(index.apply(settled.clone()), 1);
+        assert_eq!(keys(&index.indexes.owners, "owner"), [image("a")]);
+        assert_eq!(keys(&index.indexes.owners, "coowner"), [image("a")]);
+        assert!(!index.indexes.publishers.contains_key("publisher"));
+        assert_eq!(keys(&index.indexes.publishers, "other"), [image("a")]);
+
+        settled.hash_map.get_mut(&image("a")).unwrap().publishers.clear();
+        settled.hash_map.get_mut(&image("a")).unwrap().owner_pk = OwnerKeys::single("new".into());
+        assert_eq!(index.apply(settled.clone()), 1);
+        assert!(index.indexes.publishers.is_empty());
+        assert_eq!(index.indexes.owners.keys().collect::<Vec<_>>(), ["new"]);
+        // Unchanged images aren't reindexed
+        assert_eq!(index.apply(settled), 0);
+        assert_eq!(index.indexes.registered_at[&1], BTreeSet::from([image("a")]));
+        assert_eq!(index.indexes.watermarks.values().flatten().collect::<Vec<_>>(), [&image("a")]);
+    }
+
+    #[test]
+    fn replay_indexes_new_images_and_their_edits() {
+        let mut index = indexed();
+        let edit = ImageAction::RegisterEdit {
+            original_image_hash: image("a"),
+            edited_image_hash: image("a-crop"),
+            edited_canonical_hash: None,
+            original_edit_signature: "publisher:publisher".parse().unwrap(),
+            generative: false,
+        };
+        assert_eq!(index.replay(CONTRACT, &blobs(vec![register("b", "owner"), edit])), Ok(2));
+        assert_eq!(index.state.height, 4);
+        // The edit is owned by the owners of its original
+        assert_eq!(index.indexes.owners["owner"], BTreeSet::from([image("a"), image("b"), image("a-crop")]));
+        assert_eq!(index.indexes.descendants[&image("a")], BTreeSet::from([image("a-crop")]));
+        assert_eq!(index.indexes.registered_at[&4], BTreeSet::from([image("a-crop")]));
+    }
+
+    #[test]
+    fn replay_without_the_private_input_leaves_the_index_untouched() {
+        let mut index = indexed();
+        let before = index.state.as_digest();
+        assert!(index.replay(CONTRACT, &blobs(vec![register("b", "owner"), proven("c")])).is_err());
+        assert_eq!(index.state.as_digest(), before);
+        assert!(!index.state.hash_map.contains_key(&image("b")));
+        assert!(index.replay(CONTRACT, &blobs(vec![register("a", "thief")])).is_err());
+        let other_contract = vec![BlobTransaction::new("none.other", vec![register("b", "owner").as_blob("other")])];
+        assert!(index.replay(CONTRACT, &other_contract).is_err());
+    }
+
+    /// Node settling `state`, listing the blobs of `actions` as settled when set, and counting whole state fetches
+    #[derive(Default)]
+    struct FakeNode {
+        state: StdMutex<ImageState>,
+        actions: Option<Vec<ImageAction>>,
+        fetches: StdMutex<usize>,
+    }
+
+    #[async_trait]
+    impl Node for FakeNode {
+        async fn contract_state(&self, _contract_name: &str) -> anyhow::Result<StateDigest> {
+            *self.fetches.lock().unwrap() += 1;
+            Ok(self.state.lock().unwrap().as_digest())
+        }
+
+        async fn send_tx_blob(&self, _tx: &BlobTransaction) -> anyhow::Result<TxHash> {
+            unimplemented!()
+        }
+
+        async fn send_tx_proof(&self, _tx: &ProofTransaction) -> anyhow::Result<TxHash> {
+            unimplemented!()
+        }
+
+        async fn settled_blobs(&self, _contract_name: &str, from: u64) -> anyhow::Result<Option<Vec<BlobTransaction>>> {
+            Ok(self.actions.as_ref().map(|actions| blobs(actions[from as usize..].to_vec())))
+        }
+    }
+
+    /// Polls `node` once from `indexed()`, returning the index and how many times the whole state was fetched
+    async fn poll_once(node: FakeNode) -> (Index, usize) {
+        let index = indexed();
+        let mut digest = index.state.as_digest();
+        let index = RwLock::new(index);
+        poll(&node, CONTRACT, &index, &mut digest).await;
+        (index.into_inner(), node.fetches.into_inner().unwrap())
+    }
+
+    #[tokio::test]
+    async fn poll_replays_the_listed_transactions_without_fetching_the_state() {
+        let actions = vec![register("a", "Owner"), add_publisher("a", "Publisher"), register("b", "owner")];
+        let (index, fetches) = poll_once(FakeNode {
+            actions: Some(actions),
+            ..FakeNode::default()
+        })
+        .await;
+        assert_eq!((index.state.height, fetches), (3, 0));
+        assert!(index.indexes.owners["owner"].contains(&image("b")));
+
+        let (index, fetches) = poll_once(FakeNode {
+            actions: Some(vec![register("a", "Owner"), add_publisher("a", "Publisher")]),
+            ..FakeNode::default()
+        })
+        .await;
+        assert_eq!((index.state.height, fetches), (2, 0));
+    }
+
+    #[tokio::test]
+    async fn poll_falls_back_to_the_whole_state() {
+        let mut settled = indexed().state;
+        let actions = vec![register("a", "Owner"), add_publisher("a", "Publisher"), proven("c")];
+        // The node executed it with its private input, the replay can't
+        let inputs = ContractInput {
+            state: settled.as_bytes().unwrap(),
+            identity: "none.image_registry".into(),
+            tx_hash: TxHash("tx".into()),
+            private_input: vec![],
+            tx_ctx: None,
+            blobs: vec![register("c", "owner").as_blob(CONTRACT)],
+            index: sdk::BlobIndex(0),
+        };
+        settled.execute(&inputs).unwrap();
+
+        for actions in [Some(actions), None] {
+            let (index, fetches) = poll_once(FakeNode {
+                state: StdMutex::new(settled.clone()),
+                actions,
+                ..FakeNode::default()
+            })
+            .await;
+            assert_eq!((index.state.height, fetches), (3, 1));
+            assert!(index.indexes.owners["owner"].contains(&image("c")));
+        }
+    }
+}
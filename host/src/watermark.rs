@@ -0,0 +1,248 @@
+use std::f32::consts::PI;
+use contract::hash::ContentHash;
+use contract::transform::RawImage;
+use contract::ImageState;
+use image::imageops::{self, FilterType};
+use image::{ImageBuffer, Luma};
+use schemars::JsonSchema;
+use serde::Serialize;
+
+/// Side of the luminance grid the watermark lives in. Images are resampled to it on both ends, so the mark
+/// survives resizing as well as recompression.
+const GRID: u32 = 256;
+const BLOCK: usize = 8;
+/// 32 bits of ID followed by their CRC-32, so that noise decodes to a valid payload once in 2^32 extractions
+const PAYLOAD_BITS: usize = 64;
+/// Mid-frequency DCT coefficients whose difference carries one bit per block
+const COEFFICIENTS: [(usize, usize); 2] = [(2, 3), (3, 2)];
+/// Minimum coefficient difference enforced when embedding
+const STRENGTH: f32 = 24.0;
+/// Share of blocks that must agree with the decoded bits for an extraction to count
+const MIN_AGREEMENT: f32 = 0.6;
+
+/// Float images are clamped to [0, 1] by `imageops`: luminance is stored divided by 255, and the signed
+/// embedding delta divided by 510 around 0.5
+type LumaImage = ImageBuffer<Luma<f32>, Vec<f32>>;
+
+/// Watermark found in an image, and the registered originals its ID maps to
+#[derive(Serialize, JsonSchema, Debug, Clone)]
+pub struct Identification {
+    pub watermark_id: String,
+    /// Share of the blocks agreeing with the decoded bits, from 0.5 (noise) to 1
+    pub confidence: f32,
+    pub matches: Vec<ContentHash>,
+}
+
+/// Short watermark ID of a root hash: the first 32 bits of its digest
+pub fn id(root_hash: &ContentHash) -> u32 {
+    u32::from_be_bytes(root_hash.digest[..4].try_into().unwrap())
+}
+
+/// Registered originals whose ID is `id`, found by scanning the state. The server looks them up in its
+/// `Indexes` instead.
+pub fn resolve(state: &ImageState, id: u32) -> Vec<ContentHash> {
+    let mut matches: Vec<ContentHash> = state
+        .hash_map
+        .iter()
+        .filter(|(hash, metadata)| metadata.is_root && self::id(hash) == id)
+        .map(|(hash, _)| *hash)
+        .collect();
+    matches.sort();
+    matches
+}
+
+/// Adds the invisible mark of `id` to the image, spread over the luminance of every channel
+pub fn embed(image: &RawImage, id: u32) -> RawImage {
+    let small = imageops::resize(&luma(image), GRID, GRID, FilterType::Triangle);
+    let bits = payload(id);
+    let mut delta = LumaImage::new(GRID, GRID);
+
+    for (index, (x, y)) in blocks().enumerate() {
+        let block = read_block(&small, x, y);
+        let mut coefficients = dct(&block);
+        let [(u1, v1), (u2, v2)] = COEFFICIENTS;
+        let sign = if bits[index % PAYLOAD_BITS] { 1.0 } else { -1.0 };
+        let missing = STRENGTH - sign * (coefficients[u1][v1] - coefficients[u2][v2]);
+        if missing > 0.0 {
+            coefficients[u1][v1] += sign * missing / 2.0;
+            coefficients[u2][v2] -= sign * missing / 2.0;
+        }
+        let marked = idct(&coefficients);
+        for (i, j) in (0..BLOCK).flat_map(|i| (0..BLOCK).map(move |j| (i, j))) {
+            delta.put_pixel(x + j as u32, y + i as u32, Luma([0.5 + (marked[i][j] - block[i][j]) / 510.0]));
+        }
+    }
+
+    let delta = imageops::resize(&delta, image.width, image.height, FilterType::Triangle);
+    let mut pixels = image.pixels.clone();
+    for (pixel, d) in pixels.chunks_exact_mut(4).zip(delta.pixels()) {
+        for channel in &mut pixel[..3] {
+            *channel = (*channel as f32 + (d.0[0] - 0.5) * 510.0).round().clamp(0.0, 255.0) as u8;
+        }
+    }
+    RawImage::new(image.width, image.height, pixels).expect("same dimensions as the original")
+}
+
+/// Decodes the mark of a possibly recompressed or resized image. `None` when no consistent mark is found.
+pub fn extract(image: &RawImage) -> Option<(u32, f32)> {
+    let small = imageops::resize(&luma(image), GRID, GRID, FilterType::Triangle);
+    let [(u1, v1), (u2, v2)] = COEFFICIENTS;
+    let differences: Vec<f32> = blocks()
+        .map(|(x, y)| {
+            let coefficients = dct(&read_block(&small, x, y));
+            coefficients[u1][v1] - coefficients[u2][v2]
+        })
+        .collect();
+
+    let mut votes = [0.0f32; PAYLOAD_BITS];
+    for (index, difference) in differences.iter().enumerate() {
+        votes[index % PAYLOAD_BITS] += difference.signum();
+    }
+    let bits: Vec<bool> = votes.iter().map(|vote| *vote > 0.0).collect();
+    let agreeing = differences
+        .iter()
+        .enumerate()
+        .filter(|(index, difference)| (**difference > 0.0) == bits[index % PAYLOAD_BITS])
+        .count();
+    let confidence = agreeing as f32 / differences.len() as f32;
+
+    let id = bits[..32].iter().fold(0u32, |id, bit| id << 1 | *bit as u32);
+    (payload(id)[..] == bits[..] && confidence >= MIN_AGREEMENT).then_some((id, confidence))
+}
+
+/// Extracts the mark of an image and maps it to registered originals with `resolve`
+pub fn identify(image: &RawImage, resolve: impl FnOnce(u32) -> Vec<ContentHash>) -> Option<Identification> {
+    let (id, confidence) = extract(image)?;
+    Some(Identification {
+        watermark_id: format!("{:08x}", id),
+        confidence,
+        matches: resolve(id),
+    })
+}
+
+fn payload(id: u32) -> [bool; PAYLOAD_BITS] {
+    let checksum = crc32fast::hash(&id.to_be_bytes());
+    let value = (id as u64) << 32 | checksum as u64;
+    std::array::from_fn(|i| value >> (PAYLOAD_BITS - 1 - i) & 1 == 1)
+}
+
+fn luma(image: &RawImage) -> LumaImage {
+    let values = image
+        .pixels
+        .chunks_exact(4)
+        .map(|p| (0.299 * p[0] as f32 + 0.587 * p[1] as f32 + 0.114 * p[2] as f32) / 255.0)
+        .collect();
+    LumaImage::from_raw(image.width, image.height, values).expect("one value per pixel")
+}
+
+/// Top-left corners of the grid blocks, row by row
+fn blocks() -> impl Iterator<Item = (u32, u32)> {
+    let step = BLOCK as u32;
+    (0..GRID / step).flat_map(move |by| (0..GRID / step).map(move |bx| (bx * step, by * step)))
+}
+
+fn read_block(image: &LumaImage, x: u32, y: u32) -> [[f32; BLOCK]; BLOCK] {
+    std::array::from_fn(|i| std::array::from_fn(|j| image.get_pixel(x + j as u32, y + i as u32).0[0] * 255.0))
+}
+
+fn basis(k: usize, n: usize) -> f32 {
+    let scale = if k == 0 { (1.0 / BLOCK as f32).sqrt() } else { (2.0 / BLOCK as f32).sqrt() };
+    scale * ((2 * n + 1) as f32 * k as f32 * PI / (2 * BLOCK) as f32).cos()
+}
+
+/// Orthonormal 2D DCT-II of a block
+fn dct(block: &[[f32; BLOCK]; BLOCK]) -> [[f32; BLOCK]; BLOCK] {
+    std::array::from_fn(|u| {
+        std::array::from_fn(|v| {
+            (0..BLOCK)
+                .flat_map(|i| (0..BLOCK).map(move |j| (i, j)))
+                .map(|(i, j)| basis(u, i) * basis(v, j) * block[i][j])
+                .sum()
+        })
+    })
+}
+
+fn idct(coefficients: &[[f32; BLOCK]; BLOCK]) -> [[f32; BLOCK]; BLOCK] {
+    std::array::from_fn(|i| {
+        std::array::from_fn(|j| {
+            (0..BLOCK)
+                .flat_map(|u| (0..BLOCK).map(move |v| (u, v)))
+                .map(|(u, v)| basis(u, i) * basis(v, j) * coefficients[u][v])
+                .sum()
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use image::codecs::jpeg::JpegEncoder;
+    use image::{DynamicImage, RgbaImage};
+
+    use super::*;
+
+    const ID: u32 = 0x5eed_f00d;
+
+    /// Textured photo stand-in: smooth gradients with some deterministic grain
+    fn photo(width: u32, height: u32) -> RawImage {
+        let mut pixels = Vec::with_capacity((width * height * 4) as usize);
+        let mut seed = 0x1234_5678u32;
+        for y in 0..height {
+            for x in 0..width {
+                seed = seed.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                let grain = seed >> 28;
+                pixels.extend_from_slice(&[
+                    ((x * 255 / width + grain) % 256) as u8,
+                    ((y * 255 / height + grain) % 256) as u8,
+                    (((x + y) * 127 / (width + height)) + 64) as u8,
+                    255,
+                ]);
+            }
+        }
+        RawImage::new(width, height, pixels).unwrap()
+    }
+
+    fn rgba(image: &RawImage) -> DynamicImage {
+        DynamicImage::ImageRgba8(RgbaImage::from_raw(image.width, image.height, image.pixels.clone()).unwrap())
+    }
+
+    fn raw(image: DynamicImage) -> RawImage {
+        let image = image.to_rgba8();
+        RawImage::new(image.width(), image.height(), image.into_raw()).unwrap()
+    }
+
+    fn reencode(image: &RawImage, quality: u8) -> RawImage {
+        let mut jpeg = vec![];
+        rgba(image).to_rgb8().write_with_encoder(JpegEncoder::new_with_quality(&mut jpeg, quality)).unwrap();
+        raw(image::load(Cursor::new(jpeg), image::ImageFormat::Jpeg).unwrap())
+    }
+
+    #[test]
+    fn mark_survives_jpeg_reencoding() {
+        let marked = embed(&photo(640, 480), ID);
+        let (id, confidence) = extract(&reencode(&marked, 75)).unwrap();
+        assert_eq!(id, ID);
+        assert!(confidence > MIN_AGREEMENT);
+    }
+
+    #[test]
+    fn mark_survives_resizing_and_reencoding() {
+        let marked = embed(&photo(640, 480), ID);
+        let resized = raw(rgba(&marked).resize_exact(400, 300, FilterType::Triangle));
+        assert_eq!(extract(&reencode(&resized, 80)).map(|(id, _)| id), Some(ID));
+    }
+
+    #[test]
+    fn unmarked_image_has_no_mark() {
+        assert_eq!(extract(&reencode(&photo(640, 480), 75)), None);
+    }
+
+    #[test]
+    fn identify_resolves_the_extracted_id() {
+        let root = ContentHash::compute(contract::hash::HashAlgorithm::Sha256, b"root");
+        let marked = embed(&photo(320, 240), id(&root));
+        let identification = identify(&marked, |id| if id == self::id(&root) { vec![root] } else { vec![] }).unwrap();
+        assert_eq!(identification.watermark_id, format!("{:08x}", id(&root)));
+        assert_eq!(identification.matches, vec![root]);
+    }
+}
@@ -3,7 +3,7 @@
 
 extern crate alloc;
 
-use contract::ImageState;
+use contract::state::ContractState;
 use sdk::guest::execute;
 use sdk::guest::GuestEnv;
 use sdk::guest::Risc0Env;
@@ -15,9 +15,12 @@ fn main() {
     // Usually you don't need to update this file.
     // Except to specify the name of your contract type (here = Counter)
     //
+    // `ContractState` decodes every version of `ImageState`, and fails the actions instead of
+    // panicking when the state can't be decoded.
+    //
 
     let env = Risc0Env {};
     let input = env.read();
-    let (_, output) = execute::<ImageState>(&input);
+    let (_, output) = execute::<ContractState>(&input);
     env.commit(&output);
 }